@@ -0,0 +1,35 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Embeds the git commit, build time and target so a running binary can report exactly which build
+/// it is. With the `embedded-config` feature, also embeds the config file named by
+/// `BMP180_EMBEDDED_CONFIG`, `bmp180.toml` by default.
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"));
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rustc-env=BUILD_TARGET={}", env::var("TARGET").unwrap_or_default());
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    if env::var_os("CARGO_FEATURE_EMBEDDED_CONFIG").is_some() {
+        let path = env::var("BMP180_EMBEDDED_CONFIG").unwrap_or_else(|_| String::from("bmp180.toml"));
+        let embedded = PathBuf::from(env::var("OUT_DIR").unwrap()).join("embedded_config.toml");
+        if let Err(e) = fs::copy(&path, embedded) {
+            panic!("Could not embed config `{}`: {}", path, e);
+        }
+        println!("cargo:rerun-if-changed={}", path);
+        println!("cargo:rerun-if-env-changed=BMP180_EMBEDDED_CONFIG");
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{now, Data, Reading};
+use crate::sink::Sink;
+use log::{error, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+
+const HEADER: &str = "timestamp,temperature,pressure";
+
+/// Appends every reading to a CSV file for offline logging.
+pub struct CsvSink {
+    file: File,
+    path: PathBuf,
+    /// Set after a failed write, as a full or read-only SD card would fail every write after it.
+    disabled: bool,
+}
+
+impl CsvSink {
+    /// Opens the file for appending, writing the header row if the file is new or empty.
+    pub fn open(path: &Path) -> io::Result<CsvSink> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", HEADER)?;
+            file.flush()?;
+        }
+        Ok(CsvSink { file, path: path.to_path_buf(), disabled: false })
+    }
+
+    pub fn write(&mut self, config: &Data, reading: &Reading) -> io::Result<()> {
+        let timestamp = now(config)
+            .format(&Rfc3339)
+            .map_err(io::Error::other)?;
+        // Measurements that weren't read this cycle are left empty
+        let field = |value: Option<f32>| value.map(|v| v.to_string()).unwrap_or_default();
+        writeln!(self.file, "{},{},{}", timestamp, field(reading.temperature), field(reading.pressure))?;
+        self.file.flush()
+    }
+}
+
+impl Sink for CsvSink {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    fn publish_state(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        if self.disabled {
+            return Ok(());
+        }
+        if let Err(e) = self.write(config, reading) {
+            error!("Failed to write reading to CSV file `{}`: {}", self.path.display(), e);
+            warn!("Disabling the CSV sink until restart, other outputs are unaffected");
+            self.disabled = true;
+        }
+        Ok(())
+    }
+}
+
+/// Plays back the rows of a CSV file written by [`CsvSink`] in place of the sensor.
+pub struct CsvReplay {
+    rows: Vec<(Option<f32>, Option<f32>)>,
+    position: usize,
+    looping: bool,
+}
+
+impl CsvReplay {
+    /// Reads the whole file up front so a malformed row is reported before anything is published.
+    pub fn open(path: &Path, looping: bool) -> io::Result<CsvReplay> {
+        let contents = fs::read_to_string(path)?;
+        let mut rows = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            if line.is_empty() || line == HEADER {
+                continue;
+            }
+            let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, message));
+            let mut fields = line.split(',').skip(1);
+            let mut field = || -> io::Result<Option<f32>> {
+                match fields.next() {
+                    Some("") => Ok(None),
+                    Some(value) => value.parse().map(Some).map_err(|_| invalid(&format!("invalid value `{}`", value))),
+                    None => Err(invalid("expected 3 columns")),
+                }
+            };
+            rows.push((field()?, field()?));
+        }
+        Ok(CsvReplay { rows, position: 0, looping })
+    }
+
+    /// Returns the next row, or `None` once the file is exhausted and not looping.
+    pub fn next_reading(&mut self) -> Option<Reading> {
+        if self.position == self.rows.len() && self.looping {
+            self.position = 0;
+        }
+        let (temperature, pressure) = *self.rows.get(self.position)?;
+        self.position += 1;
+        Some(Reading {
+            temperature,
+            pressure,
+            read_latency: Duration::ZERO,
+            temperature_drift: None,
+            raw_temperature: temperature,
+            raw_pressure: pressure,
+            temperature_failed: false,
+            pressure_failed: false,
+        })
+    }
+}
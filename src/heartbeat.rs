@@ -0,0 +1,69 @@
+use crate::Data;
+use crate::mqtt::{self, Category};
+use log::{debug, warn};
+use rumqttc::Client;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Publishes an incrementing counter to `{identifier}/heartbeat` on a thread of its own, so it
+/// keeps going while the read loop is stuck on the sensor and only stops with the process.
+pub struct Heartbeat {
+    /// Hands over the client after a reconnect. Dropping it stops the thread.
+    clients: Sender<Client>,
+    thread: JoinHandle<()>,
+}
+
+impl Heartbeat {
+    pub fn spawn(config: &Data, client: Client, connected: Arc<AtomicBool>) -> io::Result<Heartbeat> {
+        let topic = get_heartbeat_topic(config);
+        let interval = Duration::from_secs(config.mqtt.heartbeat_interval_seconds);
+        let qos = mqtt::qos(config, Category::Diagnostics);
+        let (clients, new_clients) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(String::from("heartbeat"))
+            .spawn(move || {
+                let mut client = client;
+                let mut count: u64 = 0;
+                let mut next = Instant::now() + interval;
+                loop {
+                    match new_clients.recv_timeout(next.saturating_duration_since(Instant::now())) {
+                        Ok(new_client) => {
+                            client = new_client;
+                            continue;
+                        },
+                        Err(RecvTimeoutError::Timeout) => (),
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                    next += interval;
+                    if !connected.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    count += 1;
+                    debug!("Publishing heartbeat [{}] to topic [{}]", count, topic);
+                    // Never retained, as a retained heartbeat would outlive the process it vouches for
+                    if let Err(e) = client.try_publish(&topic, qos, false, count.to_string()) {
+                        warn!("Failed to publish heartbeat due to error: {}", e);
+                    }
+                }
+            })?;
+        Ok(Heartbeat { clients, thread })
+    }
+
+    /// Publishes through `client` from now on, after the connection was rebuilt.
+    pub fn set_client(&self, client: Client) {
+        let _ = self.clients.send(client);
+    }
+
+    pub fn stop(self) {
+        drop(self.clients);
+        let _ = self.thread.join();
+    }
+}
+
+pub fn get_heartbeat_topic(config: &Data) -> String {
+    format!("{}/heartbeat", config.mqtt.identifier)
+}
@@ -0,0 +1,78 @@
+use crate::HomeAssistantRest;
+use log::{debug, info, warn};
+use secrecy::ExposeSecret;
+use serde_json::Value;
+use std::time::Duration;
+
+/// How long a single request to HA may take, so an unreachable HA doesn't stall the read loop.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A state to set on HA, as `(entity_id, state, attributes)`.
+pub type State = (String, String, Value);
+
+/// Sets entity states through HA's REST API, for while the MQTT broker is unreachable.
+pub struct RestFallback {
+    agent: ureq::Agent,
+    active: bool,
+}
+
+impl RestFallback {
+    pub fn new() -> Self {
+        RestFallback {
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+            active: false,
+        }
+    }
+
+    /// Posts `states` if the broker has been unreachable for longer than the configured period.
+    pub fn post_states(&mut self, rest: &HomeAssistantRest, disconnected_for: Duration, states: Vec<State>) {
+        if disconnected_for < Duration::from_secs(rest.fallback_after_seconds) {
+            return;
+        }
+        if !self.active {
+            self.active = true;
+            info!("MQTT broker unreachable for {}s, posting readings to Home Assistant at {}", disconnected_for.as_secs(), rest.url);
+        }
+
+        for (entity_id, state, attributes) in states {
+            let url = format!("{}/api/states/{}", rest.url.trim_end_matches('/'), entity_id);
+            let body = serde_json::json!({ "state": state, "attributes": attributes });
+            debug!("Posting state [{}] to [{}]", state, url);
+            let result = self.agent.post(&url)
+                .set("Authorization", &format!("Bearer {}", rest.token.expose_secret()))
+                .set("Content-Type", "application/json")
+                .send_string(&body.to_string());
+            if let Err(e) = result {
+                warn!("Failed to post {} to Home Assistant due to error: {}", entity_id, e);
+                // The rest would most likely fail the same way, each after a timeout
+                return;
+            }
+        }
+    }
+
+    /// Called once the broker is reachable again.
+    pub fn stop(&mut self) {
+        if self.active {
+            self.active = false;
+            info!("MQTT broker reachable again, no longer posting readings to Home Assistant");
+        }
+    }
+}
+
+/// Whether HA is set to US customary units rather than metric, going by its temperature unit.
+pub fn uses_imperial_units(rest: &HomeAssistantRest) -> Result<bool, String> {
+    let url = format!("{}/api/config", rest.url.trim_end_matches('/'));
+    debug!("Asking Home Assistant for its unit system at [{}]", url);
+    let body = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build()
+        .get(&url)
+        .set("Authorization", &format!("Bearer {}", rest.token.expose_secret()))
+        .call()
+        .map_err(|e| e.to_string())?
+        .into_string()
+        .map_err(|e| e.to_string())?;
+    let config: Value = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+    match config["unit_system"]["temperature"].as_str() {
+        Some(unit) => Ok(unit == "°F"),
+        None => Err(String::from("the response has no unit_system.temperature")),
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{reduce_pressure, Data, Reading, TemperatureUnit};
+use crate::measurement::{Measurement, Unit};
+use crate::sink::Sink;
+use log::{debug, warn};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a client may take to send its request or read the response, so a stalled one can't
+/// hold up the scrapes after it.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the latest reading over HTTP, as Prometheus metrics on `/metrics` and as a health check
+/// on `/health`.
+pub struct HttpSink {
+    served: Arc<Mutex<Served>>,
+    /// Measurements carried over from earlier readings, so a gauge keeps its value through the
+    /// reads that didn't take its measurement.
+    latest: Option<Reading>,
+    read_failures: u64,
+}
+
+/// What the server thread answers from, kept up to date by the sink.
+struct Served {
+    /// The exposition served on `/metrics`, rendered with every reading.
+    metrics: String,
+    started: Instant,
+    last_good_read: Option<Instant>,
+    /// Set once reinitializing gave up on the sensor, until a read succeeds again.
+    sensor_lost: bool,
+    startup_grace: Duration,
+    max_read_age: Option<Duration>,
+}
+
+impl Served {
+    /// Starting until the first good read or the end of the grace period, then healthy while the
+    /// sensor is readable and its last good read is recent enough.
+    fn health(&self, now: Instant) -> Health {
+        match self.last_good_read {
+            None if now.duration_since(self.started) < self.startup_grace => Health::Starting,
+            None => Health::Unhealthy,
+            Some(_) if self.sensor_lost => Health::Unhealthy,
+            Some(read) if self.max_read_age.is_some_and(|max_age| now.duration_since(read) > max_age) => Health::Unhealthy,
+            Some(_) => Health::Healthy,
+        }
+    }
+}
+
+enum Health {
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+impl HttpSink {
+    pub fn open(config: &Data, listen: &str) -> io::Result<HttpSink> {
+        let listener = TcpListener::bind(listen)?;
+        let served = Arc::new(Mutex::new(Served {
+            metrics: String::new(),
+            started: Instant::now(),
+            last_good_read: None,
+            sensor_lost: false,
+            startup_grace: Duration::ZERO,
+            max_read_age: None,
+        }));
+
+        // Rendered before serving, so the first request already sees the grace period
+        let sink = HttpSink { served: Arc::clone(&served), latest: None, read_failures: 0 };
+        sink.render(config);
+        thread::Builder::new()
+            .name(String::from("http"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(e) = serve(&served, stream) {
+                                debug!("Failed to answer an HTTP request: {}", e);
+                            }
+                        },
+                        Err(e) => warn!("Failed to accept an HTTP connection: {}", e),
+                    }
+                }
+            })?;
+        Ok(sink)
+    }
+
+    /// Also takes the health settings over from `config`, which a reload may have changed.
+    fn render(&self, config: &Data) {
+        let metrics = render_metrics(config, self.latest.as_ref(), self.read_failures);
+        let mut served = self.served.lock().unwrap();
+        served.metrics = metrics;
+        if let Some(http) = &config.http {
+            served.startup_grace = Duration::from_secs(http.health_startup_grace_seconds);
+            served.max_read_age = http.health_max_read_age_seconds.map(Duration::from_secs);
+        }
+    }
+}
+
+/// Answers one request and closes the connection.
+fn serve(served: &Mutex<Served>, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are read up to the blank line, so closing doesn't reset a client still sending them
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next().map(|path| path.split('?').next().unwrap_or_default())) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4; charset=utf-8", served.lock().unwrap().metrics.clone()),
+        (Some("GET"), Some("/health")) => match served.lock().unwrap().health(Instant::now()) {
+            Health::Starting => ("200 OK", "text/plain; charset=utf-8", String::from("starting\n")),
+            Health::Healthy => ("200 OK", "text/plain; charset=utf-8", String::from("healthy\n")),
+            Health::Unhealthy => ("503 Service Unavailable", "text/plain; charset=utf-8", String::from("unhealthy\n")),
+        },
+        (Some("GET"), _) => ("404 Not Found", "text/plain; charset=utf-8", String::from("Not found\n")),
+        _ => ("405 Method Not Allowed", "text/plain; charset=utf-8", String::from("Only GET is supported\n")),
+    };
+    let mut stream = &stream;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body)?;
+    stream.flush()
+}
+
+/// Renders the Prometheus text exposition of `reading`. The raw gauges show the measurements
+/// before calibration and offsets next to the processed ones, for calibrating against another
+/// instrument.
+fn render_metrics(config: &Data, reading: Option<&Reading>, read_failures: u64) -> String {
+    let (prefix, labels) = metric_naming(config);
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: Option<String>| {
+        if let Some(value) = value {
+            let _ = write!(out, "# HELP {prefix}_{name} {help}\n# TYPE {prefix}_{name} {kind}\n{prefix}_{name}{labels} {value}\n");
+        }
+    };
+    // Printed as f32 so 101.2 doesn't come out as 101.19999694824219
+    let number = |value: Option<f32>| value.map(|value| value.to_string());
+    let sensor = &config.sensor;
+    let temperature = reading.and_then(|r| r.temperature);
+    let pressure = reading.and_then(|r| r.pressure);
+    for &unit in &sensor.temperature_units {
+        let (name, help) = match unit {
+            TemperatureUnit::Celsius => ("temperature_celsius", "Temperature after calibration and temperature_offset, in degrees Celsius."),
+            TemperatureUnit::Fahrenheit => ("temperature_fahrenheit", "Temperature after calibration and temperature_offset, in degrees Fahrenheit."),
+        };
+        let converted = temperature.and_then(|t| Measurement::new(t, Unit::Celsius).converted_to(unit.into()));
+        metric(name, "gauge", help, number(converted.map(|t| t.raw)));
+    }
+    metric("raw_temperature_celsius", "gauge", "Temperature as read from the sensor, before calibration and offsets, in degrees Celsius.",
+        number(reading.and_then(|r| r.raw_temperature)));
+    metric("pressure_kilopascals", "gauge", "Pressure after pressure_offset, reduced to pressure_reference, in kilopascals.",
+        number(pressure.and_then(|p| reduce_pressure(sensor, p, temperature))));
+    metric("raw_pressure_kilopascals", "gauge", "Station pressure as read from the sensor, before offsets, in kilopascals.",
+        number(reading.and_then(|r| r.raw_pressure)));
+    metric("read_failures_total", "counter", "Sensor reads that failed since startup.", Some(read_failures.to_string()));
+    out
+}
+
+/// The start of every metric name and the labels that go on every sample. The room is a label
+/// unless `room_in_name` asks for it in the names, where it is lowercased with anything but
+/// letters and digits turned into `_`.
+fn metric_naming(config: &Data) -> (String, String) {
+    let Some(http) = &config.http else {
+        return (String::from("bmp180"), String::new());
+    };
+    match http.room(config).filter(|room| !room.is_empty()) {
+        Some(room) if http.room_in_name => {
+            let room: String = room.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+                .collect();
+            (format!("{}_{}", http.metrics_prefix, room), String::new())
+        },
+        Some(room) => {
+            let room = room.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            (http.metrics_prefix.clone(), format!("{{room=\"{}\"}}", room))
+        },
+        None => (http.metrics_prefix.clone(), String::new()),
+    }
+}
+
+impl Sink for HttpSink {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn publish_state(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        let latest = self.latest.unwrap_or(*reading);
+        self.latest = Some(Reading {
+            temperature: reading.temperature.or(latest.temperature),
+            pressure: reading.pressure.or(latest.pressure),
+            raw_temperature: reading.raw_temperature.or(latest.raw_temperature),
+            raw_pressure: reading.raw_pressure.or(latest.raw_pressure),
+            ..*reading
+        });
+        {
+            let mut served = self.served.lock().unwrap();
+            served.last_good_read = Some(Instant::now());
+            served.sensor_lost = false;
+        }
+        self.render(config);
+        Ok(())
+    }
+
+    fn handle_read_failure(&mut self, config: &Data) -> Result<(), ExitCode> {
+        self.read_failures += 1;
+        self.render(config);
+        Ok(())
+    }
+
+    fn handle_sensor_lost(&mut self, _config: &Data) -> Result<(), ExitCode> {
+        self.served.lock().unwrap().sensor_lost = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn served(started: Instant) -> Served {
+        Served {
+            metrics: String::new(),
+            started,
+            last_good_read: None,
+            sensor_lost: false,
+            startup_grace: Duration::from_secs(60),
+            max_read_age: Some(Duration::from_secs(300)),
+        }
+    }
+
+    #[test]
+    fn health_is_starting_until_the_grace_period_ends_without_a_read() {
+        let started = Instant::now();
+        let served = served(started);
+        assert!(matches!(served.health(started), Health::Starting));
+        assert!(matches!(served.health(started + Duration::from_secs(59)), Health::Starting));
+        assert!(matches!(served.health(started + Duration::from_secs(60)), Health::Unhealthy));
+    }
+
+    #[test]
+    fn health_follows_the_last_good_read() {
+        let started = Instant::now();
+        let read = started + Duration::from_secs(5);
+        let mut served = Served { last_good_read: Some(read), ..served(started) };
+        assert!(matches!(served.health(read), Health::Healthy));
+        assert!(matches!(served.health(read + Duration::from_secs(300)), Health::Healthy));
+        assert!(matches!(served.health(read + Duration::from_secs(301)), Health::Unhealthy));
+
+        served.sensor_lost = true;
+        assert!(matches!(served.health(read), Health::Unhealthy));
+    }
+
+    #[test]
+    fn first_read_after_the_grace_period_turns_healthy() {
+        let started = Instant::now();
+        let read = started + Duration::from_secs(90);
+        let served = Served { last_good_read: Some(read), ..served(started) };
+        assert!(matches!(served.health(read), Health::Healthy));
+    }
+}
@@ -0,0 +1,78 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+type Job<D> = Box<dyn FnOnce(&mut D) + Send>;
+
+/// Jobs that may wait behind the running one. A hung access otherwise lets every read after it pile
+/// up on the bus, all of them running once it comes back.
+const MAX_QUEUED_JOBS: usize = 2;
+
+/// Owns the devices on one I2C bus and runs every access to them on a single long-lived thread.
+/// Accesses are serialized per bus, and callers can stop waiting on one that hangs without a thread
+/// being spawned per read.
+pub struct I2cWorker<D> {
+    jobs: SyncSender<Job<D>>,
+}
+
+#[derive(Debug)]
+pub enum WorkerError {
+    /// The job didn't finish in time. It still runs to completion, delaying the jobs after it.
+    Timeout(Duration),
+    /// Too many jobs are already waiting behind one that hangs, so the job wasn't queued.
+    Busy,
+    /// The worker thread is gone, most likely because a job panicked.
+    Stopped,
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkerError::Timeout(timeout) => write!(f, "I2C access timed out after {:?}", timeout),
+            WorkerError::Busy => write!(f, "I2C bus busy with {} accesses waiting behind a hung one", MAX_QUEUED_JOBS),
+            WorkerError::Stopped => write!(f, "I2C worker thread stopped"),
+        }
+    }
+}
+
+impl Error for WorkerError {}
+
+impl<D: Send + 'static> I2cWorker<D> {
+    pub fn spawn(bus: &str, mut devices: D) -> io::Result<Self> {
+        let (jobs, queue) = mpsc::sync_channel::<Job<D>>(MAX_QUEUED_JOBS);
+        thread::Builder::new()
+            .name(format!("i2c {}", bus))
+            .spawn(move || {
+                for job in queue {
+                    job(&mut devices);
+                }
+            })?;
+        Ok(I2cWorker { jobs })
+    }
+
+    /// Runs `job` on the worker thread, waiting at most `timeout` for its result.
+    pub fn run<T, F>(&self, timeout: Duration, job: F) -> Result<T, WorkerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut D) -> T + Send + 'static,
+    {
+        let (result_tx, result_rx) = mpsc::channel();
+        let job: Job<D> = Box::new(move |devices| {
+            // The caller may have given up waiting, in which case the result is dropped
+            let _ = result_tx.send(job(devices));
+        });
+        self.jobs.try_send(job).map_err(|e| match e {
+            TrySendError::Full(_) => WorkerError::Busy,
+            TrySendError::Disconnected(_) => WorkerError::Stopped,
+        })?;
+
+        match result_rx.recv_timeout(timeout) {
+            Ok(result) => Ok(result),
+            Err(RecvTimeoutError::Timeout) => Err(WorkerError::Timeout(timeout)),
+            Err(RecvTimeoutError::Disconnected) => Err(WorkerError::Stopped),
+        }
+    }
+}
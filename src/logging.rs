@@ -0,0 +1,100 @@
+use env_logger::Logger;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+/// APP-NAME of syslog messages.
+const SYSLOG_APP_NAME: &str = "bmp180-mqtt";
+/// The daemon facility, shifted into place in the PRI part.
+const SYSLOG_FACILITY: u8 = 3 << 3;
+
+/// Hands every record to each logger, which applies its own level and target.
+struct Loggers {
+    loggers: Vec<Logger>,
+    /// Put in front of every message.
+    session_id: Option<&'static str>,
+}
+
+impl Log for Loggers {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.loggers.iter().any(|logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        for logger in &self.loggers {
+            match self.session_id {
+                Some(session_id) => logger.log(&Record::builder()
+                    .args(format_args!("[{}] {}", session_id, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build()),
+                None => logger.log(record),
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for logger in &self.loggers {
+            logger.flush();
+        }
+    }
+}
+
+/// Installs `loggers` as the global logger, letting through the most verbose level any of them
+/// wants. `session_id` starts every message when set.
+pub fn init(loggers: Vec<Logger>, session_id: Option<&'static str>) {
+    let max_level = loggers.iter().map(Logger::filter).max().unwrap_or(LevelFilter::Off);
+    if log::set_boxed_logger(Box::new(Loggers { loggers, session_id })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Sends each log line as one UDP datagram to a syslog server.
+pub struct SyslogWriter(UdpSocket);
+
+impl SyslogWriter {
+    pub fn connect(address: &str) -> io::Result<SyslogWriter> {
+        let server = address.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address resolved to nothing"))?;
+        let local: SocketAddr = if server.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+        Ok(SyslogWriter(socket))
+    }
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats records as RFC 5424 syslog messages, without the trailing newline of the other outputs.
+pub fn syslog_format() -> impl Fn(&mut env_logger::fmt::Formatter, &Record<'_>) -> io::Result<()> + Send + Sync + 'static {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|hostname| hostname.trim().to_string())
+        .ok()
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| String::from("-"));
+    let pid = std::process::id();
+    move |buf, record| {
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let timestamp = OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_else(|_| String::from("-"));
+        write!(buf, "<{}>1 {} {} {} {} - - {}: {}",
+            SYSLOG_FACILITY + severity, timestamp, hostname, SYSLOG_APP_NAME, pid, record.target(), record.args())
+    }
+}
@@ -1,268 +1,3735 @@
 use bmp085::*;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use daemonize::{Daemonize, Stdio};
+use i2cdev::core::I2CDevice;
 use i2cdev::linux::*;
 use i2cdev::sensors::{Barometer, Thermometer};
-use log::{info, debug, error};
-use rumqttc::{Client,Connection,Event,Incoming,MqttOptions,QoS};
+use log::{info, debug, error, warn};
+#[cfg(feature = "mqtt")]
 use secrecy::{ExposeSecret, SecretBox};
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
+use i2c_worker::I2cWorker;
+use measurement::{Measurement, Unit};
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
-use std::{fs, thread};
-use std::path::PathBuf;
+use std::hash::BuildHasher;
+use std::{fs, io};
+use std::io::Write;
+use std::thread;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
-use std::time::Duration;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+#[cfg(feature = "mqtt")]
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use time::{OffsetDateTime, Time, UtcOffset};
+use time::format_description::well_known::Rfc3339;
+use time_tz::{timezones, OffsetDateTimeExt, Tz};
 
-#[derive(Deserialize)]
+mod csv;
+mod http;
+mod i2c_worker;
+mod logging;
+mod measurement;
+#[cfg(feature = "mqtt")]
+mod heartbeat;
+#[cfg(feature = "mqtt")]
+mod homeassistant_rest;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "mqtt")]
+mod msgpack;
+mod persistence;
+mod sink;
+mod tui;
+mod unix_socket;
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 struct Data {
+    /// IANA name of the timezone timestamps are given in, e.g. `Europe/Berlin`, so a fleet of Pis
+    /// agrees regardless of their system settings. UTC when unset.
+    timezone: Option<String>,
+    #[cfg(feature = "mqtt")]
     mqtt_broker: MQTTBroker,
+    #[cfg(feature = "mqtt")]
     mqtt: MQTT,
+    #[cfg(feature = "mqtt")]
+    homeassistant_rest: Option<HomeAssistantRest>,
+    /// Deployment details such as the location or an asset tag, added as they are to the
+    /// attributes of every entity.
+    #[cfg(feature = "mqtt")]
+    #[serde(default)]
+    metadata: toml::Table,
     logging: Logging,
+    #[serde(default)]
+    sensor: Sensor,
+    csv: Option<Csv>,
+    unix_socket: Option<UnixSocket>,
+    http: Option<Http>,
+    persistence: Option<Persistence>,
+    /// Why MQTT is turned off, when its sections are invalid but the rest of the config can still
+    /// drive the other outputs.
+    #[cfg(feature = "mqtt")]
+    #[serde(skip)]
+    mqtt_error: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 struct MQTTBroker {
     host: String,
     port: u16,
     username: String,
+    #[serde(serialize_with = "redact")]
     password: SecretBox<String>,
+    /// How long to wait for the broker to accept a connection before giving up on the attempt.
+    #[serde(default = "default_connect_timeout_seconds")]
+    connect_timeout_seconds: u64,
+    /// Exit after this many consecutive failed attempts to reach the broker, instead of retrying
+    /// forever. The broker rejecting the credentials always exits straight away.
+    max_reconnect_attempts: Option<u32>,
+    /// How long a connection has to hold before the reconnect delay starts over from a second. One
+    /// dropped sooner picks up the delay where it left off, so a link that keeps dropping soon
+    /// after connecting keeps backing off. 0 starts over on every accepted connection.
+    #[serde(default = "default_backoff_reset_seconds")]
+    backoff_reset_seconds: u64,
+    /// Sizes of the socket's send and receive buffers, in bytes. The kernel default when unset.
+    tcp_send_buffer_size: Option<u32>,
+    tcp_recv_buffer_size: Option<u32>,
+    /// Network interface to connect through, e.g. `wlan0`, for boards with several uplinks.
+    bind_device: Option<String>,
+    /// How to reach the broker. Defaults to `tls` with a `tls` section and `tcp` without.
+    transport: Option<BrokerTransport>,
+    /// Path of the broker's WebSocket endpoint, for the `ws` and `wss` transports.
+    #[serde(default = "default_ws_path")]
+    ws_path: String,
+    /// Certificates of the `tls` and `wss` transports.
+    tls: Option<Tls>,
+    /// The files of `tls`, read at startup and on reload so a missing one is caught right away.
+    #[serde(skip)]
+    tls_configuration: Option<rumqttc::TlsConfiguration>,
+    /// Appended to the client id with `--ephemeral-client-id`.
+    #[serde(skip)]
+    client_id_suffix: Option<String>,
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum BrokerTransport {
+    Tcp,
+    Tls,
+    /// MQTT over WebSockets, for brokers only reachable through an HTTP reverse proxy.
+    Ws,
+    Wss,
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Tls {
+    /// PEM file of the certificate authority the broker's certificate is checked against. The
+    /// certificate has to be issued for `host`, which is also sent as the SNI server name.
+    ca_file: PathBuf,
+    /// PEM files of a client certificate and its key, for brokers that authenticate clients by
+    /// certificate, such as AWS IoT Core.
+    client_cert_file: Option<PathBuf>,
+    client_key_file: Option<PathBuf>,
+    /// Protocols offered through ALPN, e.g. `["x-amzn-mqtt-ca"]` for AWS IoT Core on port 443.
+    #[serde(default)]
+    alpn: Vec<String>,
+}
+
+#[cfg(feature = "mqtt")]
+impl MQTTBroker {
+    fn apply_overrides(&mut self, overrides: &BrokerOverrides) {
+        if let Some(host) = &overrides.host {
+            self.host = host.clone();
+        }
+        if let Some(port) = overrides.port {
+            self.port = port;
+        }
+        if let Some(username) = &overrides.username {
+            self.username = username.clone();
+        }
+        if let Some(password) = &overrides.password {
+            self.password = SecretBox::new(Box::new(password.clone()));
+        }
+        if overrides.ephemeral_client_id {
+            self.client_id_suffix = Some(ephemeral_client_id_suffix().to_string());
+        }
+    }
+
+    /// Whether connecting with `other` would differ from connecting with these settings.
+    fn connection_differs(&self, other: &MQTTBroker) -> bool {
+        self.host != other.host
+            || self.port != other.port
+            || self.username != other.username
+            || self.password.expose_secret() != other.password.expose_secret()
+            || self.connect_timeout_seconds != other.connect_timeout_seconds
+            || self.tcp_send_buffer_size != other.tcp_send_buffer_size
+            || self.tcp_recv_buffer_size != other.tcp_recv_buffer_size
+            || self.bind_device != other.bind_device
+            || self.transport() != other.transport()
+            || self.ws_path != other.ws_path
+            || self.tls != other.tls
+    }
+
+    fn transport(&self) -> BrokerTransport {
+        self.transport.unwrap_or(if self.tls.is_some() { BrokerTransport::Tls } else { BrokerTransport::Tcp })
+    }
+}
+
+/// Random suffix for `--ephemeral-client-id`, picked once so reloads keep connecting under the same
+/// client id.
+#[cfg(feature = "mqtt")]
+fn ephemeral_client_id_suffix() -> &'static str {
+    static SUFFIX: OnceLock<String> = OnceLock::new();
+    SUFFIX.get_or_init(|| {
+        let random = std::collections::hash_map::RandomState::new().hash_one(std::process::id());
+        format!("{:08x}", random as u32)
+    })
+}
+
+/// Random id of this run, formatted as a version 4 UUID, telling its logs and attributes apart from
+/// those of the runs before and after it.
+fn session_id() -> &'static str {
+    static SESSION_ID: OnceLock<String> = OnceLock::new();
+    SESSION_ID.get_or_init(|| {
+        // Each RandomState is seeded anew, so the two halves are independent
+        let random = |salt: u8| std::collections::hash_map::RandomState::new().hash_one((std::process::id(), salt));
+        let id = (u128::from(random(0)) << 64) | u128::from(random(1));
+        let id = (id & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+        format!("{:08x}-{:04x}-{:04x}-{:04x}-{:012x}", id >> 96, (id >> 80) & 0xffff, (id >> 64) & 0xffff, (id >> 48) & 0xffff, id & 0xffff_ffff_ffff)
+    })
+}
+
+/// Serializes secrets as a placeholder so they never leave the process.
+#[cfg(feature = "mqtt")]
+fn redact<S: serde::Serializer>(_: &SecretBox<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("***")
+}
+
+#[cfg(feature = "mqtt")]
+fn deserialize_qos<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let qos = <u8 as serde::Deserialize>::deserialize(deserializer)?;
+    if qos > 2 {
+        return Err(serde::de::Error::custom(format!("invalid QoS {}, expected 0, 1 or 2", qos)));
+    }
+    Ok(qos)
+}
+
+#[cfg(feature = "mqtt")]
+fn deserialize_optional_qos<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+    deserialize_qos(deserializer).map(Some)
+}
+
+#[cfg(feature = "mqtt")]
+fn default_connect_timeout_seconds() -> u64 {
+    5
+}
+
+#[cfg(feature = "mqtt")]
+fn default_backoff_reset_seconds() -> u64 {
+    300
 }
 
-#[derive(Deserialize)]
+#[cfg(feature = "mqtt")]
+fn default_ws_path() -> String {
+    String::from("/mqtt")
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[allow(clippy::upper_case_acronyms)]
 struct MQTT {
     room: String,
     identifier: String,
+    /// Prepended to every entity's unique_id, e.g. `home_`, to match an existing naming scheme.
+    #[serde(default)]
+    unique_id_prefix: String,
+    name: String,
+    /// Topic Home Assistant publishes its birth message to when it starts. When set, discovery is
+    /// re-announced every time a birth message is received.
+    birth_topic: Option<String>,
+    #[serde(default = "default_birth_payload")]
+    birth_payload: String,
+    /// Topic to receive commands on. Publishing `read` to it triggers an immediate reading, `reset`
+    /// zeroes the diagnostic counters and then takes a reading, and `{"sink": "csv", "enabled":
+    /// false}` stops handing readings to a sink until it is enabled again.
+    command_topic: Option<String>,
+    /// Clear any retained message on `command_topic` before subscribing to it, so a command
+    /// published with the retain flag doesn't trigger a reading on every reconnect.
+    #[serde(default = "default_true")]
+    clear_retained_command: bool,
+    /// Maximum number of state messages held while disconnected from the broker. Once full, the
+    /// oldest message is dropped to make room for the newest.
+    #[serde(default = "default_offline_queue_size")]
+    offline_queue_size: usize,
+    /// Capacity of the channel between the publishing code and the MQTT event loop. Publishing
+    /// blocks once it is full, so a larger buffer trades memory for riding out a slow broker.
+    #[serde(default = "default_mqtt_queue_capacity")]
+    mqtt_queue_capacity: usize,
+    /// Wait for room in the queue when publishing state. Turning it off never blocks the read loop,
+    /// dropping state messages that don't fit instead.
+    #[serde(default = "default_true")]
+    publish_blocking: bool,
+    /// Maximum number of state messages held while the MQTT queue is full, published as soon as
+    /// there is room. Once full, the oldest is dropped so the freshest reading always goes out.
+    /// Takes over from `publish_blocking` when set, 0 turns it off.
+    #[serde(default)]
+    max_pending_states: usize,
+    #[serde(default)]
+    layout: Layout,
+    /// Announce the entities to HA through discovery. Turn off when they are configured by hand, so
+    /// they aren't duplicated by discovered ones.
+    #[serde(default = "default_true")]
+    publish_discovery: bool,
+    /// Times to retry a discovery message that failed to publish before skipping it.
+    #[serde(default = "default_discovery_retries")]
+    discovery_retries: u32,
+    /// Times to retry a state message that failed to publish before skipping that reading.
+    #[serde(default = "default_state_retries")]
+    state_retries: u32,
+    /// QoS of each kind of message.
+    #[serde(default)]
+    qos: QosLevels,
+    /// Older spelling of `qos.discovery`, which it overrides when set.
+    #[serde(default, deserialize_with = "deserialize_optional_qos")]
+    qos_discovery: Option<u8>,
+    /// Re-announce discovery this often, for brokers that don't persist retained messages. 0
+    /// only announces on connect and on HA's birth message.
+    #[serde(default)]
+    discovery_republish_interval_seconds: u64,
+    /// Wait this long after publishing discovery before the next state, so HA has subscribed to
+    /// the state topics of new entities by then. 0 doesn't wait.
+    #[serde(default = "default_post_discovery_delay_ms")]
+    post_discovery_delay_ms: u64,
+    #[serde(default)]
+    state_format: StateFormat,
+    #[serde(default)]
+    payload_encoding: PayloadEncoding,
+    /// Publish the state with the retain flag, so HA shows the last value right after it restarts.
+    #[serde(default = "default_true")]
+    retain_state: bool,
+    /// Retain flag of the first state published after startup, e.g. `false` so a value from an
+    /// earlier deployment isn't left behind if this one never publishes again. Defaults to
+    /// `retain_state`.
+    #[serde(default)]
+    retain_first_state: Option<bool>,
+    /// Set the entities to unknown on connecting, until the first reading replaces a value HA
+    /// would otherwise show from the last run. Left out when a last known reading is
+    /// restored instead.
+    #[serde(default)]
+    publish_unknown_on_start: bool,
+    /// Topic to publish state to instead of one derived from `room`, so several devices can share
+    /// one JSON payload. With the split format, it is the prefix of the per-measurement topics.
+    state_topic: Option<String>,
+    /// Decimal separator used in split mode. JSON payloads always use `.`.
+    #[serde(default = "default_decimal_separator")]
+    decimal_separator: String,
+    /// Suffix `identifier` with the machine hostname so the same config can be deployed to several
+    /// Pis without their entities colliding.
+    #[serde(default)]
+    append_hostname: bool,
+    /// Also suffix `name` with the hostname. Only used together with `append_hostname`.
+    #[serde(default)]
+    append_hostname_to_name: bool,
+    /// Publish `online`/`offline` to an availability topic, with `offline` set as the last will.
+    #[serde(default = "default_true")]
+    availability: bool,
+    /// Disconnects shorter than this don't mark the entities unavailable. The broker only publishes
+    /// the last will once the keep-alive lapses, so the keep-alive is stretched to cover the grace
+    /// period, and `online` is republished only once a reconnect has held briefly.
+    #[serde(default)]
+    availability_grace_seconds: u64,
+    /// Also give each measurement entity an availability topic of its own, marking it unavailable
+    /// while just that measurement fails. Needs `availability`.
+    #[serde(default)]
+    per_entity_availability: bool,
+    /// Payloads published to the availability topic, for HA setups expecting e.g. `1`/`0`.
+    #[serde(default = "default_payload_available")]
+    payload_available: String,
+    #[serde(default = "default_payload_not_available")]
+    payload_not_available: String,
+    /// What the entities show while the sensor can't be read.
+    #[serde(default)]
+    on_read_failure: OnReadFailure,
+    /// Publish an incrementing counter to `{identifier}/heartbeat` this often, whether or not the
+    /// sensor can be read, to tell a dead sensor apart from a dead process. 0 disables it.
+    #[serde(default)]
+    heartbeat_interval_seconds: u64,
+    /// Have Home Assistant record every state message, even when the value hasn't changed, so
+    /// automations triggered by updates still fire.
+    #[serde(default)]
+    force_update: bool,
+    /// Have Home Assistant mark the measurements unavailable when no state arrived for this long,
+    /// rather than keep showing a stale or unknown value.
+    expire_after_seconds: Option<u64>,
+    /// Area HA places the device in when it is first discovered. Defaults to `room`, an empty
+    /// string leaves the device unassigned.
+    suggested_area: Option<String>,
+    /// Identifier of the device this one connects through, such as a hub, shown as "connected via"
+    /// in HA.
+    via_device: Option<String>,
+    /// Page HA links to from the device, such as a local diagnostics page.
+    configuration_url: Option<String>,
+    /// MDI icons for the entities, e.g. `mdi:thermometer`.
+    temperature_icon: Option<String>,
+    pressure_icon: Option<String>,
+    /// Publish diagnostic entities (uptime, read latency, last update) alongside the measurements.
+    #[serde(default)]
+    diagnostics: bool,
+    /// Publish the Pi's CPU temperature as a diagnostic entity, for correlating the self-heating
+    /// of a sensor mounted close to it.
+    #[serde(default)]
+    cpu_temperature: bool,
+    /// Publish the diagnostics on their own this often instead of with every reading, so they can
+    /// be watched closely while the measurements are read rarely. In JSON mode they move to a
+    /// `diagnostics` topic next to the state topic.
+    diagnostics_interval_seconds: Option<u64>,
+    /// Fields of the attributes topic shared by every entity. An empty list publishes no
+    /// attributes topic at all.
+    #[serde(default = "default_attributes")]
+    attributes: Vec<Attribute>,
+    /// Add the chip's calibration coefficients to the attributes of every entity, to help spot
+    /// clone modules. Needs a non-empty `attributes`.
+    #[serde(default)]
+    publish_coefficients: bool,
+    /// Where the sensor is, in decimal degrees, added to the attributes of every entity so HA
+    /// places the entities on its map. Either both are set or neither.
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    /// Publish the resolved config, with secrets redacted, as a retained message to
+    /// `{identifier}/config` at startup.
+    #[serde(default)]
+    publish_config_echo: bool,
+    /// Publish a number entity for changing the poll interval from HA, on the fly. Only
+    /// `poll_interval` changes, `temperature_interval` and `pressure_interval` keep overriding it.
+    #[serde(default)]
+    poll_interval_control: bool,
+    /// Publish the read failures, publish failures and reconnects of the last hour to
+    /// `{identifier}/errors` this often. 0 disables the summary.
+    #[serde(default)]
+    error_summary_interval_seconds: u64,
+    /// Longest time without any message leaving for the broker before a warning is logged and an
+    /// alert is published to `{identifier}/errors`. Repeated as errors at doubling intervals while
+    /// it lasts. Set it above the longest gap expected between publishes.
+    max_publish_gap_seconds: Option<u64>,
+    /// Publish the min, max, mean and standard deviation of the readings taken over each period of
+    /// this many seconds to `{room}/stats`. 0 disables the stats.
+    #[serde(default)]
+    stats_interval_seconds: u64,
+    /// Publish the min, max, mean and standard deviation of each day's readings, retained, to
+    /// `{room}/daily/{date}` at midnight in the configured timezone.
+    #[serde(default)]
+    daily_summary: bool,
+    /// Cap on state publishes per minute, regardless of how often readings are taken. Readings over
+    /// the limit are dropped.
+    max_publishes_per_minute: Option<u32>,
+    /// Skip a reading unless a measurement moved at least this far from its last published value,
+    /// in °C and kPa. Measurements without one count as moved on any change.
+    temperature_deadband: Option<f32>,
+    pressure_deadband: Option<f32>,
+    /// The pressure deadband as a percentage of the last published pressure, in place of an
+    /// absolute one.
+    pressure_deadband_percent: Option<f32>,
+    /// Publish the latest reading again after every (re)connect, past the deadbands and the rate
+    /// limit, so HA is current without waiting for the next change to cross a deadband.
+    #[serde(default)]
+    republish_on_connect: bool,
+    /// Device triggers fired when the temperature crosses a threshold, for building automations
+    /// in HA off the event rather than a numeric comparison.
+    #[serde(default)]
+    temperature_triggers: Vec<TemperatureTrigger>,
+    #[serde(default)]
+    json_keys: JsonKeys,
+    #[serde(default)]
+    transforms: Transforms,
+    #[serde(default)]
+    state_classes: StateClasses,
+}
+
+/// A device trigger fired on `{identifier}/trigger/{name}`, with the temperature as payload, when
+/// the temperature crosses `above` or `below`. It fires again only once the temperature has moved
+/// back past the threshold by `hysteresis`, so readings hovering around it don't fire repeatedly.
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TemperatureTrigger {
+    /// Shown as the subtype of the trigger in HA, e.g. `too_hot`.
     name: String,
+    /// Exactly one of the two is set, in °C.
+    above: Option<f32>,
+    below: Option<f32>,
+    #[serde(default = "default_trigger_hysteresis")]
+    hysteresis: f32,
+}
+
+/// Keys of the measurements in the JSON state payload.
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct JsonKeys {
+    temperature: String,
+    pressure: String,
+    altitude: String,
+}
+
+#[cfg(feature = "mqtt")]
+impl Default for JsonKeys {
+    fn default() -> Self {
+        JsonKeys {
+            temperature: String::from("temperature"),
+            pressure: String::from("pressure"),
+            altitude: String::from("altitude"),
+        }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct QosLevels {
+    #[serde(deserialize_with = "deserialize_qos")]
+    discovery: u8,
+    /// States of the entities, the poll interval's included.
+    #[serde(deserialize_with = "deserialize_qos")]
+    state: u8,
+    /// Availability and the last will. Going offline on shutdown uses at least 1, as it waits for
+    /// the broker to acknowledge it.
+    #[serde(deserialize_with = "deserialize_qos")]
+    availability: u8,
+    /// Attributes, stats, error summaries, the config echo and the heartbeat.
+    #[serde(deserialize_with = "deserialize_qos")]
+    diagnostics: u8,
+    /// Subscriptions to the command, poll interval and birth topics.
+    #[serde(deserialize_with = "deserialize_qos")]
+    command: u8,
+}
+
+#[cfg(feature = "mqtt")]
+impl Default for QosLevels {
+    fn default() -> Self {
+        QosLevels { discovery: 1, state: 0, availability: 1, diagnostics: 0, command: 0 }
+    }
+}
+
+/// Linear transforms of the published measurements, for consumers expecting other scales than
+/// the units offer, such as pressure in whole pascals.
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Transforms {
+    temperature: Option<Transform>,
+    /// Applied to both the reported and the raw pressure.
+    pressure: Option<Transform>,
+    altitude: Option<Transform>,
+}
+
+/// State classes announced in discovery in place of the defaults, which are `measurement` for the
+/// numeric measurements and `total_increasing` for the counters and the uptime, which start over
+/// on a restart.
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct StateClasses {
+    temperature: Option<StateClass>,
+    /// Applies to both the reported and the raw pressure.
+    pressure: Option<StateClass>,
+    altitude: Option<StateClass>,
+    temperature_drift: Option<StateClass>,
+    temperature_rate: Option<StateClass>,
+    pressure_rate: Option<StateClass>,
+    cpu_temperature: Option<StateClass>,
+    uptime: Option<StateClass>,
+    read_latency: Option<StateClass>,
+    reinit_count: Option<StateClass>,
+    pending_states: Option<StateClass>,
+    dropped_states: Option<StateClass>,
+}
+
+/// How HA keeps long-term statistics of an entity.
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum StateClass {
+    Measurement,
+    Total,
+    TotalIncreasing,
+    /// No state class, so HA keeps no statistics.
+    None,
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Transform {
+    /// The value is published as `value * scale + offset`, after converting it to its unit.
+    #[serde(default = "default_transform_scale")]
+    scale: f32,
+    #[serde(default)]
+    offset: f32,
+    /// Decimals of the transformed value, `0` publishing an integer. The measurement's own
+    /// rounding when unset.
+    decimals: Option<usize>,
+    /// Unit announced in discovery for the transformed value, the measurement's unit when unset.
+    unit: Option<String>,
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Attribute {
+    Version,
+    Commit,
+    Built,
+    Target,
+    SamplingMode,
+    SessionId,
+    /// Rated ranges and accuracy of the detected chip, from its datasheet.
+    Capabilities,
+}
+
+#[cfg(feature = "mqtt")]
+fn default_attributes() -> Vec<Attribute> {
+    vec![Attribute::Version, Attribute::Commit, Attribute::Built, Attribute::Target]
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum OnReadFailure {
+    /// Publish nothing, so the entities keep showing the retained last value.
+    #[default]
+    Hold,
+    /// Publish `offline` to the availability topic until a read succeeds. Needs `availability`.
+    Unavailable,
+    /// Publish the last good reading again on every failed read.
+    RepublishLast,
+}
+
+#[cfg(feature = "mqtt")]
+impl MQTT {
+    /// Whether the entities are announced through HA discovery, which the `z2m` layout and
+    /// MessagePack states, which HA can't decode, leave out.
+    fn discovery(&self) -> bool {
+        self.publish_discovery && self.layout != Layout::Z2m && self.payload_encoding == PayloadEncoding::Json
+    }
+}
+
+/// Preset of the topics published to.
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Layout {
+    /// Topics under `homeassistant/sensor/{room}`, announced through HA discovery.
+    #[default]
+    Hass,
+    /// A single JSON state on `sensors/{room}` with the other topics below it, as zigbee2mqtt lays
+    /// them out, and nothing announced through discovery.
+    Z2m,
+    /// The state on `state_topic`, which has to be set, and everything else as with `hass`.
+    Custom,
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum StateFormat {
+    /// All measurements in a single JSON object on one topic.
+    #[default]
+    Json,
+    /// Each measurement as a plain number on its own topic.
+    Split,
+}
+
+/// Encoding of the JSON state format's payload.
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PayloadEncoding {
+    #[default]
+    Json,
+    /// The same object as MessagePack, a fraction of the size for links short on bandwidth. Only
+    /// for custom consumers, as HA can't decode it.
+    Msgpack,
+}
+
+/// Home Assistant's REST API, posted to instead once the MQTT broker has been unreachable for a while.
+#[cfg(feature = "mqtt")]
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct HomeAssistantRest {
+    /// Base URL of Home Assistant, e.g. `http://homeassistant.local:8123`.
+    url: String,
+    /// Long-lived access token, created from the HA user profile.
+    #[serde(serialize_with = "redact")]
+    token: SecretBox<String>,
+    /// How long the broker has to be unreachable before readings are posted over REST.
+    #[serde(default = "default_fallback_after_seconds")]
+    fallback_after_seconds: u64,
+    /// Entity ids are `{entity_prefix}_{measurement}`. Set it to match the entities HA created
+    /// from discovery so both paths update the same ones. Defaults to `sensor.{identifier}`.
+    entity_prefix: Option<String>,
+}
+
+#[cfg(feature = "mqtt")]
+fn default_fallback_after_seconds() -> u64 {
+    60
+}
+
+#[cfg(feature = "mqtt")]
+fn default_post_discovery_delay_ms() -> u64 {
+    250
+}
+
+#[cfg(feature = "mqtt")]
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(feature = "mqtt")]
+fn default_birth_payload() -> String {
+    String::from("online")
+}
+
+#[cfg(feature = "mqtt")]
+fn default_payload_available() -> String {
+    String::from("online")
+}
+
+#[cfg(feature = "mqtt")]
+fn default_payload_not_available() -> String {
+    String::from("offline")
+}
+
+#[cfg(feature = "mqtt")]
+fn default_offline_queue_size() -> usize {
+    60
+}
+
+#[cfg(feature = "mqtt")]
+fn default_mqtt_queue_capacity() -> usize {
+    100
+}
+
+#[cfg(feature = "mqtt")]
+fn default_discovery_retries() -> u32 {
+    3
+}
+
+#[cfg(feature = "mqtt")]
+fn default_state_retries() -> u32 {
+    2
+}
+
+#[cfg(feature = "mqtt")]
+fn default_trigger_hysteresis() -> f32 {
+    0.5
+}
+
+#[cfg(feature = "mqtt")]
+fn default_transform_scale() -> f32 {
+    1.0
+}
+
+#[cfg(feature = "mqtt")]
+fn default_decimal_separator() -> String {
+    String::from(".")
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 struct Logging {
     log_level: Option<String>,
+    timestamps: Option<LogTimestamps>,
+    /// Start every log line with the id of this run, to tell runs apart in collected logs.
+    #[serde(default)]
+    session_id: bool,
+    /// Levels for the log lines of single modules, e.g. `rumqttc`, overriding the level of each
+    /// output as `RUST_LOG` would.
+    #[serde(default)]
+    log_targets: BTreeMap<String, String>,
+    /// Where log lines go, all at once. Defaults to stderr alone.
+    #[serde(default)]
+    outputs: Vec<LogOutput>,
 }
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    #[arg(short, long)]
-    log_level: Option<String>,
+/// One place log lines go. `level` defaults to `log_level`, or what the command line sets.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum LogOutput {
+    /// Standard error, which journald picks up when running as a service.
+    Stderr { level: Option<String> },
+    /// Appended to a file, which is created if missing.
+    File { path: PathBuf, level: Option<String> },
+    /// Sent over UDP to a syslog server at `host:port`, as RFC 5424 messages from the daemon
+    /// facility. Always timestamped in UTC.
+    Syslog { address: String, level: Option<String> },
+}
 
-    #[arg(short, long, default_value_t = String::from("bmp180.toml") )]
-    config: String
+/// How log lines are timestamped.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum LogTimestamps {
+    /// No timestamps, for when something like journald already adds them.
+    Off,
+    /// UTC in RFC 3339.
+    Rfc3339,
+    /// Local time in RFC 3339, with the UTC offset. In `timezone` when one is set, rather than the
+    /// system's.
+    Local,
 }
 
-enum SensorComponent {
-    Temperature,
-    Pressure
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Csv {
+    path: PathBuf,
 }
 
-fn main() -> ExitCode {
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Persistence {
+    /// File the last reading is kept in.
+    path: PathBuf,
+    /// A kept reading older than this isn't published on startup.
+    #[serde(default = "default_persistence_max_age_seconds")]
+    max_age_seconds: u64,
+}
 
-    // Read passed in arguments
-    let args = Args::parse();
+fn default_persistence_max_age_seconds() -> u64 {
+    3600
+}
 
-    // Read config data
-    let config: Data = match read_config(PathBuf::from(&args.config)) {
-        Ok(c) => c,
-        Err(e) => return e
-    };
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct UnixSocket {
+    path: PathBuf,
+}
 
-    // Init logging
-    init_logging(args, &config);
+/// Serves the latest reading as Prometheus metrics on `/metrics`, and a health check on `/health`
+/// that answers 503 when the sensor can't be read.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Http {
+    /// Address and port to listen on, e.g. `127.0.0.1:9180`.
+    listen: String,
+    /// Start of every metric name, e.g. `home` for `home_temperature_celsius`.
+    #[serde(default = "default_metrics_prefix")]
+    metrics_prefix: String,
+    /// Room the sensor is in, given as a `room` label on every metric. Defaults to `[mqtt] room`.
+    room: Option<String>,
+    /// Put the room into the metric names instead, as in `home_hallway_temperature_celsius`, for
+    /// scrape configs that expect it there.
+    #[serde(default)]
+    room_in_name: bool,
+    /// How long `/health` answers "starting" with a 200 while waiting for the first good read,
+    /// before it turns unhealthy. For orchestrators that tell startup and liveness probes apart.
+    #[serde(default = "default_health_startup_grace_seconds")]
+    health_startup_grace_seconds: u64,
+    /// `/health` turns unhealthy once the last good read is older than this. Only a lost sensor
+    /// does when unset.
+    health_max_read_age_seconds: Option<u64>,
+}
 
-    info!("Starting BMP180 Temperature/Pressure Sensor");
+fn default_metrics_prefix() -> String {
+    String::from("bmp180")
+}
 
-    let i2c_dev = match LinuxI2CDevice::new("/dev/i2c-1", BMP085_I2C_ADDR) {
-        Ok(x) => x,
-        Err(_) => {
-            error!("Cannot initialize I2C device.");
-            return ExitCode::FAILURE
-        },
-    };
+fn default_health_startup_grace_seconds() -> u64 {
+    60
+}
 
-    let Ok(sensor) = BMP085BarometerThermometer::new(i2c_dev, SamplingMode::UltraHighRes) else {
-        error!("Can't initialize PMB180 thermostat sensor");
-        return ExitCode::FAILURE;
-    };
+impl Http {
+    fn room<'a>(&'a self, config: &'a Data) -> Option<&'a str> {
+        #[cfg(feature = "mqtt")]
+        let fallback = Some(config.mqtt.room.as_str());
+        #[cfg(not(feature = "mqtt"))]
+        let fallback = {
+            let _ = config;
+            None
+        };
+        self.room.as_deref().or(fallback)
+    }
+}
 
-    let (client, connection) =  get_mqtt_client(&config);
+#[derive(Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct Sensor {
+    sampling_mode: SensorSamplingMode,
+    /// Seconds between readings.
+    poll_interval: f64,
+    /// Read on multiples of the interval since midnight UTC, e.g. on the minute every 60 seconds,
+    /// so the readings of several sensors line up.
+    align_to_clock: bool,
+    /// Seconds between temperature readings, when it should differ from `poll_interval`.
+    temperature_interval: Option<f64>,
+    /// Seconds between pressure readings, when it should differ from `poll_interval`.
+    pressure_interval: Option<f64>,
+    /// Daily window readings are taken in. Outside it nothing is read or published, except on a
+    /// `read` command.
+    active_hours: Option<ActiveHours>,
+    /// Added to every temperature reading, in °C, to correct for a sensor that reads off.
+    temperature_offset: f32,
+    /// Two-point calibration against a reference thermometer, correcting a sensor whose error
+    /// varies across the range. Each pair is what the reference showed (`ref`) and what the sensor
+    /// read (`raw`), in °C. Applied before `temperature_offset`. Either all four are set or none.
+    cal_low_ref: Option<f32>,
+    cal_low_raw: Option<f32>,
+    cal_high_ref: Option<f32>,
+    cal_high_raw: Option<f32>,
+    /// Units the temperature is published in, one entity each. Celsius keeps the `temperature`
+    /// ids, the others are suffixed with the unit, e.g. `temperature_fahrenheit`.
+    temperature_units: Vec<TemperatureUnit>,
+    /// Picks the temperature and altitude units together, replacing `temperature_units` and
+    /// `altitude_unit`. The pressure is always published in kPa, which HA converts itself.
+    unit_system: Option<UnitSystem>,
+    /// Temperature file of a second sensor to cross-check against, such as a DS18B20 at
+    /// `/sys/bus/w1/devices/28-*/temperature`. The difference is published as a diagnostic.
+    reference_temperature_path: Option<PathBuf>,
+    /// Difference from the reference, in °C, above which a drift warning is logged.
+    drift_threshold: f32,
+    /// Publish the temperature as a comfort band too, cold, cool, comfortable, warm or hot. Each
+    /// bound, in °C, is where the next band starts, e.g. `[16.0, 19.0, 24.0, 27.0]`.
+    comfort_bands: Option<[f32; 4]>,
+    /// Decimals of the published temperature. Only the payload is rounded, the values kept
+    /// between readings and written to the CSV file keep their full precision.
+    temperature_decimals: Option<usize>,
+    /// Added to every pressure reading, in kPa.
+    pressure_offset: f32,
+    /// Bounds pressure readings are clamped to, in kPa. Default to the BMP180's 30 to 110 kPa
+    /// operating range; narrow them to what is plausible at the install's elevation to catch
+    /// garbage readings sooner.
+    pressure_min: f32,
+    pressure_max: f32,
+    /// Fall in pressure, in kPa, within `pressure_drop_window_seconds` that turns on a rapid drop
+    /// binary sensor, for storm alerts. Unset leaves the entity out.
+    pressure_drop_threshold: Option<f32>,
+    pressure_drop_window_seconds: u64,
+    /// Publish how fast the temperature and pressure change per minute, fitted to the readings of
+    /// the last `rate_window_seconds` so the noise of single readings doesn't swing it.
+    rate_of_change: bool,
+    rate_window_seconds: u64,
+    /// Discard the whole reading when the temperature is outside the sensor's operating range,
+    /// rather than clamping it, as the pressure derived from it is just as wrong.
+    consistency_check: bool,
+    /// Publish the temperature and pressure only as a pair from the same read, failing the whole
+    /// read when one of them couldn't be taken, rather than publishing the other on its own and
+    /// deriving the sea level pressure from an earlier temperature.
+    paired_reads: bool,
+    /// Reads taken back to back and averaged for each published value, reducing noise without
+    /// lengthening the interval. Each read takes the sampling mode's conversion time.
+    oversample: u32,
+    /// Filter applied to the readings before they are published, across reads unlike `oversample`.
+    smoothing: Smoothing,
+    /// Weight of the newest reading with `ema` smoothing, above 0 and at most 1. Lower values damp
+    /// more and follow real changes more slowly.
+    smoothing_alpha: f32,
+    /// Seconds to wait for the sensor to answer before counting the read as failed.
+    read_timeout: f64,
+    /// Times a read that failed, or got only one of the measurements, is retried before counting
+    /// as failed, waiting `read_retry_delay_ms` in between. Helps with the occasional garbled
+    /// transfer over long wires.
+    read_retries: u32,
+    read_retry_delay_ms: u64,
+    /// Speed the I2C bus is expected to run at, in Hz. Linux sets it in the kernel driver rather
+    /// than per device, so it is only checked at startup, with a warning when it differs.
+    i2c_bus_speed_hz: Option<u32>,
+    /// Check whether the sensor still answers on the bus after this many consecutive failed
+    /// reads, to tell a wiring fault from a flaky read. 0 disables the check.
+    probe_after_failures: u32,
+    /// Times initializing the sensor at startup is retried, `init_retry_delay_seconds` apart, for a
+    /// bus or chip that isn't ready yet right after booting.
+    init_retries: u32,
+    init_retry_delay_seconds: u64,
+    /// Exit at once when nothing answers at the sensor's address at startup, rather than spend
+    /// `init_retries` on a sensor that isn't connected, so an orchestrator notices promptly.
+    fail_fast_on_no_sensor: bool,
+    /// Rebuild the sensor after this many consecutive failed reads. 0 disables reinitialization.
+    reinit_after_failures: u32,
+    /// Readings thrown away after the sensor was rebuilt, as the first is often still bad.
+    discard_after_reinit: u32,
+    /// Delay before reinitializing again while reads keep failing, doubled after every attempt up
+    /// to `reinit_backoff_max_seconds`, so a sensor that came loose isn't rebuilt every few
+    /// seconds forever. Starts over once a read succeeds.
+    reinit_backoff_seconds: u64,
+    reinit_backoff_max_seconds: u64,
+    /// Give up after this many reinitializations in a row that didn't bring the sensor back,
+    /// instead of retrying forever.
+    max_reinit_attempts: Option<u32>,
+    on_reinit_exhausted: OnReinitExhausted,
+    /// Throw away readings at startup until two consecutive temperatures are within this many °C
+    /// of each other, as the sensor reads off until it has settled to its surroundings. Publishing
+    /// starts regardless after `stabilize_timeout_seconds`.
+    stabilize_tolerance: Option<f32>,
+    stabilize_timeout_seconds: u64,
+    /// Publish an altitude entity computed from the pressure reading.
+    altitude: bool,
+    altitude_unit: AltitudeUnit,
+    altitude_decimals: usize,
+    /// Publish the pressure altitude, computed from the station pressure against the standard
+    /// 101.325 kPa rather than `sea_level_pressure`.
+    pressure_altitude: bool,
+    /// Publish the density altitude, computed from the station pressure and the temperature.
+    density_altitude: bool,
+    /// Sea-level reference pressure in kPa. Adjust this to calibrate the altitude reading against a
+    /// known elevation.
+    sea_level_pressure: f32,
+    /// Bounds in meters outside of which the altitude isn't published, as it most likely comes from
+    /// a wrong `sea_level_pressure`. Default to the Dead Sea shore and a little above Everest.
+    altitude_min: f32,
+    altitude_max: f32,
+    /// Which pressure is published. The altitude entity is always computed from station pressure.
+    pressure_reference: PressureReference,
+    /// Elevation of the sensor above sea level in meters, for reducing pressure to sea level.
+    station_elevation: f32,
+    /// Also publish the station pressure as measured, as an entity of its own next to the one
+    /// reduced to `pressure_reference`.
+    raw_pressure: bool,
+}
 
-    thread::spawn(move || {read_and_publish_data(sensor, client, config)});
-    poll_for_events(connection);
+/// What happens once `max_reinit_attempts` reinitializations didn't bring the sensor back.
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum OnReinitExhausted {
+    /// Exit with a failure, for the service manager to restart or report.
+    #[default]
+    Exit,
+    /// Stop reinitializing and keep reading, with the entities unavailable until a read succeeds
+    /// whatever `on_read_failure` is.
+    Unavailable,
+}
 
-    return ExitCode::SUCCESS;
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Smoothing {
+    /// Publish every reading as it is.
+    #[default]
+    None,
+    /// Exponential moving average, keeping a single running value per measurement.
+    Ema,
 }
 
-fn read_and_publish_data(mut sensor: BMP085BarometerThermometer<LinuxI2CDevice>, client: Client, config: Data) -> ExitCode {
-    info!("Starting read and publish thread");
-    
-    match publish_sensor_discovery_messages(&client, &config) {
-        Ok(_) => (),
-        Err(_) => {
-            return ExitCode::FAILURE
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PressureReference {
+    /// As measured at the sensor, also known as QFE.
+    #[default]
+    Station,
+    /// Reduced to sea level through the standard atmosphere, as used in aviation.
+    Qnh,
+    /// Reduced to sea level using the measured temperature, as used on weather maps.
+    Qff,
+}
+
+impl Sensor {
+    fn temperature_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.temperature_interval.unwrap_or(self.poll_interval))
+    }
+
+    fn pressure_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.pressure_interval.unwrap_or(self.poll_interval))
+    }
+
+    /// Slope and intercept of the two-point temperature calibration, if one is configured.
+    fn temperature_calibration(&self) -> Result<Option<(f32, f32)>, &'static str> {
+        match (self.cal_low_ref, self.cal_low_raw, self.cal_high_ref, self.cal_high_raw) {
+            (None, None, None, None) => Ok(None),
+            (Some(low_ref), Some(low_raw), Some(high_ref), Some(high_raw)) => {
+                if low_raw == high_raw {
+                    return Err("cal_low_raw and cal_high_raw must differ");
+                }
+                let slope = (high_ref - low_ref) / (high_raw - low_raw);
+                Ok(Some((slope, low_ref - slope * low_raw)))
+            },
+            _ => Err("cal_low_ref, cal_low_raw, cal_high_ref and cal_high_raw must be set together"),
         }
-    };
-    
-    loop {
-        thread::sleep(Duration::from_secs(1));
-        let Ok((temp, pressure)) = read_from_sensor(&mut sensor) else {
-            error!("Cannot initialize I2C device.");
-            return ExitCode::FAILURE;
-        };
+    }
 
-        match publish_sensor_data(&client, &config, temp, pressure) {
-            Ok(_) => (),
-            Err(_) => {
-                return ExitCode::FAILURE
-            }
+    fn pressure_range(&self) -> RangeInclusive<f32> {
+        self.pressure_min..=self.pressure_max
+    }
+
+    fn altitude_range(&self) -> RangeInclusive<f32> {
+        self.altitude_min..=self.altitude_max
+    }
+
+    /// Shortest poll interval the sensor can keep up with: the conversion time of the sampling
+    /// mode, times the number of reads averaged.
+    fn min_poll_interval(&self) -> Duration {
+        self.sampling_mode.conversion_time() * self.oversample.max(1)
+    }
+
+    /// Shortest interval temperature-only reads can keep up with. The BMP180 converts the
+    /// temperature the same way in every sampling mode, so this doesn't depend on it.
+    fn min_temperature_interval(&self) -> Duration {
+        TEMPERATURE_CONVERSION_TIME * self.oversample.max(1)
+    }
+
+    fn read_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.read_timeout)
+    }
+}
+
+impl Default for Sensor {
+    fn default() -> Self {
+        Sensor {
+            sampling_mode: SensorSamplingMode::UltraHighRes,
+            poll_interval: 1.0,
+            align_to_clock: false,
+            temperature_interval: None,
+            pressure_interval: None,
+            active_hours: None,
+            temperature_offset: 0.0,
+            cal_low_ref: None,
+            cal_low_raw: None,
+            cal_high_ref: None,
+            cal_high_raw: None,
+            temperature_units: vec![TemperatureUnit::Celsius],
+            unit_system: None,
+            reference_temperature_path: None,
+            drift_threshold: 2.0,
+            comfort_bands: None,
+            temperature_decimals: None,
+            pressure_offset: 0.0,
+            pressure_min: *PRESSURE_RANGE.start(),
+            pressure_max: *PRESSURE_RANGE.end(),
+            pressure_drop_threshold: None,
+            pressure_drop_window_seconds: 600,
+            rate_of_change: false,
+            rate_window_seconds: 300,
+            consistency_check: false,
+            paired_reads: false,
+            oversample: 1,
+            smoothing: Smoothing::None,
+            smoothing_alpha: 0.3,
+            read_timeout: 1.0,
+            read_retries: 0,
+            read_retry_delay_ms: 50,
+            i2c_bus_speed_hz: None,
+            probe_after_failures: 3,
+            init_retries: 0,
+            init_retry_delay_seconds: 5,
+            fail_fast_on_no_sensor: true,
+            reinit_after_failures: 5,
+            discard_after_reinit: 1,
+            reinit_backoff_seconds: 30,
+            reinit_backoff_max_seconds: 900,
+            max_reinit_attempts: None,
+            on_reinit_exhausted: OnReinitExhausted::Exit,
+            stabilize_tolerance: None,
+            stabilize_timeout_seconds: 600,
+            altitude: false,
+            altitude_unit: AltitudeUnit::Meters,
+            altitude_decimals: 1,
+            pressure_altitude: false,
+            density_altitude: false,
+            sea_level_pressure: 101.325,
+            altitude_min: -500.0,
+            altitude_max: 9000.0,
+            pressure_reference: PressureReference::Station,
+            station_elevation: 0.0,
+            raw_pressure: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+enum SensorSamplingMode {
+    UltraLowPower,
+    Standard,
+    HighRes,
+    UltraHighRes,
+}
+
+/// Time the sensor spends converting a temperature, rounded up from the 4.5 ms of the datasheet.
+/// The sampling mode only sets the oversampling of pressure conversions.
+const TEMPERATURE_CONVERSION_TIME: Duration = Duration::from_millis(5);
+
+impl SensorSamplingMode {
+    /// Time the sensor spends converting a temperature and pressure reading in this mode. Reading
+    /// pressure converts the temperature a second time, per the BMP180 datasheet algorithm.
+    fn conversion_time(&self) -> Duration {
+        let pressure_ms = match self {
+            SensorSamplingMode::UltraLowPower => 5,
+            SensorSamplingMode::Standard => 8,
+            SensorSamplingMode::HighRes => 14,
+            SensorSamplingMode::UltraHighRes => 26,
         };
+        TEMPERATURE_CONVERSION_TIME * 2 + Duration::from_millis(pressure_ms)
     }
 }
 
-fn poll_for_events(mut connection: Connection) {
-    loop {
-        debug!("Polling for events");
-        for notification in connection.iter() {
-            match notification {
-                Ok(Event::Incoming(Incoming::Connect(c))) => debug!("Connected to MQTT broker {}", c.client_id),
-                Ok(e) => {
-                    debug!("Got event: {:?}", e);
-                },
-                Err(e) => {
-                    error!("Got an error when polling for events: {}", e.to_string());
-                },
-            }
+/// Start and end, as `HH:MM` on the wall clock of the configured timezone. The window runs across
+/// midnight when the end comes before the start, and includes the start but not the end. On the
+/// days the clocks change it keeps to the wall clock, so it is an hour shorter or longer, and a
+/// start or end in the skipped hour takes effect at the first time after it.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ActiveHours {
+    start: String,
+    end: String,
+}
+
+impl ActiveHours {
+    fn window(&self) -> Result<(Time, Time), String> {
+        let parse = |time: &str| {
+            let parsed = time.split_once(':')
+                .and_then(|(hour, minute)| Some((hour.parse().ok()?, minute.parse().ok()?)))
+                .and_then(|(hour, minute)| Time::from_hms(hour, minute, 0).ok());
+            parsed.ok_or_else(|| format!("`{}` isn't a time of day as HH:MM", time))
+        };
+        let (start, end) = (parse(&self.start)?, parse(&self.end)?);
+        if start == end {
+            return Err(String::from("start and end have to differ, leave active_hours out to read around the clock"));
+        }
+        Ok((start, end))
+    }
+
+    fn contains(&self, time: Time) -> bool {
+        // Checked at startup
+        let Ok((start, end)) = self.window() else {
+            return true;
+        };
+        if start < end {
+            start <= time && time < end
+        } else {
+            time >= start || time < end
         }
     }
 }
 
-fn init_logging(args: Args, config: &Data) {
-    let log_level = args.log_level.unwrap_or(
-        config.logging.log_level.clone().unwrap_or(
-            String::from("Info")
-        ));
+impl From<SensorSamplingMode> for SamplingMode {
+    fn from(mode: SensorSamplingMode) -> Self {
+        match mode {
+            SensorSamplingMode::UltraLowPower => SamplingMode::UltraLowPower,
+            SensorSamplingMode::Standard => SamplingMode::Standard,
+            SensorSamplingMode::HighRes => SamplingMode::HighRes,
+            SensorSamplingMode::UltraHighRes => SamplingMode::UltraHighRes,
+        }
+    }
+}
 
-    env_logger::builder().filter_level(log_level.parse().unwrap()).init();
+#[derive(Deserialize, Serialize, Clone, Copy)]
+enum TemperatureUnit {
+    #[serde(rename = "C")]
+    Celsius,
+    #[serde(rename = "F")]
+    Fahrenheit,
 }
 
-fn read_config(config: PathBuf) -> Result<Data, ExitCode> {
-    let contents = match fs::read_to_string(&config) {
-        Ok(c) => c,
-        Err(_) => {
-            error!("Could not read file `{}`", config.to_str().unwrap());
-            return Err(ExitCode::FAILURE);
+impl From<TemperatureUnit> for Unit {
+    fn from(unit: TemperatureUnit) -> Self {
+        match unit {
+            TemperatureUnit::Celsius => Unit::Celsius,
+            TemperatureUnit::Fahrenheit => Unit::Fahrenheit,
         }
-    };
+    }
+}
 
-    let data: Data = match toml::from_str(&contents) {
-        Ok(d) => d,
-        Err(e) => {
-            error!("Unable to load data from `{}` due to error: {}", config.to_str().unwrap(), e.message());
-            return Err(ExitCode::FAILURE);
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum UnitSystem {
+    Metric,
+    Imperial,
+    /// Whichever of the two HA is set to, asked through `[homeassistant_rest]` at startup.
+    FromHa,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+enum AltitudeUnit {
+    #[serde(rename = "m")]
+    Meters,
+    #[serde(rename = "ft")]
+    Feet,
+}
+
+impl From<AltitudeUnit> for Unit {
+    fn from(unit: AltitudeUnit) -> Self {
+        match unit {
+            AltitudeUnit::Meters => Unit::Meters,
+            AltitudeUnit::Feet => Unit::Feet,
         }
-    };
+    }
+}
 
-    Ok(data)
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(short, long)]
+    log_level: Option<String>,
+
+    /// Only log errors, overriding `[logging] log_level`. For cron jobs and scripts, e.g. with
+    /// `--once --json`
+    #[arg(short, long, conflicts_with = "log_level")]
+    quiet: bool,
+
+    /// How to timestamp log lines, overriding `[logging] timestamps` [default: rfc3339]
+    #[arg(long, value_enum)]
+    log_timestamps: Option<LogTimestamps>,
+
+    /// Config file, or directory whose `.toml` files are read in name order. Repeat to layer
+    /// several, with later files overriding the fields they set
+    #[arg(short, long, default_value = "bmp180.toml")]
+    config: Vec<PathBuf>,
+
+    /// Publish the rows of a CSV file written by the CSV sink instead of reading the sensor, one
+    /// row per poll interval
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    /// Start over from the first row once the replay file ends instead of exiting
+    #[arg(long, requires = "replay")]
+    replay_loop: bool,
+
+    /// Print every MQTT topic the config publishes or subscribes to and exit without connecting
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    print_topics: bool,
+
+    /// Print the Home Assistant `mqtt:` YAML setting up the entities discovery would, for
+    /// configuring them by hand in `configuration.yaml`, and exit without connecting
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    emit_ha_yaml: bool,
+
+    /// Print the config this run would use, after layering the config files and filling in the
+    /// defaults, as TOML with secrets redacted and exit
+    #[arg(long)]
+    dump_config: bool,
+
+    /// Print the discovery messages retained on the broker for this config, flagging where they
+    /// differ from what would be published now, and exit
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    inspect_discovery: bool,
+
+    /// Connect to the broker, wait for it to accept the credentials and disconnect again, without
+    /// publishing or subscribing to anything, and exit with a failure code if it didn't
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    validate_broker: bool,
+
+    /// Clear the retained discovery messages this config publishes, and only those, so HA removes
+    /// the entities, and exit. Run it with the config the entities were created with
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    purge_discovery: bool,
+
+    /// Log every MQTT packet sent and received, along with rumqttc's own trace output, without
+    /// raising the log level of everything else
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    trace_mqtt: bool,
+
+    /// Take a single reading, print it to stdout and exit without connecting to the broker
+    #[arg(long)]
+    once: bool,
+
+    /// With --once, print the reading as a JSON object and nothing else on stdout
+    #[arg(long, requires = "once")]
+    json: bool,
+
+    /// Check the config, read the sensor once and publish a test message to the broker, print a
+    /// pass or fail line for each and exit, with a failure code if any failed
+    #[arg(long, conflicts_with_all = ["once", "replay"])]
+    self_test: bool,
+
+    /// Read the sensor as fast as possible for SECONDS, print the throughput and latency and exit
+    /// without publishing anything
+    #[arg(long, value_name = "SECONDS", conflicts_with_all = ["once", "replay"])]
+    benchmark: Option<u64>,
+
+    /// Read the sensor as fast as possible for SECONDS, print the standard deviation and
+    /// peak-to-peak of the raw, uncalibrated readings to gauge its noise floor, and exit
+    #[arg(long, value_name = "SECONDS", conflicts_with_all = ["once", "replay", "benchmark"])]
+    burn_in: Option<u64>,
+
+    /// Keep reading the sensor at the poll interval and print each reading to stdout, with the
+    /// calibration, units and rounding applied, without connecting to the broker. Stop with Ctrl-C
+    #[arg(long, conflicts_with_all = ["once", "replay", "self_test", "benchmark", "burn_in", "daemonize"])]
+    config_test_sensor: bool,
+
+    /// Show a live dashboard of the readings, their recent trend, the broker connection and the
+    /// error counts instead of logging to stderr, while publishing as usual. Stop with Ctrl-C
+    #[arg(long, conflicts_with_all = ["once", "self_test", "benchmark", "burn_in", "config_test_sensor", "daemonize"])]
+    tui: bool,
+
+    /// Write the process id to this file, removing it again on a clean shutdown
+    #[arg(long, value_name = "PATH")]
+    pidfile: Option<PathBuf>,
+
+    /// Detach from the terminal and run in the background once started. Logs still go to stderr,
+    /// which the init script should redirect
+    #[arg(long, conflicts_with = "foreground")]
+    daemonize: bool,
+
+    /// Stay attached to the terminal (the default)
+    #[arg(long)]
+    foreground: bool,
+
+    /// Wait until the broker host resolves before connecting, giving up after SECONDS
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "120")]
+    wait_for_network: Option<u64>,
+
+    #[cfg(feature = "mqtt")]
+    #[command(flatten)]
+    broker: BrokerOverrides,
 }
 
-fn get_mqtt_client(config: &Data) -> (Client, Connection) {
-    let mut mqttoptions = MqttOptions::new(&config.mqtt.name, &config.mqtt_broker.host, config.mqtt_broker.port);
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
-    mqttoptions.set_credentials(&config.mqtt_broker.username, config.mqtt_broker.password.expose_secret());
+/// Broker settings given on the command line, which take precedence over `[mqtt_broker]`,
+/// also on reload.
+#[cfg(feature = "mqtt")]
+#[derive(clap::Args, Clone)]
+struct BrokerOverrides {
+    /// Broker host, overriding `[mqtt_broker] host`
+    #[arg(long)]
+    host: Option<String>,
+
+    /// Broker port, overriding `[mqtt_broker] port`
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Broker username, overriding `[mqtt_broker] username`
+    #[arg(long)]
+    username: Option<String>,
 
-    let (client, connection) = Client::new(mqttoptions, 10);
+    /// Broker password, overriding `[mqtt_broker] password`. Visible to other users of the
+    /// machine in the process list, so only meant for testing
+    #[arg(long)]
+    password: Option<String>,
 
-    (client, connection)
+    /// Connect with a random suffix on the client id, so a test run against a shared broker
+    /// doesn't kick the long-running instance using the same config off it. Leave it off for
+    /// steady-state runs, which should keep their stable client id
+    #[arg(long)]
+    ephemeral_client_id: bool,
 }
 
-fn read_from_sensor(sensor: &mut BMP085BarometerThermometer<LinuxI2CDevice>) -> Result<(f32, f32), Box<dyn Error>> {
-    let temp = sensor.temperature_celsius()?;
-    let pressure = sensor.pressure_kpa()?;
+#[cfg(feature = "mqtt")]
+impl std::fmt::Debug for BrokerOverrides {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BrokerOverrides")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("ephemeral_client_id", &self.ephemeral_client_id)
+            .finish()
+    }
+}
 
-    debug!("Read sensor data. Temp: [{}]. Pressure: [{}].", temp, pressure);
-    Ok((temp, pressure))
+/// Removes the PID file when dropped.
+struct PidFile {
+    path: PathBuf,
 }
 
-fn publish_sensor_data(client: &Client, config: &Data, temp: f32, pressure: f32) -> Result<(), ExitCode> {
-    let topic = format!("homeassistant/sensor/{}/state", config.mqtt.room);
-    debug!("Publishing sensor data to topic [{}]", topic);
-    let msg = get_state_message(temp, pressure);
-    match client.publish(topic, QoS::AtMostOnce, true, msg) {
-        Ok(_) => return Ok(()),
-        Err(e) => {
-            error!("Failed to publish sensor state message due to error: {}", e);
-            return Err(ExitCode::FAILURE);
+impl PidFile {
+    fn create(path: PathBuf) -> Result<PidFile, io::Error> {
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove PID file `{}`: {}", self.path.display(), e);
         }
-    };
+    }
 }
 
-fn publish_sensor_discovery_messages(client: &Client, config: &Data) -> Result<(), ExitCode> {
-    publish_temperature_discovery_message(client, config)?;
-    publish_pressure_discovery_message(client, config)?;
-    return Ok(());
+/// A single sample taken from the sensor. Measurements that weren't due this cycle are left out.
+#[derive(Clone, Copy)]
+struct Reading {
+    temperature: Option<f32>,
+    pressure: Option<f32>,
+    /// How long the sensor took to produce the sample.
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    read_latency: Duration,
+    /// Temperature minus that of the reference sensor, when one is configured.
+    temperature_drift: Option<f32>,
+    /// The measurements as the sensor gave them, before calibration and offsets.
+    raw_temperature: Option<f32>,
+    raw_pressure: Option<f32>,
+    /// Measurements that were due but failed while the other one was read.
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    temperature_failed: bool,
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    pressure_failed: bool,
 }
 
-fn publish_temperature_discovery_message(client: &Client, config: &Data) -> Result<(), ExitCode> {
-    let topic = format!("homeassistant/sensor/{}Temperature/config", config.mqtt.room);
-    debug!("Publishing sensor temperature discovery message to topic [{}]", topic);
-    let msg = get_discovery_message(config, SensorComponent::Temperature);
-    match client.publish(topic, QoS::AtMostOnce, true, msg) {
-        Ok(_) => return Ok(()),
-        Err(e) => {
-            error!("Failed to publish temerature discovery message due to error: {}", e);
-            return Err(ExitCode::FAILURE);
-        }
-    };
+/// A read that produced nothing, with the error of each measurement that was attempted, as the two
+/// conversions fail for different reasons.
+#[derive(Debug)]
+struct ReadError {
+    temperature: Option<LinuxI2CError>,
+    pressure: Option<LinuxI2CError>,
 }
 
-fn publish_pressure_discovery_message(client: &Client, config: &Data) -> Result<(), ExitCode> {
-    let topic = format!("homeassistant/sensor/{}Pressure/config", config.mqtt.room);
-    debug!("Publishing sensor pressure discovery message to topic [{}]", topic);
-    let msg = get_discovery_message(config, SensorComponent::Pressure);
-    match client.publish(topic, QoS::AtMostOnce, true, msg) {
-        Ok(_) => return Ok(()),
-        Err(e) => {
-            error!("Failed to publish pressure discovery message due to error: {}", e);
-            return Err(ExitCode::FAILURE);
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.temperature, &self.pressure) {
+            (Some(temperature), Some(pressure)) => write!(f, "temperature: {}; pressure: {}", temperature, pressure),
+            (Some(temperature), None) => write!(f, "temperature: {}", temperature),
+            (None, Some(pressure)) => write!(f, "pressure: {}", pressure),
+            (None, None) => write!(f, "nothing was read"),
         }
-    };
+    }
 }
 
-fn get_discovery_message(config: &Data, sensor_component: SensorComponent) -> String {
-    let (sensor_component_str, value_template_str, unit_str) = match sensor_component {
-        SensorComponent::Temperature => ("temperature", "value_json.temperature", "°C"),
-        SensorComponent::Pressure => ("pressure", "value_json.pressure", "kPa")
-    };
+impl Error for ReadError {}
+
+/// Counters shared between threads, exposed for diagnostics.
+#[derive(Default)]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+struct Stats {
+    rate_limited_publishes: AtomicU64,
+    /// State messages dropped on a full queue, with `publish_blocking` off.
+    dropped_publishes: AtomicU64,
+    /// State messages waiting for room in the MQTT queue, with `max_pending_states`.
+    pending_states: AtomicU64,
+    /// Pending state messages dropped for newer ones.
+    dropped_pending_states: AtomicU64,
+    /// Failed sensor reads.
+    read_failures: AtomicU64,
+    /// Messages that never made it to the broker, such as state dropped while disconnected.
+    publish_failures: AtomicU64,
+    /// Connections to the broker after the first.
+    reconnects: AtomicU64,
+    /// Messages the event loop has written to the broker connection.
+    publishes_sent: AtomicU64,
+    /// Times the sensor was rebuilt after repeated failed reads.
+    sensor_reinits: AtomicU64,
+    /// Id read from the chip, 0 until one has been read.
+    chip_id: AtomicU8,
+    /// Read from the chip at startup.
+    coefficients: OnceLock<Coefficients>,
+}
+
+/// Factory calibration the BMP180 stores in its EEPROM, named as in the datasheet.
+#[derive(Serialize, Clone, Copy, Debug)]
+struct Coefficients {
+    ac1: i16,
+    ac2: i16,
+    ac3: i16,
+    ac4: u16,
+    ac5: u16,
+    ac6: u16,
+    b1: i16,
+    b2: i16,
+    mb: i16,
+    mc: i16,
+    md: i16,
+}
+
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+impl Stats {
+    /// Zeroes the counters, keeping what was read from the chip.
+    fn reset(&self) {
+        self.rate_limited_publishes.store(0, Ordering::Relaxed);
+        self.dropped_publishes.store(0, Ordering::Relaxed);
+        self.dropped_pending_states.store(0, Ordering::Relaxed);
+        self.read_failures.store(0, Ordering::Relaxed);
+        self.publish_failures.store(0, Ordering::Relaxed);
+        self.reconnects.store(0, Ordering::Relaxed);
+        self.sensor_reinits.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Config baked in at build time, used when none of the config files exist.
+#[cfg(feature = "embedded-config")]
+const EMBEDDED_CONFIG: &str = include_str!(concat!(env!("OUT_DIR"), "/embedded_config.toml"));
+
+const I2C_BUS_PATH: &str = "/dev/i2c-1";
+const CHIP_ID_REGISTER: u8 = 0xD0;
+/// First of the 11 big-endian words of calibration coefficients.
+const COEFFICIENTS_REGISTER: u8 = 0xAA;
+const BMP180_CHIP_ID: u8 = 0x55;
+/// How long checking on a sensor that stopped answering, scan of the bus included, may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Poll interval above which unretained states leave HA showing unknown for long after it restarts.
+#[cfg(feature = "mqtt")]
+const UNRETAINED_INTERVAL_WARNING: Duration = Duration::from_secs(60);
+/// Operating range of the BMP180 from its datasheet, in °C.
+const TEMPERATURE_RANGE: RangeInclusive<f32> = -40.0..=85.0;
+/// Longest poll interval that can be set remotely, in seconds.
+const POLL_INTERVAL_MAX: f64 = 3600.0;
+/// Operating range of the BMP180 from its datasheet, in kPa.
+const PRESSURE_RANGE: RangeInclusive<f32> = 30.0..=110.0;
 
-    let discovery_msg = format!("\
-{{  
-   \"device_class\":\"{0}\",
-   \"state_topic\":\"homeassistant/sensor/{2}/state\",
-   \"unit_of_measurement\":\"{5}\",
-   \"value_template\":\"{{{{ {1} }}}}\",
-   \"unique_id\":\"{3}_{0}\",
-   \"device\":{{
-      \"identifiers\":[
-          \"{3}\"
-      ],
-      \"name\":\"{4}\"
-    }}
-}}
-",
-    sensor_component_str,
-    value_template_str,
-    config.mqtt.room,
-    config.mqtt.identifier,
-    config.mqtt.name,
-    unit_str);
-
-    return discovery_msg;
-}
-
-fn get_state_message(temp: f32, pressure: f32) -> String {
-    let state_msg = format!("\
-{{  
-   \"temperature\": {},
-   \"pressure\": {}
-}}
-", temp, pressure);
-    return state_msg;
-}
\ No newline at end of file
+type Bmp180 = BMP085BarometerThermometer<LinuxI2CDevice>;
+
+/// Where readings come from.
+enum Source {
+    Sensor(I2cWorker<Bmp180>),
+    Replay(csv::CsvReplay),
+}
+
+/// Requests sent from the MQTT event loop to the read and publish thread.
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+enum LoopSignal {
+    /// The connection to the broker was (re)established.
+    Connected,
+    RepublishDiscovery,
+    /// Take a reading immediately instead of waiting for the next interval.
+    ReadNow,
+    /// Zero the diagnostic counters, then take a reading to publish them.
+    ResetStats,
+    /// Start or stop handing readings to the named sink.
+    SetSinkEnabled(String, bool),
+    /// Poll at this interval, in seconds, from now on. Sinks are handed it once bounded.
+    SetPollInterval(f64),
+    /// The connection was rebuilt, after the broker settings changed or its event loop stopped.
+    #[cfg(feature = "mqtt")]
+    ClientChanged(rumqttc::Client),
+    /// Stop reading, the process is shutting down.
+    #[cfg(feature = "mqtt")]
+    Shutdown,
+}
+
+fn main() -> ExitCode {
+
+    // Read passed in arguments
+    let args = Args::parse();
+
+    // Read config data
+    let config_paths = args.config.clone();
+    let mut config: Data = match read_config(&config_paths) {
+        Ok(c) => c,
+        Err(e) => {
+            if args.self_test {
+                println!("FAIL  config: {}", e);
+            }
+            // Logging is set up from the config, so this one error goes out with the defaults
+            env_logger::builder().filter_level(log::LevelFilter::Info).init();
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    #[cfg(feature = "mqtt")]
+    let broker_overrides = args.broker.clone();
+    #[cfg(feature = "mqtt")]
+    config.mqtt_broker.apply_overrides(&broker_overrides);
+
+    let replay = args.replay.clone();
+    let replay_loop = args.replay_loop;
+    #[cfg(feature = "mqtt")]
+    let print_topics = args.print_topics;
+    #[cfg(feature = "mqtt")]
+    let emit_ha_yaml = args.emit_ha_yaml;
+    #[cfg(feature = "mqtt")]
+    let inspect_discovery = args.inspect_discovery;
+    #[cfg(feature = "mqtt")]
+    let validate_broker = args.validate_broker;
+    #[cfg(feature = "mqtt")]
+    let purge_discovery = args.purge_discovery;
+    let once = args.once;
+    let json = args.json;
+    let benchmark = args.benchmark;
+    let burn_in = args.burn_in;
+    let self_test = args.self_test;
+    let dump_config = args.dump_config;
+    let config_test_sensor = args.config_test_sensor;
+    let tui = args.tui;
+    let pidfile = args.pidfile.clone();
+    let daemonize = args.daemonize;
+    #[cfg(feature = "mqtt")]
+    let wait_for_network = args.wait_for_network;
+
+    // Init logging
+    if let Err(e) = init_logging(args, &config) {
+        if self_test {
+            println!("FAIL  config: {}", e);
+        }
+        env_logger::builder().filter_level(log::LevelFilter::Info).init();
+        error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+
+    #[cfg(feature = "embedded-config")]
+    if uses_embedded_config(&config_paths) {
+        info!("No config file found, using the config embedded at build time");
+    }
+
+    #[cfg(feature = "mqtt")]
+    if config.mqtt_error.is_none() && config.mqtt.append_hostname {
+        if let Err(e) = append_hostname(&mut config) {
+            error!("Unable to read the hostname to append to the identifier: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    // Reports a broken config as its first stage instead of exiting on it
+    if self_test {
+        return run_self_test(config);
+    }
+
+    if let Err(e) = validate_config(&config) {
+        error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+
+    #[cfg(feature = "mqtt")]
+    if config.mqtt_error.is_none() {
+        let checked = validate_mqtt_config(&config)
+            .and_then(|_| mqtt::load_tls(&mut config.mqtt_broker).map_err(|e| format!("Invalid [mqtt_broker.tls] config: {}", e)));
+        config.mqtt_error = checked.err();
+        if config.mqtt_error.is_none() {
+            warn_unretained_long_interval(&config);
+        }
+    }
+
+    // A broken MQTT config only stops the modes that need the broker, or a run with nothing else
+    // to publish to
+    #[cfg(feature = "mqtt")]
+    if let Some(e) = &config.mqtt_error {
+        let needs_mqtt = print_topics || emit_ha_yaml || inspect_discovery || validate_broker || purge_discovery || dump_config;
+        let reads_locally = once || benchmark.is_some() || burn_in.is_some() || config_test_sensor;
+        let other_output = config.csv.is_some() || config.unix_socket.is_some() || config.http.is_some() || tui;
+        if needs_mqtt || !(reads_locally || other_output) {
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+        warn!("{}", e);
+        if !reads_locally {
+            warn!("Running without MQTT, publishing to the other outputs only");
+        }
+    }
+
+    apply_unit_system(&mut config);
+
+    #[cfg(feature = "mqtt")]
+    if print_topics {
+        for (direction, topic) in mqtt::get_topics(&config) {
+            println!("{:<9} {}", direction, topic);
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    #[cfg(feature = "mqtt")]
+    if emit_ha_yaml {
+        print!("{}", mqtt::get_ha_yaml(&config));
+        return ExitCode::SUCCESS;
+    }
+
+    if dump_config {
+        return match toml::to_string(&config) {
+            Ok(dumped) => {
+                print!("{}", dumped);
+                ExitCode::SUCCESS
+            },
+            Err(e) => {
+                error!("Unable to serialize the config due to error: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    #[cfg(feature = "mqtt")]
+    if inspect_discovery {
+        return mqtt::inspect_discovery(&config);
+    }
+
+    #[cfg(feature = "mqtt")]
+    if validate_broker {
+        return mqtt::validate_broker(&config);
+    }
+
+    #[cfg(feature = "mqtt")]
+    if purge_discovery {
+        return mqtt::purge_discovery(&config);
+    }
+
+    info!("Starting BMP180 Temperature/Pressure Sensor");
+
+    clamp_poll_interval(&mut config.sensor);
+    log_startup_summary(&config, replay.as_deref());
+
+    let stats = Arc::new(Stats::default());
+    if let (None, Some(speed)) = (&replay, config.sensor.i2c_bus_speed_hz) {
+        check_bus_speed(speed);
+    }
+    let source = match replay {
+        Some(path) => match csv::CsvReplay::open(&path, replay_loop) {
+            Ok(replay) => {
+                info!("Replaying readings from `{}`", path.display());
+                Source::Replay(replay)
+            },
+            Err(e) => {
+                error!("Could not read replay file `{}`: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => match init_sensor_at_startup(&config.sensor, &stats) {
+            Ok(sensor) => match I2cWorker::spawn(I2C_BUS_PATH, sensor) {
+                Ok(worker) => Source::Sensor(worker),
+                Err(e) => {
+                    error!("Could not start the I2C worker thread: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            },
+            Err(e) => return e,
+        },
+    };
+
+    if once {
+        return print_single_reading(source, &config, json);
+    }
+    if let (Some(seconds), Source::Sensor(worker)) = (benchmark, &source) {
+        return run_benchmark(worker, &config.sensor, Duration::from_secs(seconds));
+    }
+    if let (Some(seconds), Source::Sensor(worker)) = (burn_in, &source) {
+        return run_burn_in(worker, &config.sensor, Duration::from_secs(seconds));
+    }
+    if config_test_sensor {
+        // Nothing sends signals here, but the sender must outlive the read loop
+        let (_signal_tx, signal_rx) = mpsc::channel();
+        return read_and_publish_data(source, vec![Box::new(sink::StdoutSink)], Arc::new(config), stats, signal_rx);
+    }
+
+    #[cfg_attr(not(feature = "mqtt"), allow(unused_mut))]
+    let mut sinks = match sink::open_sinks(&config) {
+        Ok(sinks) => sinks,
+        Err(e) => return e,
+    };
+
+    // Only detached once everything that commonly fails at startup has been checked, so those
+    // errors still reach the terminal
+    if daemonize {
+        let working_directory = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
+        if let Err(e) = Daemonize::new().working_directory(working_directory).stderr(Stdio::keep()).start() {
+            error!("Could not daemonize: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let _pidfile = match pidfile {
+        Some(path) => match PidFile::create(path.clone()) {
+            Ok(pidfile) => Some(pidfile),
+            Err(e) => {
+                error!("Could not write PID file `{}`: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    let config = Arc::new(config);
+
+    #[cfg(feature = "mqtt")]
+    if config.mqtt_error.is_none() {
+        if let Some(timeout) = wait_for_network {
+            if let Err(e) = mqtt::wait_for_network(&config.mqtt_broker, Duration::from_secs(timeout)) {
+                return e;
+            }
+        }
+
+        let (mut client, mut connection) = mqtt::get_mqtt_client(&config, &config.mqtt_broker);
+        let (signal_tx, signal_rx) = mpsc::channel();
+        let connected = Arc::new(AtomicBool::new(false));
+
+        let publisher = mqtt::Publisher::new(&config, client.clone(), Arc::clone(&connected), Arc::clone(&stats));
+        if config.mqtt.publish_config_echo && publisher.publish_config_echo(&config).is_err() {
+            return ExitCode::FAILURE;
+        }
+        sinks.push(Box::new(publisher));
+        if tui {
+            sinks.push(Box::new(tui::TuiSink::new(Arc::clone(&stats), Some(Arc::clone(&connected)))));
+        }
+        let heartbeat = match config.mqtt.heartbeat_interval_seconds {
+            0 => None,
+            _ => match heartbeat::Heartbeat::spawn(&config, client.clone(), Arc::clone(&connected)) {
+                Ok(heartbeat) => Some(heartbeat),
+                Err(e) => {
+                    error!("Could not start the heartbeat thread: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            },
+        };
+        let interrupts = mqtt::Interrupts::default();
+        if let Err(e) = register_signal_handlers(&interrupts).and_then(|_| forward_read_now_signal(signal_tx.clone())) {
+            error!("Unable to install signal handlers: {}", e);
+            return ExitCode::FAILURE;
+        }
+
+        let publish_config = Arc::clone(&config);
+        let read_stats = Arc::clone(&stats);
+        let read_stopped = Arc::clone(&interrupts.shutdown);
+        let read_thread = thread::spawn(move || {
+            let code = read_and_publish_data(source, sinks, publish_config, read_stats, signal_rx);
+            // Shuts the event loop down too, after it has sent whatever is still queued
+            read_stopped.store(true, Ordering::Relaxed);
+            code
+        });
+
+        // Settings read on the latest reload, when they changed the broker connection
+        let mut reloaded: Option<Data> = None;
+        let mut first_connect = true;
+        let mut fatal = false;
+        loop {
+            let broker = reloaded.as_ref().map_or(&config.mqtt_broker, |c| &c.mqtt_broker);
+            let exit = mqtt::poll_for_events(&mut connection, &client, &config, broker, &signal_tx, &connected, &stats, &interrupts, &mut first_connect);
+            match exit {
+                mqtt::PollExit::Reload => {
+                    let Some(new_config) = reload_config(&config_paths, &config, broker, &broker_overrides) else {
+                        continue;
+                    };
+                    info!("Broker settings changed, reconnecting to {}:{}", new_config.mqtt_broker.host, new_config.mqtt_broker.port);
+                    mqtt::disconnect(&client, &mut connection, &connected);
+                    reloaded = Some(new_config);
+                },
+                mqtt::PollExit::Rebuild => (),
+                mqtt::PollExit::Shutdown => break,
+                mqtt::PollExit::Fatal => {
+                    fatal = true;
+                    break;
+                },
+            }
+
+            let broker = reloaded.as_ref().map_or(&config.mqtt_broker, |c| &c.mqtt_broker);
+            (client, connection) = mqtt::get_mqtt_client(&config, broker);
+            // Sent ahead of the new connection's `Connected` so nothing is published on the old client
+            let _ = signal_tx.send(LoopSignal::ClientChanged(client.clone()));
+            if let Some(heartbeat) = &heartbeat {
+                heartbeat.set_client(client.clone());
+            }
+        }
+
+        info!("Shutting down");
+        // Stopping the read thread first means nothing is published after `offline`
+        let _ = signal_tx.send(LoopSignal::Shutdown);
+        let code = read_thread.join().unwrap_or(ExitCode::FAILURE);
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.stop();
+        }
+        mqtt::shutdown(&client, &mut connection, &config, &connected);
+        return if fatal { ExitCode::FAILURE } else { code };
+    }
+
+    // Built without MQTT, or running without it as its config is invalid
+    if tui {
+        sinks.push(Box::new(tui::TuiSink::new(Arc::clone(&stats), None)));
+    }
+    // Nothing sends signals without an MQTT event loop, but the sender must outlive the read loop
+    let (_signal_tx, signal_rx) = mpsc::channel();
+    read_and_publish_data(source, sinks, config, stats, signal_rx)
+}
+
+fn read_and_publish_data(
+    mut source: Source,
+    mut sinks: Vec<Box<dyn sink::Sink>>,
+    config: Arc<Data>,
+    stats: Arc<Stats>,
+    signals: Receiver<LoopSignal>,
+) -> ExitCode {
+    info!("Starting read and publish thread");
+    let mut temperature_interval = config.sensor.temperature_interval();
+    let mut pressure_interval = config.sensor.pressure_interval();
+    let mut next_temperature = first_tick(temperature_interval, config.sensor.align_to_clock);
+    let mut next_pressure = first_tick(pressure_interval, config.sensor.align_to_clock);
+    let mut clock = ClockWatch::new();
+    let mut consecutive_failures: u32 = 0;
+    let mut discard_remaining: u32 = 0;
+    // Reinitializations since the last successful read, and when the next one may happen
+    let mut reinit_attempts: u32 = 0;
+    let mut next_reinit = Instant::now();
+    let mut reinit_exhausted = false;
+    // Replayed readings were taken after any warmup already
+    let mut warmup = match (&source, config.sensor.stabilize_tolerance) {
+        (Source::Sensor(_), Some(tolerance)) => Some(Warmup::new(tolerance, Duration::from_secs(config.sensor.stabilize_timeout_seconds))),
+        _ => None,
+    };
+    // Sinks disabled at runtime still follow the connection, they just aren't handed readings
+    let mut disabled_sinks: HashSet<String> = HashSet::new();
+    let mut ema = Ema::default();
+    let mut inactive = false;
+    
+    loop {
+        let mut wake_at = next_temperature.min(next_pressure);
+        if let Some(deadline) = sinks.iter().filter_map(|sink| sink.next_deadline()).min() {
+            wake_at = wake_at.min(deadline);
+        }
+
+        let (read_temperature, read_pressure) = match signals.recv_timeout(wake_at.saturating_duration_since(Instant::now())) {
+            Ok(LoopSignal::RepublishDiscovery) => {
+                info!("Republishing discovery messages");
+                for sink in &mut sinks {
+                    sink.publish_discovery(&config);
+                }
+                continue;
+            },
+            #[cfg(feature = "mqtt")]
+            Ok(LoopSignal::Shutdown) => return ExitCode::SUCCESS,
+            // Reads on demand don't disturb the regular schedule
+            Ok(LoopSignal::ReadNow) => {
+                info!("Reading sensor on demand");
+                (true, true)
+            },
+            Ok(LoopSignal::SetSinkEnabled(name, enabled)) => {
+                if !sinks.iter().any(|sink| sink.name() == name) {
+                    warn!("Ignoring command for unknown sink [{}]", name);
+                } else if enabled && disabled_sinks.remove(&name) {
+                    info!("Enabled sink [{}]", name);
+                } else if !enabled && disabled_sinks.insert(name.clone()) {
+                    info!("Disabled sink [{}]", name);
+                }
+                continue;
+            },
+            Ok(LoopSignal::SetPollInterval(requested)) => {
+                let poll_interval = requested.clamp(config.sensor.min_poll_interval().as_secs_f64(), POLL_INTERVAL_MAX);
+                info!("Setting the poll interval to {}s", poll_interval);
+                temperature_interval = Duration::from_secs_f64(config.sensor.temperature_interval.unwrap_or(poll_interval));
+                pressure_interval = Duration::from_secs_f64(config.sensor.pressure_interval.unwrap_or(poll_interval));
+                // A shorter interval takes effect straight away rather than after the current one
+                next_temperature = next_temperature.min(Instant::now() + temperature_interval);
+                next_pressure = next_pressure.min(Instant::now() + pressure_interval);
+                for sink in &mut sinks {
+                    if sink.handle_signal(&config, &LoopSignal::SetPollInterval(poll_interval)).is_err() {
+                        return ExitCode::FAILURE;
+                    }
+                }
+                continue;
+            },
+            Ok(LoopSignal::ResetStats) => {
+                info!("Resetting statistics");
+                stats.reset();
+                (true, true)
+            },
+            Ok(signal) => {
+                for sink in &mut sinks {
+                    if sink.handle_signal(&config, &signal).is_err() {
+                        return ExitCode::FAILURE;
+                    }
+                }
+                continue;
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                for sink in &mut sinks {
+                    if sink.publish_due(&config).is_err() {
+                        return ExitCode::FAILURE;
+                    }
+                }
+
+                if let Some(jump) = clock.jump() {
+                    info!("System clock jumped by {:+.1}s", jump);
+                    if config.sensor.align_to_clock {
+                        next_temperature = first_tick(temperature_interval, true);
+                        next_pressure = first_tick(pressure_interval, true);
+                        continue;
+                    }
+                }
+
+                let now = Instant::now();
+                let due = (next_temperature <= now, next_pressure <= now);
+                if due.0 {
+                    next_temperature = next_tick(next_temperature, temperature_interval, now);
+                }
+                if due.1 {
+                    next_pressure = next_tick(next_pressure, pressure_interval, now);
+                }
+                if due == (false, false) {
+                    continue;
+                }
+                if let Some(active_hours) = &config.sensor.active_hours {
+                    let active = active_hours.contains(crate::now(&config).time());
+                    if active == inactive {
+                        inactive = !active;
+                        if active {
+                            info!("Within active_hours again, resuming readings");
+                        } else {
+                            info!("Outside active_hours, pausing readings until {}", active_hours.start);
+                        }
+                    }
+                    if !active {
+                        continue;
+                    }
+                }
+                due
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("MQTT event loop stopped unexpectedly");
+                return ExitCode::FAILURE;
+            },
+        };
+
+        let result = match &mut source {
+            Source::Sensor(worker) => read_on_worker(worker, &config.sensor, read_temperature, read_pressure)
+                .map(|reading| cross_check(&config.sensor, calibrate(&config.sensor, reading))),
+            Source::Replay(replay) => match replay.next_reading() {
+                Some(reading) => Ok(reading),
+                None => {
+                    info!("Reached the end of the replay file");
+                    return ExitCode::SUCCESS;
+                },
+            },
+        }.and_then(|reading| check_paired(&config.sensor, reading));
+
+        let reading = match result {
+            Ok(reading) => {
+                consecutive_failures = 0;
+                reinit_attempts = 0;
+                reinit_exhausted = false;
+                if reading.temperature_failed || reading.pressure_failed {
+                    stats.read_failures.fetch_add(1, Ordering::Relaxed);
+                }
+                reading
+            },
+            Err(e) => {
+                consecutive_failures += 1;
+                stats.read_failures.fetch_add(1, Ordering::Relaxed);
+                error!("Failed to read from sensor ({} consecutive failures): {}", consecutive_failures, e);
+                for sink in sinks.iter_mut().filter(|sink| !disabled_sinks.contains(sink.name())) {
+                    if sink.handle_read_failure(&config).is_err() {
+                        return ExitCode::FAILURE;
+                    }
+                }
+
+                let reinit_after = config.sensor.reinit_after_failures;
+                if let Source::Sensor(worker) = &source {
+                    if consecutive_failures == config.sensor.probe_after_failures {
+                        if let Err(e) = worker.run(PROBE_TIMEOUT, |_| probe_sensor()) {
+                            warn!("Could not check whether the sensor still answers: {}. The bus may be hung", e);
+                        }
+                    }
+                    let reinit_due = reinit_after > 0 && consecutive_failures >= reinit_after && !reinit_exhausted
+                        && Instant::now() >= next_reinit;
+                    if reinit_due && config.sensor.max_reinit_attempts.is_some_and(|max| reinit_attempts >= max) {
+                        match config.sensor.on_reinit_exhausted {
+                            OnReinitExhausted::Exit => {
+                                error!("Giving up after {} reinitializations that didn't bring the sensor back", reinit_attempts);
+                                return ExitCode::FAILURE;
+                            },
+                            OnReinitExhausted::Unavailable => {
+                                error!("Giving up on reinitializing the sensor after {} attempts, reading on until it answers again", reinit_attempts);
+                                reinit_exhausted = true;
+                                for sink in &mut sinks {
+                                    if sink.handle_sensor_lost(&config).is_err() {
+                                        return ExitCode::FAILURE;
+                                    }
+                                }
+                            },
+                        }
+                    } else if reinit_due {
+                        warn!("Reinitializing sensor after {} consecutive failures", consecutive_failures);
+                        let backoff = config.sensor.reinit_backoff_seconds.saturating_mul(1 << reinit_attempts.min(16))
+                            .min(config.sensor.reinit_backoff_max_seconds);
+                        reinit_attempts += 1;
+                        next_reinit = Instant::now() + Duration::from_secs(backoff);
+                        // Built on the worker, so opening the device and reading the calibration share
+                        // the bus serialization and the timeout of the reads
+                        let (sampling_mode, job_stats) = (config.sensor.sampling_mode, Arc::clone(&stats));
+                        let replaced = worker.run(config.sensor.read_timeout(), move |sensor| {
+                            init_sensor(sampling_mode, &job_stats).map(|new_sensor| *sensor = new_sensor)
+                        });
+                        match replaced {
+                            Ok(Ok(())) => {
+                                let reinits = stats.sensor_reinits.fetch_add(1, Ordering::Relaxed) + 1;
+                                info!("Sensor reinitialized ({} times since startup)", reinits);
+                                discard_remaining = config.sensor.discard_after_reinit;
+                            },
+                            Ok(Err(_)) => warn!("Sensor reinitialization failed, retrying in {}s at the earliest", backoff),
+                            Err(e) => warn!("Sensor reinitialization failed ({}), retrying in {}s at the earliest", e, backoff),
+                        }
+                    }
+                }
+                continue;
+            },
+        };
+
+        if discard_remaining > 0 {
+            discard_remaining -= 1;
+            debug!("Discarding reading taken right after reinitializing the sensor");
+            continue;
+        }
+
+        // Nothing left to publish once both measurements were discarded
+        let reading = discard_non_finite(reading);
+        if reading.temperature.is_none() && reading.pressure.is_none() {
+            continue;
+        }
+        if let Some(w) = &mut warmup {
+            if w.holds_back(&reading) {
+                continue;
+            }
+            warmup = None;
+        }
+        let reading = match config.sensor.smoothing {
+            Smoothing::None => reading,
+            Smoothing::Ema => ema.smooth(config.sensor.smoothing_alpha, reading),
+        };
+
+        for sink in sinks.iter_mut().filter(|sink| !disabled_sinks.contains(sink.name())) {
+            if sink.publish_state(&config, &reading).is_err() {
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+}
+
+/// Rejects a prefix Prometheus wouldn't accept as the start of a metric name.
+fn validate_http(config: &Data, http: &Http) -> Result<(), String> {
+    let prefix = &http.metrics_prefix;
+    let valid_start = prefix.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':');
+    if !valid_start || !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+        return Err(format!("metrics_prefix `{}` has to start with a letter, `_` or `:` and hold only letters, digits, `_` and `:`", prefix));
+    }
+    if http.room_in_name && http.room(config).is_none_or(str::is_empty) {
+        return Err(String::from("room_in_name needs a room to put into the names"));
+    }
+    Ok(())
+}
+
+/// Warns when states aren't retained and come slowly, as HA then shows the entities as unknown
+/// after it restarts until the next reading, with nothing telling that apart from a dead sensor.
+#[cfg(feature = "mqtt")]
+fn warn_unretained_long_interval(config: &Data) {
+    let interval = config.sensor.temperature_interval().max(config.sensor.pressure_interval());
+    if config.mqtt.retain_state || config.mqtt.expire_after_seconds.is_some() || interval <= UNRETAINED_INTERVAL_WARNING {
+        return;
+    }
+    warn!("States aren't retained, so HA shows the entities as unknown for up to {}s after it restarts. \
+        Set retain_state = true, or expire_after_seconds to have them shown unavailable instead", interval.as_secs());
+}
+
+#[cfg(feature = "mqtt")]
+fn append_hostname(config: &mut Data) -> Result<(), io::Error> {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")?;
+    let hostname = hostname.trim();
+
+    config.mqtt.identifier = format!("{}_{}", config.mqtt.identifier, hostname);
+    if config.mqtt.append_hostname_to_name {
+        config.mqtt.name = format!("{} {}", config.mqtt.name, hostname);
+    }
+    debug!("Appended hostname to identifier: [{}]", config.mqtt.identifier);
+    Ok(())
+}
+
+/// When to first read at `interval`. Aligned to the clock, that is the next multiple of the
+/// interval since the Unix epoch, e.g. on the minute for 60 seconds.
+fn first_tick(interval: Duration, align_to_clock: bool) -> Instant {
+    let now = Instant::now();
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    match (align_to_clock, interval.as_nanos()) {
+        (true, nanos) if nanos > 0 => {
+            let remainder = Duration::from_nanos((since_epoch.as_nanos() % nanos) as u64);
+            now + (interval - remainder)
+        },
+        _ => now + interval,
+    }
+}
+
+/// Holds back readings until the sensor has stabilized, see `stabilize_tolerance`.
+struct Warmup {
+    tolerance: f32,
+    timeout: Duration,
+    started: Instant,
+    last_temperature: Option<f32>,
+}
+
+impl Warmup {
+    fn new(tolerance: f32, timeout: Duration) -> Warmup {
+        info!("Waiting for consecutive temperatures within {} °C of each other before publishing", tolerance);
+        Warmup { tolerance, timeout, started: Instant::now(), last_temperature: None }
+    }
+
+    /// Whether `reading` is to be thrown away as the sensor hasn't stabilized yet. Readings without
+    /// a temperature are held back too, as there is nothing to tell from them.
+    fn holds_back(&mut self, reading: &Reading) -> bool {
+        if self.started.elapsed() >= self.timeout {
+            warn!("Sensor hasn't stabilized within {}s, publishing anyway", self.timeout.as_secs());
+            return false;
+        }
+        let Some(temperature) = reading.temperature else {
+            return true;
+        };
+        match self.last_temperature.replace(temperature) {
+            Some(last) if (temperature - last).abs() <= self.tolerance => {
+                info!("Sensor stabilized after {}s, publishing readings", self.started.elapsed().as_secs());
+                false
+            },
+            last => {
+                debug!("Discarding reading while the sensor stabilizes, temperature {} after {:?}", temperature, last);
+                true
+            },
+        }
+    }
+}
+
+/// Running values of `ema` smoothing, seeded by the first reading of each measurement.
+#[derive(Default)]
+struct Ema {
+    temperature: Option<f32>,
+    pressure: Option<f32>,
+}
+
+impl Ema {
+    /// Folds the measurements of `reading` into the running values and replaces them with those.
+    fn smooth(&mut self, alpha: f32, mut reading: Reading) -> Reading {
+        let fold = |average: &mut Option<f32>, value: &mut Option<f32>| {
+            if let Some(v) = *value {
+                let smoothed = average.map_or(v, |average| average + alpha * (v - average));
+                *average = Some(smoothed);
+                *value = Some(smoothed);
+            }
+        };
+        fold(&mut self.temperature, &mut reading.temperature);
+        fold(&mut self.pressure, &mut reading.pressure);
+        reading
+    }
+}
+
+/// Wall-clock time moving this much more or less than the monotonic clock counts as the clock
+/// being set, e.g. by NTP after booting a Pi without a real-time clock.
+const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// Notices the wall clock being set. Readings are scheduled on the monotonic clock, so a jump
+/// never stalls or rushes them, but readings aligned to the wall clock have to be realigned.
+struct ClockWatch {
+    wall: SystemTime,
+    monotonic: Instant,
+}
+
+impl ClockWatch {
+    fn new() -> ClockWatch {
+        ClockWatch { wall: SystemTime::now(), monotonic: Instant::now() }
+    }
+
+    /// Seconds the wall clock jumped by since the last call, negative when it went back.
+    fn jump(&mut self) -> Option<f64> {
+        let (wall, monotonic) = (SystemTime::now(), Instant::now());
+        // An error means the wall clock went backwards
+        let wall_elapsed = match wall.duration_since(self.wall) {
+            Ok(elapsed) => elapsed.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64(),
+        };
+        let jump = wall_elapsed - monotonic.duration_since(self.monotonic).as_secs_f64();
+        *self = ClockWatch { wall, monotonic };
+        (jump.abs() >= CLOCK_JUMP_THRESHOLD.as_secs_f64()).then_some(jump)
+    }
+}
+
+/// The tick after `previous` on a fixed schedule, so the time spent reading doesn't push later
+/// readings back. Ticks missed while a read ran long are skipped rather than caught up on.
+fn next_tick(previous: Instant, interval: Duration, now: Instant) -> Instant {
+    if interval.is_zero() {
+        return now;
+    }
+    let missed = (now.saturating_duration_since(previous).as_nanos() / interval.as_nanos()) as u32;
+    previous + interval * (missed + 1)
+}
+
+/// Raises the poll interval to the conversion time of the sampling mode, times the number of reads
+/// averaged, as polling any faster would only queue up reads behind the sensor. Temperature-only
+/// reads skip the pressure conversion, so their interval may be shorter.
+fn clamp_poll_interval(sensor: &mut Sensor) {
+    let samples = sensor.oversample.max(1);
+    let sampling_mode = format!("{:?} sampling mode", sensor.sampling_mode);
+    let clamp = |name: &str, interval: &mut f64, conversion_time: Duration, reads: &str| {
+        if *interval < conversion_time.as_secs_f64() {
+            warn!("{} of {}s is shorter than the {:?} conversion time of {} {} reads, using {:?} instead",
+                name, interval, conversion_time, samples, reads, conversion_time);
+            *interval = conversion_time.as_secs_f64();
+        }
+    };
+
+    let min_poll_interval = sensor.min_poll_interval();
+    let min_temperature_interval = sensor.min_temperature_interval();
+    clamp("Poll interval", &mut sensor.poll_interval, min_poll_interval, &sampling_mode);
+    if let Some(interval) = &mut sensor.temperature_interval {
+        clamp("Temperature interval", interval, min_temperature_interval, "temperature");
+    }
+    if let Some(interval) = &mut sensor.pressure_interval {
+        clamp("Pressure interval", interval, min_poll_interval, &sampling_mode);
+    }
+}
+
+/// Takes one reading for `--once`. Only the reading goes to stdout, logs stay on stderr so the
+/// output can be piped.
+fn print_single_reading(mut source: Source, config: &Data, json: bool) -> ExitCode {
+    let result = match &mut source {
+        Source::Sensor(worker) => read_on_worker(worker, &config.sensor, true, true).map(|reading| calibrate(&config.sensor, reading)),
+        Source::Replay(replay) => replay.next_reading().ok_or_else(|| "the replay file has no readings".into()),
+    };
+    let reading = match result {
+        Ok(reading) => discard_non_finite(reading),
+        Err(e) => {
+            error!("Failed to read from sensor: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let altitude = reading.pressure.filter(|_| config.sensor.altitude)
+        .and_then(|pressure| calculate_altitude(pressure, config.sensor.sea_level_pressure).converted_to(config.sensor.altitude_unit.into()))
+        .map(|altitude| altitude.rounded(config.sensor.altitude_decimals));
+    let aviation_altitude = |enabled: bool, altitude: Option<Measurement>| altitude.filter(|_| enabled)
+        .and_then(|altitude| altitude.converted_to(config.sensor.altitude_unit.into()))
+        .map(|altitude| altitude.rounded(config.sensor.altitude_decimals));
+    let pressure_altitude = aviation_altitude(config.sensor.pressure_altitude, reading.pressure.map(calculate_pressure_altitude));
+    let density_altitude = aviation_altitude(config.sensor.density_altitude, reading.pressure.zip(reading.temperature)
+        .map(|(pressure, temperature)| calculate_density_altitude(pressure, temperature)));
+
+    if json {
+        // Widened through the shortest representation, so 101.2 doesn't print as 101.19999694824219
+        let number = |value: f32| value.to_string().parse::<f64>().unwrap_or_default();
+        let mut output = serde_json::json!({
+            "temperature": reading.temperature.map(number),
+            "pressure": reading.pressure.map(number),
+            "timestamp": now(config).format(&Rfc3339).unwrap_or_default(),
+        });
+        if let Some(altitude) = altitude {
+            output["altitude"] = serde_json::json!(number(altitude.raw));
+        }
+        if let Some(altitude) = pressure_altitude {
+            output["pressure_altitude"] = serde_json::json!(number(altitude.raw));
+        }
+        if let Some(altitude) = density_altitude {
+            output["density_altitude"] = serde_json::json!(number(altitude.raw));
+        }
+        println!("{}", output);
+    } else {
+        if let Some(temperature) = reading.temperature {
+            println!("Temperature: {} {}", temperature, Unit::Celsius.symbol());
+        }
+        if let Some(pressure) = reading.pressure {
+            println!("Pressure: {} {}", pressure, Unit::Kilopascals.symbol());
+        }
+        if let Some(altitude) = altitude {
+            println!("Altitude: {:.*} {}", config.sensor.altitude_decimals, altitude, altitude.unit.symbol());
+        }
+        if let Some(altitude) = pressure_altitude {
+            println!("Pressure altitude: {:.*} {}", config.sensor.altitude_decimals, altitude, altitude.unit.symbol());
+        }
+        if let Some(altitude) = density_altitude {
+            println!("Density altitude: {:.*} {}", config.sensor.altitude_decimals, altitude, altitude.unit.symbol());
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Checks each stage of a deployment in turn, printing a line per stage. Later stages still run
+/// after one fails, so a single run shows everything that needs fixing.
+fn run_self_test(mut config: Data) -> ExitCode {
+    let mut passed = true;
+    let mut report = |stage: &str, result: Result<String, String>| match result {
+        Ok(detail) => println!("PASS  {}: {}", stage, detail),
+        Err(e) => {
+            passed = false;
+            println!("FAIL  {}: {}", stage, e);
+        },
+    };
+
+    // The broker is only tried with a valid MQTT config, whatever the rest of the config
+    #[cfg(feature = "mqtt")]
+    let mqtt_validated = match config.mqtt_error.take() {
+        Some(e) => Err(e),
+        None => validate_mqtt_config(&config)
+            .and_then(|_| mqtt::load_tls(&mut config.mqtt_broker).map_err(|e| format!("Invalid [mqtt_broker.tls] config: {}", e))),
+    };
+    let validated = validate_config(&config);
+    #[cfg(feature = "mqtt")]
+    let validated = validated.and(mqtt_validated.clone());
+    report("config", validated.map(|()| String::from("loaded and valid")));
+    apply_unit_system(&mut config);
+
+    // The sensor is opened on the worker, as there is none to hand it yet, so the whole stage gets
+    // the read timeout
+    let sampling_mode = config.sensor.sampling_mode;
+    let sensor = I2cWorker::spawn(I2C_BUS_PATH, ()).map_err(|e| e.to_string())
+        .and_then(|worker| worker.run(config.sensor.read_timeout(), move |_| match init_sensor(sampling_mode, &Stats::default()) {
+            Ok(mut sensor) => read_from_sensor(&mut sensor, true, true).map_err(|e| e.to_string()),
+            Err(_) => Err(String::from("could not be initialized, see the log above")),
+        }).map_err(|e| e.to_string()))
+        .and_then(|result| result)
+        .map(|reading| calibrate(&config.sensor, reading))
+        .and_then(|reading| {
+            let shown = |value: Option<f32>, unit: Unit| value.map_or_else(|| String::from("n/a"), |value| format!("{} {}", value, unit.symbol()));
+            let detail = format!("{}, {}", shown(reading.temperature, Unit::Celsius), shown(reading.pressure, Unit::Kilopascals));
+            if reading.temperature.is_some() && reading.pressure.is_some() { Ok(detail) } else { Err(detail) }
+        });
+    report("sensor", sensor);
+
+    #[cfg(feature = "mqtt")]
+    {
+        let broker = &config.mqtt_broker;
+        let result = if mqtt_validated.is_ok() {
+            mqtt::self_test(&config)
+                .map(|()| format!("published a test message to {}:{}", broker.host, broker.port))
+                .map_err(|e| format!("{}:{}: {}", broker.host, broker.port, e))
+        } else {
+            Err(String::from("not tried with an invalid MQTT config"))
+        };
+        report("broker", result);
+    }
+
+    if passed { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+/// Reads back to back for `duration` and prints a summary to stdout.
+fn run_benchmark(worker: &I2cWorker<Bmp180>, sensor_config: &Sensor, duration: Duration) -> ExitCode {
+    info!("Benchmarking {:?} sampling mode for {:?}", sensor_config.sampling_mode, duration);
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    let mut last_error = None;
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        match read_on_worker(worker, sensor_config, true, true) {
+            Ok(reading) => latencies.push(reading.read_latency),
+            Err(e) => {
+                errors += 1;
+                last_error = Some(e.to_string());
+            },
+        }
+    }
+    let elapsed = started.elapsed();
+
+    println!("Sampling mode: {:?}", sensor_config.sampling_mode);
+    println!("Reads: {} in {:.1}s ({:.1} reads/s)", latencies.len(), elapsed.as_secs_f64(), latencies.len() as f64 / elapsed.as_secs_f64());
+    if let (Some(min), Some(max)) = (latencies.iter().min(), latencies.iter().max()) {
+        let average = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+        println!("Latency: min {:?}, max {:?}, avg {:?}", min, max, average);
+    }
+    println!("Errors: {}", errors);
+    if let Some(e) = last_error {
+        println!("Last error: {}", e);
+    }
+    if latencies.is_empty() { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+/// Reads back to back for `duration` and prints the noise of the raw readings to stdout. Each read
+/// stands alone, as averaging with `oversample` would hide the noise being measured.
+fn run_burn_in(worker: &I2cWorker<Bmp180>, sensor_config: &Sensor, duration: Duration) -> ExitCode {
+    info!("Measuring the sensor noise in {:?} sampling mode for {:?}", sensor_config.sampling_mode, duration);
+    let mut temperatures = Vec::new();
+    let mut pressures = Vec::new();
+    let mut errors = 0;
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        let result = worker.run(sensor_config.read_timeout(), |sensor| {
+            read_from_sensor(sensor, true, true).map_err(|e| e.to_string())
+        });
+        match result.map_err(|e| e.to_string()).and_then(|reading| reading) {
+            Ok(reading) => {
+                temperatures.extend(reading.temperature.map(f64::from));
+                pressures.extend(reading.pressure.map(f64::from));
+            },
+            Err(e) => {
+                errors += 1;
+                debug!("Read failed during burn-in: {}", e);
+            },
+        }
+    }
+
+    let summarize = |name: &str, values: &[f64], unit: Unit| {
+        if values.is_empty() {
+            println!("{}: no readings", name);
+            return;
+        }
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+        let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        println!("{}: mean {:.3} {unit}, stddev {:.4} {unit}, peak-to-peak {:.4} {unit}",
+            name, mean, variance.sqrt(), max - min, unit = unit.symbol());
+    };
+    println!("Sampling mode: {:?}", sensor_config.sampling_mode);
+    println!("Reads: {} in {:.1}s", temperatures.len().max(pressures.len()), started.elapsed().as_secs_f64());
+    summarize("Temperature", &temperatures, Unit::Celsius);
+    summarize("Pressure", &pressures, Unit::Kilopascals);
+    println!("Errors: {}", errors);
+    if temperatures.is_empty() && pressures.is_empty() { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+/// Logs the effective configuration on one line. The broker password is never included.
+fn log_startup_summary(config: &Data, replay: Option<&Path>) {
+    let mut summary = Vec::new();
+
+    #[cfg(feature = "mqtt")]
+    if config.mqtt_error.is_none() {
+        summary.push(format!("broker={}:{}", config.mqtt_broker.host, config.mqtt_broker.port));
+        summary.push(format!("user={}", config.mqtt_broker.username));
+        summary.push(format!("room={}", config.mqtt.room));
+        summary.push(format!("identifier={}", config.mqtt.identifier));
+    }
+
+    summary.push(format!("session={}", session_id()));
+    let sensor = &config.sensor;
+    summary.push(format!("interval={}s", sensor.poll_interval));
+    if sensor.temperature_interval.is_some() || sensor.pressure_interval.is_some() {
+        summary.push(format!("temperature_interval={}s", sensor.temperature_interval().as_secs_f64()));
+        summary.push(format!("pressure_interval={}s", sensor.pressure_interval().as_secs_f64()));
+    }
+    summary.push(format!("sampling_mode={:?}", sensor.sampling_mode));
+    let mut units: Vec<&str> = sensor.temperature_units.iter().map(|&unit| Unit::from(unit).symbol()).collect();
+    units.push(Unit::Kilopascals.symbol());
+    summary.push(format!("units={}", units.join(",")));
+    if sensor.altitude {
+        summary.push(format!("altitude={}", Unit::from(sensor.altitude_unit).symbol()));
+    }
+
+    let mut sinks = Vec::new();
+    #[cfg(feature = "mqtt")]
+    if config.mqtt_error.is_none() {
+        sinks.push(String::from("mqtt"));
+    }
+    #[cfg(feature = "mqtt")]
+    if let Some(rest) = &config.homeassistant_rest {
+        sinks.push(format!("homeassistant_rest:{}", rest.url));
+    }
+    if let Some(csv) = &config.csv {
+        sinks.push(format!("csv:{}", csv.path.display()));
+    }
+    if let Some(socket) = &config.unix_socket {
+        sinks.push(format!("unix_socket:{}", socket.path.display()));
+    }
+    if let Some(http) = &config.http {
+        sinks.push(format!("http:{}", http.listen));
+    }
+    summary.push(format!("sinks=[{}]", sinks.join(",")));
+    if let Some(replay) = replay {
+        summary.push(format!("replay={}", replay.display()));
+    }
+
+    info!("Effective configuration: {}", summary.join(" "));
+}
+
+/// Suggests a fix for the usual causes of failing to open the I2C bus.
+fn i2c_error_hint(e: &io::Error) -> &'static str {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => "Is your user in the `i2c` group?",
+        io::ErrorKind::NotFound => "Is I2C enabled in raspi-config?",
+        _ => "Is I2C enabled in raspi-config and is your user in the `i2c` group?",
+    }
+}
+
+fn init_logging(args: Args, config: &Data) -> Result<(), String> {
+    let log_level = if args.quiet { Some(String::from("Error")) } else { args.log_level };
+    let log_level = log_level.unwrap_or(
+        config.logging.log_level.clone().unwrap_or(
+            String::from("Info")
+        ));
+    let log_level: log::LevelFilter = log_level.parse()
+        .map_err(|_| format!("Unknown log level `{}`, expected one of off, error, warn, info, debug and trace", log_level))?;
+
+    let timestamps = args.log_timestamps.or(config.logging.timestamps).unwrap_or(LogTimestamps::Rfc3339);
+    let timezone = configured_timezone(config);
+    let mut local_offset_unknown = false;
+    // The offset can only be looked up safely while the process is single-threaded
+    let offset = match (timestamps, timezone) {
+        (LogTimestamps::Local, None) => UtcOffset::current_local_offset().unwrap_or_else(|_| {
+            local_offset_unknown = true;
+            UtcOffset::UTC
+        }),
+        _ => UtcOffset::UTC,
+    };
+
+    // Problems setting up an output are logged through the others once they are up
+    let mut problems = Vec::new();
+    let default_outputs = [LogOutput::Stderr { level: None }];
+    let outputs = if config.logging.outputs.is_empty() { &default_outputs[..] } else { &config.logging.outputs };
+    let mut loggers = Vec::new();
+    // The dashboard takes over the terminal
+    for output in outputs.iter().filter(|output| !(args.tui && matches!(output, LogOutput::Stderr { .. }))) {
+        let (LogOutput::Stderr { level } | LogOutput::File { level, .. } | LogOutput::Syslog { level, .. }) = output;
+        let mut builder = env_logger::builder();
+        builder.filter_level(match level.as_deref().map(str::parse) {
+            Some(Ok(level)) => level,
+            Some(Err(_)) => {
+                problems.push(format!("Unknown log level `{}`, using {} instead", level.as_deref().unwrap_or_default(), log_level));
+                log_level
+            },
+            None => log_level,
+        });
+        for (target, level) in &config.logging.log_targets {
+            match level.parse() {
+                Ok(level) => {
+                    builder.filter_module(target, level);
+                },
+                Err(_) => {
+                    // Checked once per output, but only worth reporting once
+                    let problem = format!("Unknown log level `{}` for target `{}`, leaving its level as is", level, target);
+                    if !problems.contains(&problem) {
+                        problems.push(problem);
+                    }
+                },
+            }
+        }
+        #[cfg(feature = "mqtt")]
+        if args.trace_mqtt {
+            builder.filter_module("rumqttc", log::LevelFilter::Trace);
+            builder.filter_module(mqtt::TRACE_TARGET, log::LevelFilter::Trace);
+        }
+
+        match output {
+            LogOutput::Stderr { .. } => format_timestamps(&mut builder, timestamps, timezone, offset),
+            LogOutput::File { path, .. } => match fs::OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => {
+                    builder.target(env_logger::Target::Pipe(Box::new(file)));
+                    format_timestamps(&mut builder, timestamps, timezone, offset);
+                },
+                Err(e) => {
+                    problems.push(format!("Could not open log file `{}`: {}", path.display(), e));
+                    continue;
+                }
+            },
+            LogOutput::Syslog { address, .. } => match logging::SyslogWriter::connect(address) {
+                Ok(writer) => {
+                    builder.target(env_logger::Target::Pipe(Box::new(writer)));
+                    builder.format(logging::syslog_format());
+                },
+                Err(e) => {
+                    problems.push(format!("Could not reach syslog server `{}`: {}", address, e));
+                    continue;
+                }
+            },
+        }
+        loggers.push(builder.build());
+    }
+    // Errors have to show up somewhere
+    if loggers.is_empty() && !args.tui {
+        let mut builder = env_logger::builder();
+        builder.filter_level(log_level);
+        format_timestamps(&mut builder, timestamps, timezone, offset);
+        loggers.push(builder.build());
+    }
+    logging::init(loggers, config.logging.session_id.then(session_id));
+
+    for problem in problems {
+        warn!("{}", problem);
+    }
+    if local_offset_unknown {
+        warn!("Unable to determine the local UTC offset, logging in UTC instead");
+    }
+    Ok(())
+}
+
+/// Sets up the timestamps of the stderr and file outputs.
+fn format_timestamps(builder: &mut env_logger::Builder, timestamps: LogTimestamps, timezone: Option<&'static Tz>, offset: UtcOffset) {
+    match timestamps {
+        LogTimestamps::Off => {
+            builder.format_timestamp(None);
+        },
+        LogTimestamps::Rfc3339 => (),
+        LogTimestamps::Local => {
+            builder.format(move |buf, record| {
+                let now = OffsetDateTime::now_utc();
+                let now = match timezone {
+                    Some(timezone) => now.to_timezone(timezone),
+                    None => now.to_offset(offset),
+                };
+                let timestamp = now
+                    .replace_nanosecond(0)
+                    .ok()
+                    .and_then(|t| t.format(&Rfc3339).ok())
+                    .unwrap_or_default();
+                writeln!(buf, "[{} {:<5} {}] {}", timestamp, record.level(), record.target(), record.args())
+            });
+        },
+    }
+}
+
+fn configured_timezone(config: &Data) -> Option<&'static Tz> {
+    config.timezone.as_deref().and_then(timezones::get_by_name)
+}
+
+/// Converts `time` to the configured timezone, leaving it in UTC when none is set.
+fn in_timezone(config: &Data, time: OffsetDateTime) -> OffsetDateTime {
+    match configured_timezone(config) {
+        Some(timezone) => time.to_timezone(timezone),
+        None => time,
+    }
+}
+
+/// The current time in the configured timezone, for timestamps in payloads.
+fn now(config: &Data) -> OffsetDateTime {
+    in_timezone(config, OffsetDateTime::now_utc())
+}
+
+/// Sets the temperature and altitude units from `unit_system`, if set.
+fn apply_unit_system(config: &mut Data) {
+    let imperial = match config.sensor.unit_system {
+        None => return,
+        Some(UnitSystem::Metric) => false,
+        Some(UnitSystem::Imperial) => true,
+        Some(UnitSystem::FromHa) => ha_uses_imperial(config),
+    };
+    let (temperature_unit, altitude_unit) = if imperial {
+        (TemperatureUnit::Fahrenheit, AltitudeUnit::Feet)
+    } else {
+        (TemperatureUnit::Celsius, AltitudeUnit::Meters)
+    };
+    config.sensor.temperature_units = vec![temperature_unit];
+    config.sensor.altitude_unit = altitude_unit;
+}
+
+/// Asks HA for its unit system, falling back to metric when it can't be asked.
+fn ha_uses_imperial(config: &Data) -> bool {
+    #[cfg(feature = "mqtt")]
+    if let Some(rest) = config.homeassistant_rest.as_ref().filter(|_| config.mqtt_error.is_none()) {
+        return match homeassistant_rest::uses_imperial_units(rest) {
+            Ok(imperial) => {
+                info!("Using the {} units Home Assistant is set to", if imperial { "imperial" } else { "metric" });
+                imperial
+            },
+            Err(e) => {
+                warn!("Could not ask Home Assistant at {} for its unit system, using metric units: {}", rest.url, e);
+                false
+            }
+        };
+    }
+    #[cfg(not(feature = "mqtt"))]
+    let _ = config;
+    // Only left without [homeassistant_rest] when it was turned off along with MQTT
+    warn!("Home Assistant can't be asked for its unit system without [homeassistant_rest], using metric units");
+    false
+}
+
+/// Checks what the types of the config can't express. Fatal at startup, while a reload failing it
+/// keeps the current config.
+fn validate_config(config: &Data) -> Result<(), String> {
+    if let Some(timezone) = config.timezone.as_deref().filter(|_| configured_timezone(config).is_none()) {
+        return Err(format!("Unknown timezone `{}`, expected an IANA name such as `Europe/Berlin`", timezone));
+    }
+    let sensor = &config.sensor;
+    if let Err(e) = sensor.temperature_calibration() {
+        return Err(format!("Invalid temperature calibration: {}", e));
+    }
+    if let Some(Err(e)) = sensor.active_hours.as_ref().map(ActiveHours::window) {
+        return Err(format!("Invalid active_hours: {}", e));
+    }
+    if sensor.pressure_min > sensor.pressure_max {
+        return Err(format!("pressure_min of {} kPa is above pressure_max of {} kPa", sensor.pressure_min, sensor.pressure_max));
+    }
+    if sensor.altitude_min > sensor.altitude_max {
+        return Err(format!("altitude_min of {} m is above altitude_max of {} m", sensor.altitude_min, sensor.altitude_max));
+    }
+    if sensor.rate_of_change && sensor.rate_window_seconds == 0 {
+        return Err(String::from("rate_window_seconds has to be above 0 with rate_of_change"));
+    }
+    if sensor.reinit_backoff_seconds > sensor.reinit_backoff_max_seconds {
+        return Err(format!("reinit_backoff_seconds of {} is above reinit_backoff_max_seconds of {}",
+            sensor.reinit_backoff_seconds, sensor.reinit_backoff_max_seconds));
+    }
+    if sensor.comfort_bands.is_some_and(|bounds| !bounds.is_sorted_by(|a, b| a < b)) {
+        return Err(String::from("comfort_bands have to be in increasing order"));
+    }
+    if let Some(tolerance) = sensor.stabilize_tolerance.filter(|tolerance| tolerance.is_nan() || *tolerance <= 0.0) {
+        return Err(format!("stabilize_tolerance of {} °C has to be above 0", tolerance));
+    }
+    if sensor.smoothing == Smoothing::Ema && !(sensor.smoothing_alpha > 0.0 && sensor.smoothing_alpha <= 1.0) {
+        return Err(format!("smoothing_alpha of {} has to be above 0 and at most 1", sensor.smoothing_alpha));
+    }
+    #[cfg(feature = "mqtt")]
+    if sensor.unit_system == Some(UnitSystem::FromHa) && config.homeassistant_rest.is_none() && config.mqtt_error.is_none() {
+        return Err(String::from("unit_system = \"from_ha\" needs a [homeassistant_rest] section to ask Home Assistant through"));
+    }
+    #[cfg(not(feature = "mqtt"))]
+    if sensor.unit_system == Some(UnitSystem::FromHa) {
+        return Err(String::from("unit_system = \"from_ha\" needs a build with the mqtt feature"));
+    }
+    if sensor.paired_reads && sensor.temperature_interval() != sensor.pressure_interval() {
+        return Err(String::from("paired_reads needs the temperature and pressure read together, so temperature_interval and pressure_interval can't differ"));
+    }
+    if let Some(http) = &config.http {
+        validate_http(config, http).map_err(|e| format!("Invalid [http] config: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Checks the `[mqtt]` section, which unlike the rest only stops the outputs built on it.
+#[cfg(feature = "mqtt")]
+fn validate_mqtt_config(config: &Data) -> Result<(), String> {
+    validate_transport(&config.mqtt_broker).map_err(|e| format!("Invalid [mqtt_broker] config: {}", e))?;
+    validate_mqtt_names(&config.mqtt)
+        .and_then(|_| validate_temperature_triggers(&config.mqtt))
+        .and_then(|_| validate_transforms(&config.mqtt.transforms))
+        .and_then(|_| validate_layout(&config.mqtt))
+        .and_then(|_| validate_deadbands(&config.mqtt))
+        .and_then(|_| validate_location(&config.mqtt))
+        .map_err(|e| format!("Invalid [mqtt] config: {}", e))
+}
+
+/// Rejects names that would break the client id or the topics built from them. `name` is the
+/// client id, which brokers commonly refuse with a separator or wildcard in it, while `room` and
+/// `identifier` can't hold wildcards. `room` can't hold a separator either with discovery, as it
+/// is part of the object id in the discovery topics.
+#[cfg(feature = "mqtt")]
+fn validate_mqtt_names(mqtt: &MQTT) -> Result<(), String> {
+    if let Some(c) = mqtt.name.chars().find(|c| matches!(c, '/' | '+' | '#')) {
+        return Err(format!("name `{}` contains `{}`, which isn't allowed in an MQTT client id", mqtt.name, c));
+    }
+    for (field, value) in [("room", &mqtt.room), ("identifier", &mqtt.identifier)] {
+        if let Some(c) = value.chars().find(|c| matches!(c, '+' | '#')) {
+            return Err(format!("{} `{}` contains the MQTT wildcard `{}`", field, value, c));
+        }
+    }
+    // HA only matches discovery topics of the form homeassistant/<platform>/<object id>/config
+    if mqtt.discovery() && mqtt.room.contains('/') {
+        return Err(format!("room `{}` contains `/`, which puts the discovery topics on more levels than Home Assistant reads; use another separator or turn off publish_discovery", mqtt.room));
+    }
+    Ok(())
+}
+
+/// Rejects temperature triggers without exactly one threshold, or with a name that can't be part
+/// of their topic or isn't unique.
+#[cfg(feature = "mqtt")]
+fn validate_temperature_triggers(mqtt: &MQTT) -> Result<(), String> {
+    for (i, trigger) in mqtt.temperature_triggers.iter().enumerate() {
+        if trigger.above.is_some() == trigger.below.is_some() {
+            return Err(format!("temperature trigger `{}` needs exactly one of `above` and `below`", trigger.name));
+        }
+        if trigger.hysteresis < 0.0 {
+            return Err(format!("temperature trigger `{}` has a negative hysteresis", trigger.name));
+        }
+        if trigger.name.is_empty() || trigger.name.contains(['/', '+', '#']) {
+            return Err(format!("temperature trigger name `{}` has to be non-empty and can't contain `/`, `+` or `#`", trigger.name));
+        }
+        if mqtt.temperature_triggers[..i].iter().any(|other| other.name == trigger.name) {
+            return Err(format!("temperature trigger name `{}` is used more than once", trigger.name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mqtt")]
+fn validate_layout(mqtt: &MQTT) -> Result<(), String> {
+    match mqtt.layout {
+        Layout::Z2m if matches!(mqtt.state_format, StateFormat::Split) => Err(String::from("the z2m layout publishes a single JSON state, so it can't be combined with state_format = \"split\"")),
+        Layout::Custom if mqtt.state_topic.is_none() => Err(String::from("the custom layout needs state_topic to be set")),
+        _ if mqtt.payload_encoding == PayloadEncoding::Msgpack && matches!(mqtt.state_format, StateFormat::Split) => {
+            Err(String::from("payload_encoding = \"msgpack\" encodes the JSON state, so it needs state_format = \"json\""))
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(feature = "mqtt")]
+fn validate_deadbands(mqtt: &MQTT) -> Result<(), String> {
+    if mqtt.pressure_deadband.is_some() && mqtt.pressure_deadband_percent.is_some() {
+        return Err(String::from("set only one of pressure_deadband and pressure_deadband_percent"));
+    }
+    let named = [
+        ("temperature_deadband", mqtt.temperature_deadband),
+        ("pressure_deadband", mqtt.pressure_deadband),
+        ("pressure_deadband_percent", mqtt.pressure_deadband_percent),
+    ];
+    match named.into_iter().find(|(_, deadband)| deadband.is_some_and(|deadband| !deadband.is_finite() || deadband < 0.0)) {
+        Some((name, _)) => Err(format!("{} has to be a non-negative number", name)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "mqtt")]
+fn validate_location(mqtt: &MQTT) -> Result<(), String> {
+    match (mqtt.latitude, mqtt.longitude) {
+        (None, None) => Ok(()),
+        (Some(latitude), Some(longitude)) if (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) => Ok(()),
+        (Some(_), Some(_)) => Err(String::from("latitude has to be within -90 to 90 and longitude within -180 to 180 degrees")),
+        _ => Err(String::from("set both latitude and longitude, or neither")),
+    }
+}
+
+/// Checks that the certificates in `tls` are there exactly when the transport uses them.
+#[cfg(feature = "mqtt")]
+fn validate_transport(broker: &MQTTBroker) -> Result<(), String> {
+    let encrypted = matches!(broker.transport(), BrokerTransport::Tls | BrokerTransport::Wss);
+    if encrypted && broker.tls.is_none() {
+        return Err(String::from("the tls and wss transports need a [mqtt_broker.tls] section"));
+    }
+    if !encrypted && broker.tls.is_some() {
+        return Err(String::from("[mqtt_broker.tls] is only used by the tls and wss transports"));
+    }
+    if !broker.ws_path.starts_with('/') {
+        return Err(format!("ws_path `{}` has to start with /", broker.ws_path));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mqtt")]
+fn validate_transforms(transforms: &Transforms) -> Result<(), String> {
+    let named = [("temperature", &transforms.temperature), ("pressure", &transforms.pressure), ("altitude", &transforms.altitude)];
+    for (name, transform) in named {
+        let Some(transform) = transform else {
+            continue;
+        };
+        if !transform.scale.is_finite() || transform.scale == 0.0 || !transform.offset.is_finite() {
+            return Err(format!("the {} transform needs a finite, non-zero scale and a finite offset", name));
+        }
+    }
+    Ok(())
+}
+
+/// Requests a reload on SIGHUP and a shutdown on SIGTERM or SIGINT. A second SIGTERM or SIGINT
+/// exits straight away, in case shutting down cleanly hangs.
+#[cfg(feature = "mqtt")]
+fn register_signal_handlers(interrupts: &mqtt::Interrupts) -> Result<(), io::Error> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::flag;
+
+    flag::register(SIGHUP, Arc::clone(&interrupts.reload))?;
+    for signal in [SIGTERM, SIGINT] {
+        flag::register_conditional_shutdown(signal, 1, Arc::clone(&interrupts.shutdown))?;
+        flag::register(signal, Arc::clone(&interrupts.shutdown))?;
+    }
+    Ok(())
+}
+
+/// Takes a reading straight away on SIGUSR1, for scripts on the same host that want a fresh one
+/// without going through the broker. Handled on a thread of its own, as the event loop only checks
+/// the other signals between events.
+#[cfg(feature = "mqtt")]
+fn forward_read_now_signal(signals: mpsc::Sender<LoopSignal>) -> Result<(), io::Error> {
+    use signal_hook::consts::SIGUSR1;
+    use signal_hook::iterator::Signals;
+
+    let mut sigusr1 = Signals::new([SIGUSR1])?;
+    thread::Builder::new()
+        .name(String::from("sigusr1"))
+        .spawn(move || {
+            for _ in sigusr1.forever() {
+                debug!("Received SIGUSR1");
+                if signals.send(LoopSignal::ReadNow).is_err() {
+                    break;
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Re-reads the config file on SIGHUP. Only the `[mqtt_broker]` settings take effect without a
+/// restart; the new config is returned when they changed.
+#[cfg(feature = "mqtt")]
+fn reload_config(paths: &[PathBuf], config: &Data, broker: &MQTTBroker, overrides: &BrokerOverrides) -> Option<Data> {
+    info!("Reloading config");
+    let mut new_config = match read_config(paths) {
+        Ok(Data { mqtt_error: Some(e), .. }) | Err(e) => {
+            error!("{}", e);
+            warn!("Keeping the current config");
+            return None;
+        }
+        Ok(c) => c,
+    };
+    new_config.mqtt_broker.apply_overrides(overrides);
+    if new_config.mqtt.append_hostname {
+        if let Err(e) = append_hostname(&mut new_config) {
+            warn!("Unable to read the hostname to append to the identifier, keeping the current config: {}", e);
+            return None;
+        }
+    }
+    if let Err(e) = validate_config(&new_config).and_then(|_| validate_mqtt_config(&new_config)) {
+        error!("{}", e);
+        warn!("Keeping the current config");
+        return None;
+    }
+    if let Err(e) = mqtt::load_tls(&mut new_config.mqtt_broker) {
+        warn!("Invalid [mqtt_broker.tls] config, keeping the current config: {}", e);
+        return None;
+    }
+    clamp_poll_interval(&mut new_config.sensor);
+    // HA is only asked again when the unit system changed
+    if new_config.sensor.unit_system.is_some() && new_config.sensor.unit_system == config.sensor.unit_system {
+        new_config.sensor.temperature_units = config.sensor.temperature_units.clone();
+        new_config.sensor.altitude_unit = config.sensor.altitude_unit;
+    } else {
+        apply_unit_system(&mut new_config);
+    }
+
+    // Compared through their serialized form, which leaves out the broker settings
+    let without_broker = |data: &Data| serde_json::to_value(data).ok().map(|mut value| {
+        value.as_object_mut().map(|o| o.remove("mqtt_broker"));
+        value
+    });
+    if without_broker(config) != without_broker(&new_config) {
+        warn!("Only [mqtt_broker] settings are applied on reload, restart to apply the other changes");
+    }
+
+    if !broker.connection_differs(&new_config.mqtt_broker) {
+        info!("Broker settings unchanged");
+        return None;
+    }
+    Some(new_config)
+}
+
+/// Reads and merges the config files, each overriding the fields set by the ones before it.
+/// Errors are returned rather than logged, as logging isn't set up yet at startup.
+fn read_config(paths: &[PathBuf]) -> Result<Data, String> {
+    #[cfg(feature = "embedded-config")]
+    if uses_embedded_config(paths) {
+        return toml::from_str(EMBEDDED_CONFIG)
+            .map_err(|e| format!("Unable to load data from the embedded config due to error: {}", e.message()));
+    }
+
+    let mut merged = toml::Table::new();
+    for path in config_files(paths)? {
+        let contents = match fs::read(&path) {
+            Ok(bytes) => decode_config(&path, bytes)?,
+            Err(_) => return Err(format!("Could not read file `{}`", path.display())),
+        };
+
+        match toml::from_str(&contents) {
+            Ok(table) => merge_tables(&mut merged, table),
+            Err(e) => return Err(format!("Unable to load data from `{}` due to error: {}", path.display(), e.message())),
+        }
+    }
+
+    // Builds without MQTT accept the same files, despite not knowing these sections
+    #[cfg(not(feature = "mqtt"))]
+    for section in ["mqtt_broker", "mqtt", "homeassistant_rest", "metadata"] {
+        merged.remove(section);
+    }
+
+    // Only fully checked once merged, as an earlier file may leave out fields a later one sets
+    let data: Data = match toml::Value::Table(merged.clone()).try_into() {
+        Ok(d) => d,
+        Err(e) => {
+            let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+            let e = format!("Unable to load data from `{}` due to error: {}", paths.join("`, `"), e.message());
+            #[cfg(feature = "mqtt")]
+            if let Some(data) = without_mqtt(merged, &e) {
+                return Ok(data);
+            }
+            return Err(e);
+        }
+    };
+
+    Ok(data)
+}
+
+/// Stand-ins for the sections MQTT needs, which are never used as MQTT is turned off with them.
+#[cfg(feature = "mqtt")]
+const MQTT_PLACEHOLDERS: &str = r#"
+[mqtt_broker]
+host = ""
+port = 0
+username = ""
+password = ""
+
+[mqtt]
+room = ""
+identifier = ""
+name = ""
+"#;
+
+/// The config with MQTT turned off, when it only fails to load because of the MQTT sections.
+#[cfg(feature = "mqtt")]
+fn without_mqtt(mut merged: toml::Table, error: &str) -> Option<Data> {
+    for section in ["mqtt_broker", "mqtt", "homeassistant_rest"] {
+        merged.remove(section);
+    }
+    merged.extend(toml::from_str::<toml::Table>(MQTT_PLACEHOLDERS).ok()?);
+    let mut data: Data = toml::Value::Table(merged).try_into().ok()?;
+    data.mqtt_error = Some(error.to_string());
+    Some(data)
+}
+
+#[cfg(feature = "embedded-config")]
+fn uses_embedded_config(paths: &[PathBuf]) -> bool {
+    !paths.iter().any(|path| path.exists())
+}
+
+/// Decodes a config file as UTF-8, which TOML requires, pointing at the first offending line
+/// otherwise. A leading byte order mark, as some Windows editors write, is dropped.
+fn decode_config(path: &Path, bytes: Vec<u8>) -> Result<String, String> {
+    if bytes.starts_with(&[0xFF, 0xFE]) || bytes.starts_with(&[0xFE, 0xFF]) {
+        return Err(format!("`{}` is saved as UTF-16, save it as UTF-8 instead", path.display()));
+    }
+    match String::from_utf8(bytes) {
+        Ok(contents) => Ok(contents.strip_prefix('\u{feff}').map(String::from).unwrap_or(contents)),
+        Err(e) => {
+            let valid = &e.as_bytes()[..e.utf8_error().valid_up_to()];
+            let line = valid.iter().filter(|&&b| b == b'\n').count() + 1;
+            Err(format!("`{}` is not valid UTF-8 (line {}), save it as UTF-8 instead, e.g. under the encoding option of the editor's Save As dialog",
+                path.display(), line))
+        },
+    }
+}
+
+/// Expands directories into the `.toml` files they contain, sorted by name.
+fn config_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => return Err(format!("Could not read config directory `{}`: {}", path.display(), e)),
+        };
+        let mut dir_files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|file| file.extension().is_some_and(|extension| extension == "toml") && file.is_file())
+            .collect();
+        dir_files.sort();
+        files.extend(dir_files);
+    }
+    Ok(files)
+}
+
+/// Merges `overlay` into `base`, recursing into tables present in both and replacing anything else.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge_tables(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            },
+        }
+    }
+}
+
+/// Opens the I2C bus and calibrates the sensor. Used at startup and to reinitialize a sensor that
+/// keeps failing.
+fn init_sensor(sampling_mode: SensorSamplingMode, stats: &Stats) -> Result<Bmp180, ExitCode> {
+    let mut i2c_dev = match LinuxI2CDevice::new(I2C_BUS_PATH, BMP085_I2C_ADDR) {
+        Ok(x) => x,
+        Err(e) => {
+            let e = io::Error::from(e);
+            error!("Cannot initialize I2C device `{}`: {}. {}", I2C_BUS_PATH, e, i2c_error_hint(&e));
+            return Err(ExitCode::FAILURE)
+        },
+    };
+
+    if let Some(chip_id) = detect_chip(&mut i2c_dev) {
+        stats.chip_id.store(chip_id, Ordering::Relaxed);
+    }
+    if stats.coefficients.get().is_none() {
+        if let Some(coefficients) = read_coefficients(&mut i2c_dev) {
+            let _ = stats.coefficients.set(coefficients);
+        }
+    }
+
+    match BMP085BarometerThermometer::new(i2c_dev, sampling_mode.into()) {
+        Ok(sensor) => Ok(sensor),
+        Err(e) => {
+            error!("Can't initialize BMP180 thermostat sensor: {}", e);
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+/// Warns when the bus doesn't run at `i2c_bus_speed_hz`. On a Raspberry Pi the speed is set with
+/// `dtparam=i2c_arm_baudrate` in `config.txt`, and the device tree reports it back.
+fn check_bus_speed(wanted: u32) {
+    let bus = Path::new(I2C_BUS_PATH).file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let path = format!("/sys/class/i2c-adapter/{}/of_node/clock-frequency", bus);
+    let speed = match fs::read(&path).ok().and_then(|bytes| <[u8; 4]>::try_from(bytes).ok()) {
+        Some(bytes) => u32::from_be_bytes(bytes),
+        None => {
+            warn!("Cannot read the I2C bus speed from `{}` to check it against i2c_bus_speed_hz", path);
+            return;
+        }
+    };
+    if speed == wanted {
+        debug!("I2C bus runs at {} Hz", speed);
+    } else {
+        warn!("I2C bus runs at {} Hz rather than the configured {} Hz. Set `dtparam=i2c_arm_baudrate={}` in config.txt and reboot", speed, wanted, wanted);
+    }
+}
+
+/// Initializes the sensor, retrying `init_retries` times. Read failures later on are left to
+/// `reinit_after_failures` instead, as the sensor was there once.
+fn init_sensor_at_startup(sensor_config: &Sensor, stats: &Stats) -> Result<Bmp180, ExitCode> {
+    let mut attempts: u32 = 0;
+    loop {
+        let code = match init_sensor(sensor_config.sampling_mode, stats) {
+            Ok(sensor) => return Ok(sensor),
+            Err(code) => code,
+        };
+        if sensor_config.fail_fast_on_no_sensor && sensor_absent() {
+            error!("Nothing answers at {:#04x} on `{}`, giving up as no sensor is connected", BMP085_I2C_ADDR, I2C_BUS_PATH);
+            return Err(code);
+        }
+        if attempts == sensor_config.init_retries {
+            if attempts > 0 {
+                error!("Giving up after {} attempts to initialize the sensor", attempts + 1);
+            }
+            return Err(code);
+        }
+        attempts += 1;
+        warn!("Retrying sensor initialization in {}s ({} of {})", sensor_config.init_retry_delay_seconds, attempts, sensor_config.init_retries);
+        thread::sleep(Duration::from_secs(sensor_config.init_retry_delay_seconds));
+    }
+}
+
+/// Whether the bus opens but nothing acknowledges the sensor's address. A bus that can't be opened
+/// doesn't count, as its driver may still be loading.
+fn sensor_absent() -> bool {
+    LinuxI2CDevice::new(I2C_BUS_PATH, BMP085_I2C_ADDR).is_ok_and(|mut dev| dev.smbus_read_byte().is_err())
+}
+
+/// Logs whether the sensor still answers at its address and, if it doesn't, what else answers on
+/// the bus, turning a run of failed reads into a hint at the hardware.
+fn probe_sensor() {
+    let mut dev = match LinuxI2CDevice::new(I2C_BUS_PATH, BMP085_I2C_ADDR) {
+        Ok(dev) => dev,
+        Err(e) => {
+            let e = io::Error::from(e);
+            error!("Cannot open I2C bus `{}` to check on the sensor: {}. {}", I2C_BUS_PATH, e, i2c_error_hint(&e));
+            return;
+        }
+    };
+    match dev.smbus_read_byte_data(CHIP_ID_REGISTER) {
+        Ok(BMP180_CHIP_ID) => warn!("Sensor still responds at {:#04x}, so the failed reads point at a flaky connection or a failing chip", BMP085_I2C_ADDR),
+        Ok(chip_id) => error!("A device with chip id {:#04x} responds at {:#04x} instead of the BMP180 ({:#04x}), check what is wired to the bus", chip_id, BMP085_I2C_ADDR, BMP180_CHIP_ID),
+        Err(_) => {
+            // Reading a byte is how i2cdetect probes, and harmless for the devices found on
+            // sensor boards
+            let answering: Vec<String> = (0x08..=0x77u16)
+                .filter(|&address| address != BMP085_I2C_ADDR)
+                .filter(|&address| LinuxI2CDevice::new(I2C_BUS_PATH, address).is_ok_and(|mut dev| dev.smbus_read_byte().is_ok()))
+                .map(|address| format!("{:#04x}", address))
+                .collect();
+            if answering.is_empty() {
+                error!("Device no longer responds at {:#04x} and nothing else answers on `{}`, check the wiring and power", BMP085_I2C_ADDR, I2C_BUS_PATH);
+            } else {
+                error!("Device no longer responds at {:#04x}, check the wiring. Still answering on `{}`: {}", BMP085_I2C_ADDR, I2C_BUS_PATH, answering.join(", "));
+            }
+        }
+    }
+}
+
+/// Reads the chip id register and logs which chip answered. The BMP085 and BMP180 share an id, so
+/// they can't be told apart, but a different id means the module isn't what it claims to be.
+fn detect_chip(dev: &mut LinuxI2CDevice) -> Option<u8> {
+    let chip_id = match dev.smbus_read_byte_data(CHIP_ID_REGISTER) {
+        Ok(id) => id,
+        Err(e) => {
+            warn!("Could not read the chip id: {}", e);
+            return None;
+        }
+    };
+
+    match chip_id {
+        BMP180_CHIP_ID => info!("Detected a BMP085/BMP180 (chip id {:#04x})", chip_id),
+        0x56..=0x58 => warn!("Detected a BMP280 (chip id {:#04x}) instead of a BMP085/BMP180, readings will be wrong. Is the module mislabelled?", chip_id),
+        0x60 => warn!("Detected a BME280 (chip id {:#04x}) instead of a BMP085/BMP180, readings will be wrong. Is the module mislabelled?", chip_id),
+        _ => warn!("Unexpected chip id {:#04x}, expected {:#04x} for a BMP085/BMP180. The module may be counterfeit or mis-wired", chip_id, BMP180_CHIP_ID),
+    }
+    Some(chip_id)
+}
+
+/// Reads the calibration coefficients, warning when they look like those of a faulty or clone
+/// module. The bmp085 crate reads them too, but keeps them to itself.
+fn read_coefficients(dev: &mut LinuxI2CDevice) -> Option<Coefficients> {
+    let bytes = match dev.smbus_read_i2c_block_data(COEFFICIENTS_REGISTER, 22) {
+        Ok(bytes) if bytes.len() == 22 => bytes,
+        Ok(bytes) => {
+            warn!("Could not read the calibration coefficients: got {} of 22 bytes", bytes.len());
+            return None;
+        },
+        Err(e) => {
+            warn!("Could not read the calibration coefficients: {}", e);
+            return None;
+        }
+    };
+    let words: Vec<u16> = bytes.chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]])).collect();
+    let coefficients = Coefficients {
+        ac1: words[0] as i16,
+        ac2: words[1] as i16,
+        ac3: words[2] as i16,
+        ac4: words[3],
+        ac5: words[4],
+        ac6: words[5],
+        b1: words[6] as i16,
+        b2: words[7] as i16,
+        mb: words[8] as i16,
+        mc: words[9] as i16,
+        md: words[10] as i16,
+    };
+    debug!("Read calibration coefficients {:?}", coefficients);
+
+    // Per the datasheet, no genuine coefficient is all zeros or all ones
+    if words.iter().any(|&word| word == 0x0000 || word == 0xFFFF) {
+        warn!("Calibration coefficients contain 0x0000 or 0xFFFF, which a genuine BMP180 never stores. The module may be faulty or counterfeit");
+    }
+    Some(coefficients)
+}
+
+/// Reads the sensor on its bus worker, giving up after the configured read timeout. A read that
+/// times out isn't retried, as the retry would only queue up behind the hung one.
+fn read_on_worker(worker: &I2cWorker<Bmp180>, sensor_config: &Sensor, read_temperature: bool, read_pressure: bool) -> Result<Reading, Box<dyn Error>> {
+    let samples = sensor_config.oversample.max(1);
+    let mut attempt = 0;
+    loop {
+        // The error is stringified as boxed errors can't be sent back across threads
+        let result = worker.run(sensor_config.read_timeout() * samples, move |sensor| {
+            read_averaged(sensor, samples, read_temperature, read_pressure).map_err(|e| e.to_string())
+        })?;
+        let retry = match &result {
+            Ok(reading) => reading.temperature_failed || reading.pressure_failed,
+            Err(_) => true,
+        };
+        if !retry || attempt == sensor_config.read_retries {
+            return Ok(result?);
+        }
+        attempt += 1;
+        debug!("Retrying the read in {}ms, attempt {} of {}", sensor_config.read_retry_delay_ms, attempt, sensor_config.read_retries);
+        thread::sleep(Duration::from_millis(sensor_config.read_retry_delay_ms));
+    }
+}
+
+/// Averages `samples` reads taken back to back. A single failed read fails them all.
+fn read_averaged(sensor: &mut Bmp180, samples: u32, read_temperature: bool, read_pressure: bool) -> Result<Reading, Box<dyn Error>> {
+    if samples == 1 {
+        return read_from_sensor(sensor, read_temperature, read_pressure);
+    }
+    let started = Instant::now();
+    let readings = (0..samples)
+        .map(|_| read_from_sensor(sensor, read_temperature, read_pressure))
+        .collect::<Result<Vec<Reading>, _>>()?;
+    let mean = |value: fn(&Reading) -> Option<f32>| {
+        readings.iter().map(value).sum::<Option<f32>>().map(|sum| sum / samples as f32)
+    };
+    Ok(Reading {
+        temperature: mean(|r| r.temperature),
+        pressure: mean(|r| r.pressure),
+        read_latency: started.elapsed(),
+        temperature_drift: None,
+        raw_temperature: mean(|r| r.raw_temperature),
+        raw_pressure: mean(|r| r.raw_pressure),
+        temperature_failed: readings.iter().any(|r| r.temperature_failed),
+        pressure_failed: readings.iter().any(|r| r.pressure_failed),
+    })
+}
+
+fn read_from_sensor(sensor: &mut Bmp180, read_temperature: bool, read_pressure: bool) -> Result<Reading, Box<dyn Error>> {
+    let started = Instant::now();
+    let temp = read_temperature.then(|| sensor.temperature_celsius());
+    let pressure = read_pressure.then(|| sensor.pressure_kpa());
+    let read_latency = started.elapsed();
+
+    // Only fails when nothing could be read, a measurement failing on its own is left out so the
+    // other one is still published
+    let (temp, pressure) = match (temp, pressure) {
+        (Some(Err(temperature)), None) => return Err(ReadError { temperature: Some(temperature), pressure: None }.into()),
+        (None, Some(Err(pressure))) => return Err(ReadError { temperature: None, pressure: Some(pressure) }.into()),
+        (Some(Err(temperature)), Some(Err(pressure))) => return Err(ReadError { temperature: Some(temperature), pressure: Some(pressure) }.into()),
+        readings => readings,
+    };
+    if let Some(Err(e)) = &temp {
+        warn!("Failed to read the temperature, keeping only the pressure: {}", e);
+    }
+    if let Some(Err(e)) = &pressure {
+        // The pressure conversion is the longer one and is compensated with a fresh temperature
+        // read, so failing on its own it points at the timing rather than the wiring
+        warn!("Failed to read the pressure, keeping only the temperature: {}. A pressure conversion failing on its own is often a timing problem; try a faster sampling_mode", e);
+    }
+    let temperature = temp.as_ref().and_then(|r| r.as_ref().ok()).copied();
+    let pressure_value = pressure.as_ref().and_then(|r| r.as_ref().ok()).copied();
+    let reading = Reading {
+        temperature,
+        pressure: pressure_value,
+        read_latency,
+        temperature_drift: None,
+        raw_temperature: temperature,
+        raw_pressure: pressure_value,
+        temperature_failed: matches!(temp, Some(Err(_))),
+        pressure_failed: matches!(pressure, Some(Err(_))),
+    };
+
+    debug!("Read sensor data. Temp: [{:?}]. Pressure: [{:?}]. Took [{:?}].", reading.temperature, reading.pressure, read_latency);
+    Ok(reading)
+}
+
+/// Applies the configured calibration and offsets to a reading taken from the sensor, keeping the result within
+/// the range the BMP180 is specified for.
+fn calibrate(sensor: &Sensor, reading: Reading) -> Reading {
+    // Checked at startup
+    let calibration = sensor.temperature_calibration().ok().flatten();
+    let temperature = reading.temperature.map(|t| {
+        let temperature = Measurement::new(t, Unit::Celsius);
+        match calibration {
+            Some((slope, intercept)) => temperature.scaled(slope, intercept),
+            None => temperature,
+        }.with_offset(sensor.temperature_offset)
+    });
+    let pressure = reading.pressure.map(|p| Measurement::new(p, Unit::Kilopascals).with_offset(sensor.pressure_offset));
+
+    // The pressure is compensated using the temperature, so it can't be trusted when the
+    // temperature is out of range even if it looks plausible itself
+    if sensor.consistency_check {
+        if let Some(t) = temperature.filter(|t| !TEMPERATURE_RANGE.contains(&t.raw)) {
+            warn!("Temperature of {}{} is out of range, discarding this reading", t, t.unit.symbol());
+            return Reading { temperature: None, pressure: None, ..reading };
+        }
+    }
+
+    // An infinite value is left for discard_non_finite, as clamping would pass it off as a bound
+    let clamp = |m: Measurement, range: &RangeInclusive<f32>| {
+        if m.raw.is_finite() { m.clamped(*range.start(), *range.end()).raw } else { m.raw }
+    };
+    Reading {
+        temperature: temperature.map(|t| clamp(t, &TEMPERATURE_RANGE)),
+        pressure: pressure.map(|p| clamp(p, &sensor.pressure_range())),
+        ..reading
+    }
+}
+
+/// Fails a reading that got only one of the measurements when `paired_reads` is set, so nothing
+/// from it is published next to a value kept from an earlier read.
+fn check_paired(sensor: &Sensor, reading: Reading) -> Result<Reading, Box<dyn Error>> {
+    if !sensor.paired_reads || reading.temperature.is_some() == reading.pressure.is_some() {
+        return Ok(reading);
+    }
+    let missing = if reading.temperature.is_none() { "temperature" } else { "pressure" };
+    Err(format!("got no {}, dropping the whole reading as paired_reads is set", missing).into())
+}
+
+/// Compares the temperature with the reference sensor, warning when they have drifted apart.
+fn cross_check(sensor: &Sensor, reading: Reading) -> Reading {
+    let (Some(path), Some(temperature)) = (&sensor.reference_temperature_path, reading.temperature) else {
+        return reading;
+    };
+    let reference = match read_reference_temperature(path) {
+        Ok(reference) => reference,
+        Err(e) => {
+            warn!("Could not read reference temperature from `{}`: {}", path.display(), e);
+            return reading;
+        }
+    };
+
+    let drift = temperature - reference;
+    if drift.abs() > sensor.drift_threshold {
+        warn!("Temperature of {}°C is {:.2}°C off the reference sensor, the BMP180 may need calibrating", temperature, drift);
+    }
+    Reading { temperature_drift: Some(drift), ..reading }
+}
+
+/// Reads a 1-Wire temperature in °C, from either the `temperature` file holding millidegrees or
+/// the older `w1_slave` file ending `t=<millidegrees>`.
+fn read_reference_temperature(path: &Path) -> Result<f32, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    if contents.contains("crc=") && !contents.contains("YES") {
+        return Err("CRC check failed".into());
+    }
+    let millidegrees = contents.rsplit("t=").next().unwrap_or_default().trim();
+    Ok(millidegrees.parse::<i32>()? as f32 / 1000.0)
+}
+
+/// Drops measurements that are NaN or infinite, which have no representation in JSON.
+fn discard_non_finite(reading: Reading) -> Reading {
+    let finite = |name: &str, value: Option<f32>| value.filter(|v| {
+        if !v.is_finite() {
+            warn!("Discarding {} reading of {}", name, v);
+        }
+        v.is_finite()
+    });
+    Reading {
+        temperature: finite("temperature", reading.temperature),
+        pressure: finite("pressure", reading.pressure),
+        temperature_drift: finite("temperature drift", reading.temperature_drift),
+        raw_temperature: reading.raw_temperature.filter(|v| v.is_finite()),
+        raw_pressure: reading.raw_pressure.filter(|v| v.is_finite()),
+        ..reading
+    }
+}
+
+/// Reduces station pressure, in kPa, to the configured reference. QFF needs the temperature in
+/// °C, so gives `None` without one.
+fn reduce_pressure(sensor: &Sensor, pressure: f32, temperature: Option<f32>) -> Option<f32> {
+    let elevation = sensor.station_elevation;
+    match sensor.pressure_reference {
+        PressureReference::Station => Some(pressure),
+        PressureReference::Qnh => {
+            // The ICAO formula works in hPa
+            let exponent = 0.190263;
+            let hpa = ((pressure * 10.0).powf(exponent) + 8.417286e-5 * elevation).powf(1.0 / exponent);
+            Some(hpa / 10.0)
+        },
+        PressureReference::Qff => {
+            // Hypsometric equation, taking the air column below the station to be at the mean of
+            // the measured temperature and the standard lapse rate extended to sea level
+            let mean_temperature = temperature? + 273.15 + 0.0065 * elevation / 2.0;
+            Some(pressure * (9.80665 * elevation / (287.05 * mean_temperature)).exp())
+        },
+    }
+}
+
+/// Calculates the altitude from the given pressure using the international barometric formula.
+fn calculate_altitude(pressure: f32, sea_level_pressure: f32) -> Measurement {
+    Measurement::new(44330.0 * (1.0 - (pressure / sea_level_pressure).powf(1.0 / 5.255)), Unit::Meters)
+}
+
+/// Calculates the pressure altitude, the height in the ICAO standard atmosphere at which the
+/// pressure is the station pressure in kPa.
+fn calculate_pressure_altitude(pressure: f32) -> Measurement {
+    Measurement::new(44330.8 * (1.0 - (pressure / 101.325).powf(0.190263)), Unit::Meters)
+}
+
+/// Calculates the density altitude, the height in the ICAO standard atmosphere at which the air is
+/// as dense as at the station, from the station pressure in kPa and the temperature in °C. Taken
+/// as dry air, as the BMP180 doesn't measure humidity.
+fn calculate_density_altitude(pressure: f32, temperature: f32) -> Measurement {
+    let density_ratio = (pressure / 101.325) * (288.15 / (temperature + 273.15));
+    Measurement::new(44330.8 * (1.0 - density_ratio.powf(0.234969)), Unit::Meters)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const BASE_CONFIG: &str = r#"
+[mqtt_broker]
+host = "127.0.0.1"
+port = 1883
+username = "sensor"
+password = "hunter2"
+
+[mqtt]
+room = "hallway"
+identifier = "0000"
+name = "Thermostat Sensor"
+
+[logging]
+"#;
+
+    /// A config with the required sections, and `extra` merged over them as a later config file
+    /// would be.
+    #[cfg(feature = "mqtt")]
+    pub(crate) fn config(extra: &str) -> Data {
+        let mut merged: toml::Table = toml::from_str(BASE_CONFIG).unwrap();
+        merge_tables(&mut merged, toml::from_str(extra).unwrap());
+        toml::Value::Table(merged).try_into().unwrap()
+    }
+
+    /// A reading of `temperature` and `pressure` with nothing else.
+    pub(crate) fn reading(temperature: Option<f32>, pressure: Option<f32>) -> Reading {
+        Reading {
+            temperature,
+            pressure,
+            read_latency: Duration::ZERO,
+            temperature_drift: None,
+            raw_temperature: temperature,
+            raw_pressure: pressure,
+            temperature_failed: false,
+            pressure_failed: false,
+        }
+    }
+
+    #[test]
+    fn smoothing_keeps_full_precision() {
+        // Rounded to a tenth at every step, the average would stay at 20.0 for good
+        let mut ema = Ema::default();
+        let mut smoothed = ema.smooth(0.5, reading(Some(20.0), None));
+        for _ in 0..10 {
+            smoothed = ema.smooth(0.5, reading(Some(20.04), None));
+        }
+        let temperature = smoothed.temperature.unwrap();
+        assert!(temperature > 20.039 && temperature <= 20.04, "{}", temperature);
+        assert_eq!(ema.temperature, smoothed.temperature);
+    }
+
+    #[test]
+    fn config_with_metadata_loads() {
+        let path = std::env::temp_dir().join(format!("bmp180-metadata-{}.toml", std::process::id()));
+        // Builds without MQTT drop [metadata] along with the sections it goes out through
+        fs::write(&path, format!("{}\n[metadata]\nlocation = \"Hallway\"\nasset_tag = 1234\n\n[sensor]\npoll_interval = 5.0\n", BASE_CONFIG)).unwrap();
+        let config = read_config(std::slice::from_ref(&path));
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.sensor.poll_interval, 5.0);
+        #[cfg(feature = "mqtt")]
+        assert_eq!(config.metadata.get("location").and_then(toml::Value::as_str), Some("Hallway"));
+    }
+
+    fn paired_sensor() -> Sensor {
+        Sensor { paired_reads: true, ..Sensor::default() }
+    }
+
+    #[test]
+    fn paired_reads_pass_complete_pairs() {
+        let sensor = paired_sensor();
+        let reading = check_paired(&sensor, reading(Some(21.5), Some(101.2))).unwrap();
+        assert_eq!((reading.temperature, reading.pressure), (Some(21.5), Some(101.2)));
+    }
+
+    #[test]
+    fn paired_reads_drop_a_reading_missing_either_measurement() {
+        let sensor = paired_sensor();
+        let temperature_failed = Reading { temperature_failed: true, ..reading(None, Some(101.2)) };
+        let pressure_failed = Reading { pressure_failed: true, ..reading(Some(21.5), None) };
+        for (reading, missing) in [(temperature_failed, "temperature"), (pressure_failed, "pressure")] {
+            let e = check_paired(&sensor, reading).err().unwrap();
+            assert!(e.to_string().contains(&format!("got no {}", missing)), "{}", e);
+        }
+    }
+
+    #[test]
+    fn paired_reads_never_mix_measurements_of_different_reads() {
+        let sensor = paired_sensor();
+        let reads = [
+            reading(Some(20.0), Some(101.0)),
+            reading(Some(21.0), None),
+            reading(None, Some(102.0)),
+            reading(Some(23.0), Some(103.0)),
+        ];
+        let passed: Vec<_> = reads.into_iter()
+            .filter_map(|reading| check_paired(&sensor, reading).ok())
+            .map(|reading| (reading.temperature, reading.pressure))
+            .collect();
+        assert_eq!(passed, [(Some(20.0), Some(101.0)), (Some(23.0), Some(103.0))]);
+    }
+
+    #[test]
+    fn unpaired_reads_pass_single_measurements() {
+        let reading = check_paired(&Sensor::default(), reading(Some(21.5), None)).unwrap();
+        assert_eq!((reading.temperature, reading.pressure), (Some(21.5), None));
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn names_with_mqtt_separators_or_wildcards_are_rejected() {
+        for c in ['/', '+', '#'] {
+            let config = config(&format!("mqtt.name = \"Hallway {} Sensor\"", c));
+            let e = validate_mqtt_names(&config.mqtt).unwrap_err();
+            assert!(e.contains(&format!("`{}`", c)), "{}", e);
+        }
+        for field in ["room", "identifier"] {
+            for c in ['+', '#'] {
+                let config = config(&format!("mqtt.{} = \"hall{}way\"", field, c));
+                let e = validate_mqtt_names(&config.mqtt).unwrap_err();
+                assert!(e.starts_with(field), "{}", e);
+            }
+        }
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn plain_names_are_accepted() {
+        let config = config("mqtt.name = \"Thermostat Sensor (Hallway)\"\nmqtt.room = \"upstairs_hallway\"");
+        assert_eq!(validate_mqtt_names(&config.mqtt), Ok(()));
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn nested_rooms_are_rejected_with_discovery() {
+        let with_discovery = config("mqtt.room = \"upstairs/hallway\"");
+        let e = validate_mqtt_names(&with_discovery.mqtt).unwrap_err();
+        assert!(e.starts_with("room `upstairs/hallway` contains `/`"), "{}", e);
+
+        // Without discovery the room only nests the device's own topics
+        let without_discovery = config("mqtt.room = \"upstairs/hallway\"\nmqtt.publish_discovery = false");
+        assert_eq!(validate_mqtt_names(&without_discovery.mqtt), Ok(()));
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn serialized_config_redacts_the_password() {
+        let config = config("");
+        // The config echo goes out as JSON, --dump-config prints TOML
+        for serialized in [serde_json::to_string(&config).unwrap(), toml::to_string(&config).unwrap()] {
+            assert!(serialized.contains(r#""***""#), "{}", serialized);
+            assert!(!serialized.contains("hunter2"), "{}", serialized);
+        }
+    }
+}
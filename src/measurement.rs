@@ -0,0 +1,120 @@
+use std::fmt;
+
+const FEET_PER_METER: f32 = 3.28084;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    Kilopascals,
+    Meters,
+    Feet,
+}
+
+impl Unit {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Fahrenheit => "°F",
+            Unit::Kilopascals => "kPa",
+            Unit::Meters => "m",
+            Unit::Feet => "ft",
+        }
+    }
+}
+
+/// A value together with its unit, so every transformation between the sensor and the published
+/// payload goes through one place.
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    pub raw: f32,
+    pub unit: Unit,
+}
+
+impl Measurement {
+    pub fn new(raw: f32, unit: Unit) -> Self {
+        Measurement { raw, unit }
+    }
+
+    /// Shifts the value by `offset`, given in the measurement's unit.
+    pub fn with_offset(self, offset: f32) -> Self {
+        Measurement { raw: self.raw + offset, ..self }
+    }
+
+    /// Maps the value through `slope * value + intercept`, for a linear calibration.
+    pub fn scaled(self, slope: f32, intercept: f32) -> Self {
+        Measurement { raw: slope * self.raw + intercept, ..self }
+    }
+
+    /// `None` when the units measure different quantities.
+    pub fn converted_to(self, unit: Unit) -> Option<Self> {
+        let raw = match (self.unit, unit) {
+            (from, to) if from == to => self.raw,
+            (Unit::Meters, Unit::Feet) => self.raw * FEET_PER_METER,
+            (Unit::Feet, Unit::Meters) => self.raw / FEET_PER_METER,
+            (Unit::Celsius, Unit::Fahrenheit) => self.raw * 9.0 / 5.0 + 32.0,
+            (Unit::Fahrenheit, Unit::Celsius) => (self.raw - 32.0) * 5.0 / 9.0,
+            _ => return None,
+        };
+        Some(Measurement { raw, unit })
+    }
+
+    /// Rounds to `decimals`, turning a value that rounds to `-0` into `0`.
+    pub fn rounded(self, decimals: usize) -> Self {
+        let factor = 10f32.powi(decimals as i32);
+        Measurement { raw: (self.raw * factor).round() / factor + 0.0, ..self }
+    }
+
+    pub fn clamped(self, min: f32, max: f32) -> Self {
+        Measurement { raw: self.raw.clamp(min, max), ..self }
+    }
+}
+
+impl fmt::Display for Measurement {
+    /// Formats like the raw value, honoring the precision if one is given. A value that shows as
+    /// zero never gets a sign, so readings hovering around 0 don't flip between `0.0` and `-0.0`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let formatted = match f.precision() {
+            Some(precision) => format!("{:.*}", precision, self.raw),
+            None => self.raw.to_string(),
+        };
+        match formatted.strip_prefix('-') {
+            Some(unsigned) if unsigned.bytes().all(|b| b == b'0' || b == b'.') => f.write_str(unsigned),
+            _ => f.write_str(&formatted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn celsius(raw: f32) -> Measurement {
+        Measurement::new(raw, Unit::Celsius)
+    }
+
+    #[test]
+    fn values_rounding_to_zero_lose_their_sign() {
+        for raw in [-0.0, -0.04, -0.001] {
+            assert_eq!(celsius(raw).rounded(1).to_string(), "0");
+            assert_eq!(format!("{:.1}", celsius(raw).rounded(1)), "0.0");
+            assert_eq!(format!("{:.1}", celsius(raw)), "0.0");
+        }
+    }
+
+    #[test]
+    fn sub_zero_values_keep_their_sign() {
+        assert_eq!(celsius(-0.05).rounded(1).to_string(), "-0.1");
+        assert_eq!(format!("{:.1}", celsius(-12.34)), "-12.3");
+        assert_eq!(format!("{:.2}", celsius(-0.006)), "-0.01");
+        assert_eq!(celsius(-40.0).to_string(), "-40");
+    }
+
+    #[test]
+    fn readings_crossing_zero_format_alike_on_both_sides() {
+        let formatted: Vec<String> = [-0.12, -0.04, 0.0, 0.04, 0.12].into_iter()
+            .map(|raw| format!("{:.1}", celsius(raw).rounded(1)))
+            .collect();
+        assert_eq!(formatted, ["-0.1", "0.0", "0.0", "0.0", "0.1"]);
+    }
+}
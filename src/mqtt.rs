@@ -0,0 +1,3093 @@
+use crate::{msgpack, Attribute, BrokerTransport, Data, SensorSamplingMode, StateClass, HomeAssistantRest, Layout, LoopSignal, MQTTBroker, OnReadFailure, PayloadEncoding, Reading, StateFormat, Stats, TemperatureTrigger, Transform, calculate_altitude, calculate_density_altitude, calculate_pressure_altitude, in_timezone, now, reduce_pressure, session_id, BMP180_CHIP_ID, POLL_INTERVAL_MAX};
+use crate::heartbeat;
+use crate::homeassistant_rest::{self, RestFallback};
+use crate::persistence;
+use crate::measurement::{Measurement, Unit};
+use crate::sink::Sink;
+use log::{info, debug, error, trace, warn};
+use rumqttc::{Client,ConnectReturnCode,Connection,ConnectionError,Event,Incoming,LastWill,MqttOptions,NetworkOptions,Outgoing,QoS,StateError,SubscribeFilter,TlsConfiguration,TlsError,Transport};
+use rumqttc::tokio_rustls::rustls;
+use secrecy::ExposeSecret;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+use time::{Date, OffsetDateTime};
+use time::format_description::well_known::Rfc3339;
+
+#[derive(Clone, Copy)]
+enum SensorComponent {
+    /// One per configured temperature unit.
+    Temperature(Unit),
+    Pressure,
+    /// Station pressure, before any reduction to `pressure_reference`.
+    RawPressure,
+    Altitude,
+    /// Altitude in the standard atmosphere at the station pressure, as an altimeter set to 1013.25 hPa reads.
+    PressureAltitude,
+    /// Altitude in the standard atmosphere with the density of the air at the station.
+    DensityAltitude,
+    TemperatureDrift,
+    /// Change per minute over `rate_window_seconds`.
+    TemperatureRate,
+    PressureRate,
+    /// The temperature as one of [`COMFORT_BANDS`].
+    ComfortBand,
+    RapidDrop,
+    Uptime,
+    ReadLatency,
+    LastUpdate,
+    ReinitCount,
+    ChipId,
+    PendingStates,
+    DroppedStates,
+    /// The Pi's own temperature, for telling self-heating apart from the room warming up.
+    CpuTemperature,
+}
+
+impl SensorComponent {
+    /// Stable identifier of the component, used as the unique_id suffix.
+    fn id(&self) -> &'static str {
+        match self {
+            SensorComponent::Temperature(Unit::Fahrenheit) => "temperature_fahrenheit",
+            SensorComponent::Temperature(_) => "temperature",
+            SensorComponent::Pressure => "pressure",
+            SensorComponent::RawPressure => "raw_pressure",
+            SensorComponent::Altitude => "altitude",
+            SensorComponent::PressureAltitude => "pressure_altitude",
+            SensorComponent::DensityAltitude => "density_altitude",
+            SensorComponent::TemperatureDrift => "temperature_drift",
+            SensorComponent::TemperatureRate => "temperature_rate",
+            SensorComponent::PressureRate => "pressure_rate",
+            SensorComponent::ComfortBand => "comfort_band",
+            SensorComponent::RapidDrop => "rapid_drop",
+            SensorComponent::Uptime => "uptime",
+            SensorComponent::ReadLatency => "read_latency",
+            SensorComponent::LastUpdate => "last_update",
+            SensorComponent::ReinitCount => "reinit_count",
+            SensorComponent::ChipId => "chip_id",
+            SensorComponent::PendingStates => "pending_states",
+            SensorComponent::DroppedStates => "dropped_states",
+            SensorComponent::CpuTemperature => "cpu_temperature",
+        }
+    }
+
+    /// Key of the component in the JSON state payload.
+    fn json_key(&self, config: &Data) -> String {
+        match self {
+            SensorComponent::Temperature(Unit::Fahrenheit) => format!("{}_fahrenheit", config.mqtt.json_keys.temperature),
+            SensorComponent::Temperature(_) => config.mqtt.json_keys.temperature.clone(),
+            SensorComponent::Pressure => config.mqtt.json_keys.pressure.clone(),
+            SensorComponent::RawPressure => format!("{}_raw", config.mqtt.json_keys.pressure),
+            SensorComponent::Altitude => config.mqtt.json_keys.altitude.clone(),
+            _ => self.id().to_string(),
+        }
+    }
+
+    /// Appended to the room to build the discovery topic.
+    fn topic_name(&self) -> &'static str {
+        match self {
+            SensorComponent::Temperature(Unit::Fahrenheit) => "TemperatureFahrenheit",
+            SensorComponent::Temperature(_) => "Temperature",
+            SensorComponent::Pressure => "Pressure",
+            SensorComponent::RawPressure => "RawPressure",
+            SensorComponent::Altitude => "Altitude",
+            SensorComponent::PressureAltitude => "PressureAltitude",
+            SensorComponent::DensityAltitude => "DensityAltitude",
+            SensorComponent::TemperatureDrift => "TemperatureDrift",
+            SensorComponent::TemperatureRate => "TemperatureRate",
+            SensorComponent::PressureRate => "PressureRate",
+            SensorComponent::ComfortBand => "ComfortBand",
+            SensorComponent::RapidDrop => "RapidDrop",
+            SensorComponent::Uptime => "Uptime",
+            SensorComponent::ReadLatency => "ReadLatency",
+            SensorComponent::LastUpdate => "LastUpdate",
+            SensorComponent::ReinitCount => "ReinitCount",
+            SensorComponent::ChipId => "ChipId",
+            SensorComponent::PendingStates => "PendingStates",
+            SensorComponent::DroppedStates => "DroppedStates",
+            SensorComponent::CpuTemperature => "CpuTemperature",
+        }
+    }
+
+    fn device_class(&self) -> Option<&'static str> {
+        match self {
+            SensorComponent::Temperature(_) | SensorComponent::CpuTemperature => Some("temperature"),
+            SensorComponent::Pressure | SensorComponent::RawPressure => Some("pressure"),
+            SensorComponent::Altitude | SensorComponent::PressureAltitude | SensorComponent::DensityAltitude => Some("distance"),
+            SensorComponent::Uptime | SensorComponent::ReadLatency => Some("duration"),
+            SensorComponent::LastUpdate => Some("timestamp"),
+            SensorComponent::ComfortBand => Some("enum"),
+            SensorComponent::TemperatureDrift | SensorComponent::TemperatureRate | SensorComponent::PressureRate | SensorComponent::RapidDrop
+                | SensorComponent::ReinitCount | SensorComponent::ChipId | SensorComponent::PendingStates | SensorComponent::DroppedStates => None,
+        }
+    }
+
+    /// State class announced in discovery, from `[mqtt.state_classes]` or else the default for the
+    /// component. `None` for text, timestamps and the binary sensor, which HA keeps no statistics of.
+    fn state_class(&self, config: &Data) -> Option<&'static str> {
+        let classes = &config.mqtt.state_classes;
+        let (configured, default) = match self {
+            SensorComponent::Temperature(_) => (classes.temperature, StateClass::Measurement),
+            SensorComponent::Pressure | SensorComponent::RawPressure => (classes.pressure, StateClass::Measurement),
+            SensorComponent::Altitude | SensorComponent::PressureAltitude | SensorComponent::DensityAltitude => {
+                (classes.altitude, StateClass::Measurement)
+            },
+            SensorComponent::TemperatureDrift => (classes.temperature_drift, StateClass::Measurement),
+            SensorComponent::TemperatureRate => (classes.temperature_rate, StateClass::Measurement),
+            SensorComponent::PressureRate => (classes.pressure_rate, StateClass::Measurement),
+            SensorComponent::CpuTemperature => (classes.cpu_temperature, StateClass::Measurement),
+            SensorComponent::Uptime => (classes.uptime, StateClass::TotalIncreasing),
+            SensorComponent::ReadLatency => (classes.read_latency, StateClass::Measurement),
+            SensorComponent::ReinitCount => (classes.reinit_count, StateClass::TotalIncreasing),
+            SensorComponent::PendingStates => (classes.pending_states, StateClass::Measurement),
+            SensorComponent::DroppedStates => (classes.dropped_states, StateClass::TotalIncreasing),
+            SensorComponent::ComfortBand | SensorComponent::RapidDrop | SensorComponent::LastUpdate | SensorComponent::ChipId => return None,
+        };
+        match configured.unwrap_or(default) {
+            StateClass::Measurement => Some("measurement"),
+            StateClass::Total => Some("total"),
+            StateClass::TotalIncreasing => Some("total_increasing"),
+            StateClass::None => None,
+        }
+    }
+
+    /// The transform of `[mqtt.transforms]` applied to the published value, if any.
+    fn transform<'a>(&self, config: &'a Data) -> Option<&'a Transform> {
+        let transforms = &config.mqtt.transforms;
+        match self {
+            SensorComponent::Temperature(_) => transforms.temperature.as_ref(),
+            SensorComponent::Pressure | SensorComponent::RawPressure => transforms.pressure.as_ref(),
+            SensorComponent::Altitude | SensorComponent::PressureAltitude | SensorComponent::DensityAltitude => transforms.altitude.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn unit<'a>(&self, config: &'a Data) -> Option<&'a str> {
+        if let Some(unit) = self.transform(config).and_then(|transform| transform.unit.as_deref()) {
+            return Some(unit);
+        }
+        match self {
+            SensorComponent::Temperature(unit) => Some(unit.symbol()),
+            SensorComponent::Pressure | SensorComponent::RawPressure => Some(Unit::Kilopascals.symbol()),
+            SensorComponent::Altitude | SensorComponent::PressureAltitude | SensorComponent::DensityAltitude => {
+                Some(Unit::from(config.sensor.altitude_unit).symbol())
+            },
+            SensorComponent::TemperatureDrift | SensorComponent::CpuTemperature => Some(Unit::Celsius.symbol()),
+            SensorComponent::TemperatureRate => Some("°C/min"),
+            SensorComponent::PressureRate => Some("kPa/min"),
+            SensorComponent::ComfortBand => None,
+            SensorComponent::Uptime => Some("s"),
+            SensorComponent::ReadLatency => Some("ms"),
+            SensorComponent::RapidDrop | SensorComponent::LastUpdate | SensorComponent::ReinitCount | SensorComponent::ChipId
+                | SensorComponent::PendingStates | SensorComponent::DroppedStates => None,
+        }
+    }
+
+    /// Entity name shown in HA. Left unset for components HA can name from their device class alone.
+    fn entity_name(&self) -> Option<&'static str> {
+        match self {
+            SensorComponent::Temperature(Unit::Fahrenheit) => Some("Temperature (°F)"),
+            SensorComponent::Temperature(_) | SensorComponent::Pressure => None,
+            SensorComponent::RawPressure => Some("Raw pressure"),
+            SensorComponent::Altitude => Some("Altitude"),
+            SensorComponent::PressureAltitude => Some("Pressure altitude"),
+            SensorComponent::DensityAltitude => Some("Density altitude"),
+            SensorComponent::TemperatureDrift => Some("Temperature drift"),
+            SensorComponent::TemperatureRate => Some("Temperature rate"),
+            SensorComponent::PressureRate => Some("Pressure rate"),
+            SensorComponent::ComfortBand => Some("Comfort"),
+            SensorComponent::RapidDrop => Some("Rapid pressure drop"),
+            SensorComponent::Uptime => Some("Uptime"),
+            SensorComponent::ReadLatency => Some("Read latency"),
+            SensorComponent::LastUpdate => Some("Last update"),
+            SensorComponent::ReinitCount => Some("Sensor reinitializations"),
+            SensorComponent::ChipId => Some("Chip ID"),
+            SensorComponent::PendingStates => Some("Pending state messages"),
+            SensorComponent::DroppedStates => Some("Dropped state messages"),
+            SensorComponent::CpuTemperature => Some("CPU temperature"),
+        }
+    }
+
+    /// Custom MDI icon. Left unset so HA falls back to the device class icon.
+    fn icon<'a>(&self, config: &'a Data) -> Option<&'a str> {
+        match self {
+            SensorComponent::Temperature(_) => config.mqtt.temperature_icon.as_deref(),
+            SensorComponent::Pressure | SensorComponent::RawPressure => config.mqtt.pressure_icon.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Published on the diagnostics schedule rather than with every reading.
+    fn is_scheduled_diagnostic(&self, config: &Data) -> bool {
+        config.mqtt.diagnostics_interval_seconds.is_some_and(|interval| interval > 0) && self.entity_category().is_some()
+    }
+
+    fn entity_category(&self) -> Option<&'static str> {
+        match self {
+            SensorComponent::Temperature(_) | SensorComponent::Pressure | SensorComponent::RawPressure | SensorComponent::Altitude
+                | SensorComponent::PressureAltitude | SensorComponent::DensityAltitude | SensorComponent::TemperatureRate
+                | SensorComponent::PressureRate | SensorComponent::ComfortBand | SensorComponent::RapidDrop => None,
+            _ => Some("diagnostic"),
+        }
+    }
+
+    /// Comes from a measurement of the sensor, so it gets an availability topic of its own with
+    /// `per_entity_availability`.
+    fn is_measurement(&self) -> bool {
+        matches!(self, SensorComponent::Temperature(_) | SensorComponent::Pressure | SensorComponent::RawPressure
+            | SensorComponent::Altitude | SensorComponent::PressureAltitude | SensorComponent::DensityAltitude
+            | SensorComponent::TemperatureDrift | SensorComponent::TemperatureRate | SensorComponent::PressureRate
+            | SensorComponent::ComfortBand | SensorComponent::RapidDrop)
+    }
+
+    /// Whether the measurement behind the component succeeded in `reading`, or `None` when it
+    /// wasn't due.
+    fn read_in(&self, reading: &Reading) -> Option<bool> {
+        let (value, failed) = match self {
+            SensorComponent::Temperature(_) | SensorComponent::TemperatureDrift | SensorComponent::TemperatureRate | SensorComponent::ComfortBand => {
+                (reading.temperature, reading.temperature_failed)
+            },
+            // The density altitude falls back on the last temperature like the pressure reduction
+            SensorComponent::Pressure | SensorComponent::RawPressure | SensorComponent::Altitude | SensorComponent::PressureAltitude
+                | SensorComponent::DensityAltitude | SensorComponent::PressureRate | SensorComponent::RapidDrop => {
+                (reading.pressure, reading.pressure_failed)
+            },
+            _ => return None,
+        };
+        if failed { Some(false) } else { value.map(|_| true) }
+    }
+
+    /// HA integration the component is discovered as.
+    fn platform(&self) -> &'static str {
+        match self {
+            SensorComponent::RapidDrop => "binary_sensor",
+            _ => "sensor",
+        }
+    }
+}
+
+/// Components published for the given config, in payload order.
+fn get_enabled_components(config: &Data) -> Vec<SensorComponent> {
+    let mut components: Vec<SensorComponent> = config.sensor.temperature_units.iter()
+        .map(|&unit| SensorComponent::Temperature(unit.into()))
+        .collect();
+    components.push(SensorComponent::Pressure);
+    if config.sensor.raw_pressure {
+        components.push(SensorComponent::RawPressure);
+    }
+    if config.sensor.altitude {
+        components.push(SensorComponent::Altitude);
+    }
+    if config.sensor.pressure_altitude {
+        components.push(SensorComponent::PressureAltitude);
+    }
+    if config.sensor.density_altitude {
+        components.push(SensorComponent::DensityAltitude);
+    }
+    if config.sensor.reference_temperature_path.is_some() {
+        components.push(SensorComponent::TemperatureDrift);
+    }
+    if config.sensor.rate_of_change {
+        components.extend([SensorComponent::TemperatureRate, SensorComponent::PressureRate]);
+    }
+    if config.sensor.comfort_bands.is_some() {
+        components.push(SensorComponent::ComfortBand);
+    }
+    if config.sensor.pressure_drop_threshold.is_some() {
+        components.push(SensorComponent::RapidDrop);
+    }
+    if config.mqtt.diagnostics {
+        components.extend([
+            SensorComponent::Uptime,
+            SensorComponent::ReadLatency,
+            SensorComponent::LastUpdate,
+            SensorComponent::ReinitCount,
+            SensorComponent::ChipId,
+        ]);
+        if config.mqtt.max_pending_states > 0 {
+            components.extend([SensorComponent::PendingStates, SensorComponent::DroppedStates]);
+        }
+    }
+    if config.mqtt.cpu_temperature {
+        components.push(SensorComponent::CpuTemperature);
+    }
+    components
+}
+
+/// States of the comfort band entity, from coldest to hottest.
+const COMFORT_BANDS: [&str; 5] = ["cold", "cool", "comfortable", "warm", "hot"];
+
+/// The band `temperature` falls in, given the upper bounds of all but the hottest band.
+fn comfort_band(bounds: [f32; 4], temperature: f32) -> &'static str {
+    COMFORT_BANDS[bounds.iter().take_while(|&&bound| temperature >= bound).count()]
+}
+
+enum StateValue {
+    Number(String),
+    Text(String),
+}
+
+/// Longest pause between attempts to resolve the broker in [`wait_for_network`], and between
+/// reconnects.
+const MAX_NETWORK_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Pause before retrying a state message that failed to publish.
+const STATE_RETRY_DELAY: Duration = Duration::from_millis(200);
+/// How soon state messages held back by a full MQTT queue are tried again.
+const PENDING_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How long to wait for queued messages to go out when disconnecting.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a reconnect has to hold before `online` is republished, when a grace period is set.
+const ONLINE_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Connections dropped within this long of being accepted count towards [`SHORT_SESSION_LIMIT`].
+const SHORT_SESSION: Duration = Duration::from_secs(5);
+
+/// Where the kernel reports the temperature of the Pi's SoC, in millidegrees Celsius.
+const CPU_TEMPERATURE_PATH: &str = "/sys/class/thermal/thermal_zone0/temp";
+
+/// Short connections in a row after which a duplicate client id is suspected.
+const SHORT_SESSION_LIMIT: u32 = 3;
+
+/// Pause before rebuilding a connection whose event loop stopped, so a new one failing the same
+/// way doesn't spin.
+const REBUILD_DELAY: Duration = Duration::from_secs(1);
+
+/// Log target of the packet trace enabled by `--trace-mqtt`.
+pub const TRACE_TARGET: &str = "mqtt_trace";
+
+/// A message ready to be published, as `(topic, payload)`. Binary with `payload_encoding = "msgpack"`.
+type StateMessage = (String, Vec<u8>);
+
+/// Token bucket allowing short bursts while capping the sustained publish rate.
+struct RateLimiter {
+    capacity: f64,
+    tokens: f64,
+    refill_per_second: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            capacity: per_minute as f64,
+            tokens: per_minute as f64,
+            refill_per_second: per_minute as f64 / 60.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Pressure readings over the last `pressure_drop_window_seconds`, to spot a rapid fall.
+#[derive(Default)]
+struct PressureTrend {
+    /// Oldest first.
+    samples: VecDeque<(Instant, f32)>,
+    dropping: bool,
+}
+
+impl PressureTrend {
+    /// Records `pressure`, which counts as dropping when it is more than `threshold` below the
+    /// highest reading within the window.
+    fn update(&mut self, config: &Data, threshold: f32, pressure: f32) {
+        let now = Instant::now();
+        let window = Duration::from_secs(config.sensor.pressure_drop_window_seconds);
+        while self.samples.front().is_some_and(|&(at, _)| now.duration_since(at) > window) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, pressure));
+
+        let peak = self.samples.iter().map(|&(_, p)| p).fold(pressure, f32::max);
+        let dropping = peak - pressure > threshold;
+        if dropping && !self.dropping {
+            warn!("Pressure fell {:.2} kPa to {} kPa within {}s", peak - pressure, pressure, window.as_secs());
+        } else if !dropping && self.dropping {
+            info!("Pressure no longer falling rapidly");
+        }
+        self.dropping = dropping;
+    }
+}
+
+/// Readings of one measurement over the last `rate_window_seconds`, for its change per minute.
+#[derive(Default)]
+struct RateOfChange {
+    /// Oldest first.
+    samples: VecDeque<(Instant, f32)>,
+}
+
+impl RateOfChange {
+    fn update(&mut self, config: &Data, value: f32) {
+        let now = Instant::now();
+        let window = Duration::from_secs(config.sensor.rate_window_seconds);
+        while self.samples.front().is_some_and(|&(at, _)| now.duration_since(at) > window) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, value));
+    }
+
+    /// Least-squares slope of the readings in the window, per minute, which smooths out the noise
+    /// of single readings. `None` until there are two readings apart in time.
+    fn per_minute(&self) -> Option<f32> {
+        let &(first, _) = self.samples.front()?;
+        let points: Vec<(f64, f64)> = self.samples.iter()
+            .map(|&(at, value)| (at.duration_since(first).as_secs_f64() / 60.0, value as f64))
+            .collect();
+        let count = points.len() as f64;
+        let mean_time = points.iter().map(|&(time, _)| time).sum::<f64>() / count;
+        let mean_value = points.iter().map(|&(_, value)| value).sum::<f64>() / count;
+        let covariance: f64 = points.iter().map(|&(time, value)| (time - mean_time) * (value - mean_value)).sum();
+        let variance: f64 = points.iter().map(|&(time, _)| (time - mean_time).powi(2)).sum();
+        if variance == 0.0 {
+            return None;
+        }
+        Some((covariance / variance) as f32)
+    }
+}
+
+/// Running aggregate of one measurement over a stats interval or a day.
+#[derive(Default)]
+struct Aggregate {
+    count: u64,
+    sum: f64,
+    sum_of_squares: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Aggregate {
+    fn add(&mut self, value: f32) {
+        let value = value as f64;
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        }
+        self.count += 1;
+        self.sum += value;
+        self.sum_of_squares += value * value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// The aggregates so far, or null without any readings. The standard deviation is that of
+    /// the population.
+    fn to_json(&self) -> Value {
+        if self.count == 0 {
+            return Value::Null;
+        }
+        let mean = self.sum / self.count as f64;
+        let variance = (self.sum_of_squares / self.count as f64 - mean * mean).max(0.0);
+        json!({
+            "count": self.count,
+            "min": self.min,
+            "max": self.max,
+            "mean": mean,
+            "stddev": variance.sqrt(),
+        })
+    }
+}
+
+/// Period the error summary covers.
+const ERROR_SUMMARY_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Cumulative `(read_failures, publish_failures, reconnects)`.
+type ErrorCounts = (u64, u64, u64);
+
+fn error_counts(stats: &Stats) -> ErrorCounts {
+    (
+        stats.read_failures.load(Ordering::Relaxed),
+        stats.publish_failures.load(Ordering::Relaxed),
+        stats.reconnects.load(Ordering::Relaxed),
+    )
+}
+
+/// Publishes on behalf of the read loop, holding state messages back while the broker is unreachable.
+pub struct Publisher {
+    client: Client,
+    connected: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    rate_limiter: Option<RateLimiter>,
+    started: Instant,
+    /// Most recent values, used to complete JSON payloads for readings that only took one measurement.
+    last_temperature: Option<f32>,
+    last_pressure: Option<f32>,
+    /// Reading restored from `[persistence]`, published on the first connect unless a live one
+    /// came first.
+    last_known: Option<Reading>,
+    /// The last reading published, completed with the values above.
+    last_reading: Option<Reading>,
+    /// State messages of the last reading, sent again as they are by `republish_last`. Going
+    /// through `publish_state` again would feed the stale values to the aggregates, trends and
+    /// triggers, and the deadbands would hold them back.
+    last_state_messages: Vec<StateMessage>,
+    /// Values last published, which the deadbands are measured from.
+    published_temperature: Option<f32>,
+    published_pressure: Option<f32>,
+    /// Whether any state was published yet, for `retain_first_state`.
+    state_published: bool,
+    /// Set while `offline` is announced because the sensor can't be read, and until the first
+    /// reading with a value, so HA never sees the device online without data.
+    sensor_unavailable: bool,
+    /// Ids of the measurement entities announced `offline` with `per_entity_availability`.
+    unavailable_entities: HashSet<&'static str>,
+    pressure_trend: PressureTrend,
+    temperature_rate: RateOfChange,
+    pressure_rate: RateOfChange,
+    /// Current poll interval in seconds, reported to the number entity of `poll_interval_control`.
+    poll_interval: f64,
+    /// When to announce `online` after a (re)connect.
+    online_due: Option<Instant>,
+    /// When to re-announce discovery, if it is republished periodically.
+    discovery_due: Option<Instant>,
+    /// Earliest time for the first state after discovery, see `post_discovery_delay_ms`.
+    state_not_before: Option<Instant>,
+    error_summary_due: Option<Instant>,
+    /// `publishes_sent` when it last moved, and when that was noticed, for `max_publish_gap_seconds`.
+    publishes_sent: (u64, Instant),
+    /// Alerts raised since a message last went out.
+    publish_gap_alerts: u32,
+    max_publish_gap: Option<Duration>,
+    diagnostics_due: Option<Instant>,
+    stats_due: Option<Instant>,
+    temperature_stats: Aggregate,
+    pressure_stats: Aggregate,
+    /// Day the daily summary is aggregated for, unset until its first reading.
+    daily_date: Option<Date>,
+    daily_temperature: Aggregate,
+    daily_pressure: Aggregate,
+    daily_due: Option<Instant>,
+    /// Daily summaries that came due while disconnected, as topic and payload.
+    pending_daily_summaries: Vec<(String, String)>,
+    /// Counts taken at each error summary, oldest first, back to the last one before the hour
+    /// began. The summary is the difference from the oldest, so it covers the hour plus at most
+    /// one summary interval.
+    error_snapshots: VecDeque<(Instant, ErrorCounts)>,
+    /// Whether a connection was made before, to tell reconnects apart.
+    connected_before: bool,
+    /// One entry per reading, which may span several messages in split mode.
+    offline_queue: VecDeque<Vec<StateMessage>>,
+    /// State messages and their retain flag waiting for room in the MQTT queue, oldest first.
+    pending_states: VecDeque<(StateMessage, bool)>,
+    pending_retry_due: Option<Instant>,
+    /// When a state message first found the broker unreachable, cleared on connect.
+    disconnected_since: Option<Instant>,
+    rest_fallback: RestFallback,
+    /// Whether each temperature trigger can fire, by name. Unset until the first temperature.
+    trigger_armed: HashMap<String, bool>,
+}
+
+impl Publisher {
+    pub fn new(config: &Data, client: Client, connected: Arc<AtomicBool>, stats: Arc<Stats>) -> Self {
+        let error_snapshots = VecDeque::from([(Instant::now(), error_counts(&stats))]);
+        let publishes_sent = (stats.publishes_sent.load(Ordering::Relaxed), Instant::now());
+        Publisher {
+            client,
+            connected,
+            stats,
+            rate_limiter: config.mqtt.max_publishes_per_minute.map(RateLimiter::new),
+            started: Instant::now(),
+            last_temperature: None,
+            last_pressure: None,
+            last_reading: None,
+            last_state_messages: Vec::new(),
+            published_temperature: None,
+            published_pressure: None,
+            last_known: config.persistence.as_ref().and_then(persistence::load_last_reading),
+            state_published: false,
+            sensor_unavailable: config.mqtt.availability,
+            unavailable_entities: HashSet::new(),
+            pressure_trend: PressureTrend::default(),
+            temperature_rate: RateOfChange::default(),
+            pressure_rate: RateOfChange::default(),
+            poll_interval: config.sensor.poll_interval,
+            online_due: None,
+            discovery_due: None,
+            state_not_before: None,
+            error_summary_due: match config.mqtt.error_summary_interval_seconds {
+                0 => None,
+                interval => Some(Instant::now() + Duration::from_secs(interval)),
+            },
+            error_snapshots,
+            publishes_sent,
+            publish_gap_alerts: 0,
+            max_publish_gap: config.mqtt.max_publish_gap_seconds.map(Duration::from_secs),
+            stats_due: match config.mqtt.stats_interval_seconds {
+                0 => None,
+                interval => Some(Instant::now() + Duration::from_secs(interval)),
+            },
+            temperature_stats: Aggregate::default(),
+            pressure_stats: Aggregate::default(),
+            daily_date: None,
+            daily_temperature: Aggregate::default(),
+            daily_pressure: Aggregate::default(),
+            daily_due: config.mqtt.daily_summary.then(|| Instant::now() + until_midnight(config)),
+            pending_daily_summaries: Vec::new(),
+            diagnostics_due: config.mqtt.diagnostics_interval_seconds
+                .filter(|&interval| interval > 0)
+                .map(|interval| Instant::now() + Duration::from_secs(interval)),
+            connected_before: false,
+            offline_queue: VecDeque::new(),
+            pending_states: VecDeque::new(),
+            pending_retry_due: None,
+            disconnected_since: None,
+            rest_fallback: RestFallback::new(),
+            trigger_armed: HashMap::new(),
+        }
+    }
+
+    /// Publishes through `client` from now on, after the connection was rebuilt.
+    fn set_client(&mut self, client: Client) {
+        self.client = client;
+    }
+
+    /// Brings the broker up to date after a (re)connect.
+    fn handle_connected(&mut self, config: &Data) -> Result<(), ExitCode> {
+        if self.connected_before {
+            self.stats.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+        self.connected_before = true;
+        self.disconnected_since = None;
+        self.rest_fallback.stop();
+
+        // Discovery goes out before any queued state so HA knows about the entities first
+        self.publish_discovery(config);
+        publish_attributes(&self.client, config, &self.stats)?;
+        if config.mqtt.poll_interval_control {
+            publish_poll_interval(&self.client, config, self.poll_interval)?;
+        }
+        if config.mqtt.availability && config.mqtt.per_entity_availability {
+            for sensor_component in get_enabled_components(config).into_iter().filter(SensorComponent::is_measurement) {
+                let online = !self.unavailable_entities.contains(sensor_component.id());
+                publish_entity_availability(&self.client, config, sensor_component, online)?;
+            }
+        }
+        self.flush_offline_queue(config)?;
+        self.publish_pending_daily_summaries(config)?;
+        if let Some(last_known) = self.last_known.take().filter(|_| self.last_reading.is_none()) {
+            self.publish_last_known(config, &last_known)?;
+        } else if config.mqtt.publish_unknown_on_start && !self.state_published {
+            debug!("Publishing unknown states until the first reading");
+            self.wait_for_discovery();
+            self.publish_states(config, get_unknown_state_messages(config), config.mqtt.retain_state)?;
+        } else if config.mqtt.republish_on_connect {
+            self.republish_latest(config)?;
+        }
+
+        if config.mqtt.availability {
+            let debounce = if config.mqtt.availability_grace_seconds > 0 { ONLINE_DEBOUNCE } else { Duration::ZERO };
+            self.online_due = Some(Instant::now() + debounce);
+            self.publish_due(config)?;
+        }
+        Ok(())
+    }
+
+    pub fn publish_config_echo(&self, config: &Data) -> Result<(), ExitCode> {
+        let topic = get_config_echo_topic(config);
+        let msg = match serde_json::to_string(config) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Failed to serialize config echo due to error: {}", e);
+                return Err(ExitCode::FAILURE);
+            }
+        };
+        debug!("Publishing config echo to topic [{}]", topic);
+        let qos = qos(config, Category::Diagnostics);
+        trace_publish(&topic, qos, true, &msg);
+        match self.client.publish(topic, qos, true, msg) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Failed to publish config echo due to error: {}", e);
+                Err(ExitCode::FAILURE)
+            }
+        }
+    }
+
+    /// Publishes the aggregates of the readings since the last stats and starts over.
+    fn publish_stats(&mut self, config: &Data) -> Result<(), ExitCode> {
+        let topic = get_stats_topic(config);
+        let msg = json!({
+            "interval_seconds": config.mqtt.stats_interval_seconds,
+            "temperature": self.temperature_stats.to_json(),
+            "pressure": self.pressure_stats.to_json(),
+        }).to_string();
+        self.temperature_stats = Aggregate::default();
+        self.pressure_stats = Aggregate::default();
+
+        debug!("Publishing stats to topic [{}]", topic);
+        let qos = qos(config, Category::Diagnostics);
+        trace_publish(&topic, qos, false, &msg);
+        match self.client.publish(topic, qos, false, msg) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Failed to publish stats due to error: {}", e);
+                Err(ExitCode::FAILURE)
+            }
+        }
+    }
+
+    /// Adds the reading to the day's summary, first publishing the previous day's if the date
+    /// changed since the last reading.
+    fn add_to_daily_summary(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        let today = now(config).date();
+        if self.daily_date.is_some_and(|date| date != today) {
+            self.publish_daily_summary(config)?;
+        }
+        self.daily_date = Some(today);
+        if let Some(temperature) = reading.temperature {
+            self.daily_temperature.add(temperature);
+        }
+        if let Some(pressure) = reading.pressure {
+            self.daily_pressure.add(pressure);
+        }
+        Ok(())
+    }
+
+    /// Publishes the aggregates of the day so far under its date and starts over. Held back until
+    /// the next connect while disconnected, as each day has a topic of its own.
+    fn publish_daily_summary(&mut self, config: &Data) -> Result<(), ExitCode> {
+        let Some(date) = self.daily_date.take() else {
+            return Ok(());
+        };
+        let msg = json!({
+            "date": date.to_string(),
+            "temperature": std::mem::take(&mut self.daily_temperature).to_json(),
+            "pressure": std::mem::take(&mut self.daily_pressure).to_json(),
+        }).to_string();
+        self.pending_daily_summaries.push((get_daily_summary_topic(config, &date.to_string()), msg));
+        if self.connected.load(Ordering::Relaxed) {
+            self.publish_pending_daily_summaries(config)?;
+        }
+        Ok(())
+    }
+
+    fn publish_pending_daily_summaries(&mut self, config: &Data) -> Result<(), ExitCode> {
+        let qos = qos(config, Category::Diagnostics);
+        for (topic, msg) in std::mem::take(&mut self.pending_daily_summaries) {
+            debug!("Publishing daily summary to topic [{}]", topic);
+            trace_publish(&topic, qos, true, &msg);
+            if let Err(e) = self.client.publish(topic, qos, true, msg) {
+                error!("Failed to publish daily summary due to error: {}", e);
+                return Err(ExitCode::FAILURE);
+            }
+        }
+        Ok(())
+    }
+
+    /// When the next publish gap alert is due if nothing goes out before then.
+    fn publish_gap_due(&self) -> Option<Instant> {
+        self.max_publish_gap.map(|gap| self.publishes_sent.1 + gap * 2u32.saturating_pow(self.publish_gap_alerts))
+    }
+
+    /// Raises an alert once no message has left for the broker in `max_publish_gap_seconds`,
+    /// catching publishes that fail without an error, and again at doubling intervals.
+    fn check_publish_gap(&mut self, config: &Data) {
+        let sent = self.stats.publishes_sent.load(Ordering::Relaxed);
+        if sent != self.publishes_sent.0 {
+            if self.publish_gap_alerts > 0 {
+                info!("Messages are leaving for the MQTT broker again");
+            }
+            self.publishes_sent = (sent, Instant::now());
+            self.publish_gap_alerts = 0;
+            return;
+        }
+        if self.publish_gap_due().is_none_or(|due| due > Instant::now()) {
+            return;
+        }
+        self.publish_gap_alerts += 1;
+        let gap = self.publishes_sent.1.elapsed();
+        let connected = self.connected.load(Ordering::Relaxed);
+        let state = if connected { "while connected" } else { "while disconnected" };
+        if self.publish_gap_alerts == 1 {
+            warn!("No message has left for the MQTT broker in {}s {}", gap.as_secs(), state);
+        } else {
+            error!("Still no message has left for the MQTT broker, for {}s now {}", gap.as_secs(), state);
+        }
+
+        // Queued without waiting, as a stuck event loop would block it; it goes out once the
+        // connection recovers
+        let topic = get_error_summary_topic(config);
+        let msg = json!({
+            "alert": "publish_gap",
+            "seconds": gap.as_secs(),
+            "connected": connected,
+        }).to_string();
+        let qos = qos(config, Category::Diagnostics);
+        trace_publish(&topic, qos, false, &msg);
+        if let Err(e) = self.client.try_publish(topic, qos, false, msg) {
+            debug!("Failed to queue publish gap alert due to error: {}", e);
+        }
+    }
+
+    fn publish_error_summary(&mut self, config: &Data) -> Result<(), ExitCode> {
+        let now = Instant::now();
+        let counts = error_counts(&self.stats);
+        while self.error_snapshots.get(1).is_some_and(|&(at, _)| now.duration_since(at) >= ERROR_SUMMARY_WINDOW) {
+            self.error_snapshots.pop_front();
+        }
+        // Counters reset from the command topic can fall below the snapshot
+        let (read_failures, publish_failures, reconnects) = self.error_snapshots.front().map_or(counts, |&(_, oldest)| {
+            (counts.0.saturating_sub(oldest.0), counts.1.saturating_sub(oldest.1), counts.2.saturating_sub(oldest.2))
+        });
+        self.error_snapshots.push_back((now, counts));
+
+        let topic = get_error_summary_topic(config);
+        let msg = json!({
+            "window_seconds": ERROR_SUMMARY_WINDOW.as_secs(),
+            "read_failures": read_failures,
+            "publish_failures": publish_failures,
+            "reconnects": reconnects,
+        }).to_string();
+        debug!("Publishing error summary to topic [{}]", topic);
+        let qos = qos(config, Category::Diagnostics);
+        trace_publish(&topic, qos, true, &msg);
+        match self.client.publish(topic, qos, true, msg) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Failed to publish error summary due to error: {}", e);
+                Err(ExitCode::FAILURE)
+            }
+        }
+    }
+
+    /// Marks the measurement entities unavailable while their measurement fails, and available again
+    /// once it is read.
+    fn update_entity_availability(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        for sensor_component in get_enabled_components(config).into_iter().filter(SensorComponent::is_measurement) {
+            let changed = match sensor_component.read_in(reading) {
+                Some(true) => self.unavailable_entities.remove(sensor_component.id()),
+                Some(false) => self.unavailable_entities.insert(sensor_component.id()),
+                None => false,
+            };
+            if !changed {
+                continue;
+            }
+            let online = !self.unavailable_entities.contains(sensor_component.id());
+            if online {
+                info!("The {} entity is readable again", sensor_component.id());
+            } else {
+                warn!("Marking the {} entity unavailable until it can be read again", sensor_component.id());
+            }
+            if self.connected.load(Ordering::Relaxed) {
+                publish_entity_availability(&self.client, config, sensor_component, online)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fires the temperature triggers `temperature` crossed, and rearms those it moved back past
+    /// by their hysteresis. The first temperature only tells which side it starts on, and a
+    /// crossing while disconnected is lost.
+    fn fire_temperature_triggers(&mut self, config: &Data, temperature: f32) -> Result<(), ExitCode> {
+        for trigger in &config.mqtt.temperature_triggers {
+            let (crossed, rearmed) = match (trigger.above, trigger.below) {
+                (Some(above), _) => (temperature > above, temperature <= above - trigger.hysteresis),
+                (None, Some(below)) => (temperature < below, temperature >= below + trigger.hysteresis),
+                (None, None) => continue,
+            };
+            match self.trigger_armed.get(&trigger.name) {
+                None => {
+                    self.trigger_armed.insert(trigger.name.clone(), !crossed);
+                },
+                Some(true) if crossed => {
+                    self.trigger_armed.insert(trigger.name.clone(), false);
+                    info!("Temperature {} crossed the threshold of trigger {}", temperature, trigger.name);
+                    if self.connected.load(Ordering::Relaxed) {
+                        publish_trigger(&self.client, config, trigger, temperature)?;
+                    }
+                },
+                Some(false) if rearmed => {
+                    self.trigger_armed.insert(trigger.name.clone(), true);
+                    debug!("Trigger {} rearmed at {}", trigger.name, temperature);
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Retain flag of the next state publish, which differs for the first one with
+    /// `retain_first_state`.
+    fn retain_state(&mut self, config: &Data) -> bool {
+        let first = !std::mem::replace(&mut self.state_published, true);
+        match config.mqtt.retain_first_state {
+            Some(retain) if first => retain,
+            _ => config.mqtt.retain_state,
+        }
+    }
+
+    /// The temperature and pressure changes per minute, for [`get_state_values`].
+    fn rates(&self) -> (Option<f32>, Option<f32>) {
+        (self.temperature_rate.per_minute(), self.pressure_rate.per_minute())
+    }
+
+    /// Publishes the reading from before the restart, marked with `last_known` in JSON mode. Having
+    /// data to show, the entities are announced online as for a live reading.
+    fn publish_last_known(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        info!("Publishing the last known reading from before the restart");
+        self.sensor_unavailable = false;
+        let mut values = get_state_values(config, reading, None, false, (None, None), self.started.elapsed(), &self.stats);
+        values.retain(|(sensor_component, _)| !sensor_component.is_scheduled_diagnostic(config));
+        self.wait_for_discovery();
+        let retain = self.retain_state(config);
+        self.publish_states(config, get_state_messages(config, values, true), retain)
+    }
+
+    /// Publishes the latest reading again, past the deadbands and the rate limit. Nothing goes out
+    /// before the first reading, or while the sensor is unavailable.
+    fn republish_latest(&mut self, config: &Data) -> Result<(), ExitCode> {
+        let Some(reading) = self.last_reading.filter(|_| !self.sensor_unavailable) else {
+            return Ok(());
+        };
+        debug!("Republishing the latest reading after connecting");
+        self.published_temperature = reading.temperature.or(self.published_temperature);
+        self.published_pressure = reading.pressure.or(self.published_pressure);
+        let mut values = get_state_values(config, &reading, self.last_temperature, self.pressure_trend.dropping, self.rates(), self.started.elapsed(), &self.stats);
+        values.retain(|(sensor_component, _)| !sensor_component.is_scheduled_diagnostic(config));
+        self.wait_for_discovery();
+        let retain = self.retain_state(config);
+        self.publish_states(config, get_state_messages(config, values, false), retain)
+    }
+
+    /// Blocks until the delay after the last discovery has passed. Only the first state after it
+    /// waits, and for at most `post_discovery_delay_ms`.
+    fn wait_for_discovery(&mut self) {
+        if let Some(remaining) = self.state_not_before.take().map(|at| at.saturating_duration_since(Instant::now())) {
+            if !remaining.is_zero() {
+                debug!("Waiting {}ms for HA to process discovery before publishing state", remaining.as_millis());
+                thread::sleep(remaining);
+            }
+        }
+    }
+
+    fn flush_offline_queue(&mut self, config: &Data) -> Result<(), ExitCode> {
+        if !self.offline_queue.is_empty() {
+            info!("Flushing {} state messages queued while disconnected", self.offline_queue.len());
+            self.wait_for_discovery();
+        }
+        while let Some(msgs) = self.offline_queue.pop_front() {
+            let retain = self.retain_state(config);
+            self.publish_states(config, msgs, retain)?;
+        }
+        Ok(())
+    }
+
+    /// Publishes through the pending buffer with `max_pending_states`, so a full queue costs the
+    /// oldest messages rather than blocking the read loop.
+    fn publish_states(&mut self, config: &Data, msgs: Vec<StateMessage>, retain: bool) -> Result<(), ExitCode> {
+        if config.mqtt.max_pending_states == 0 {
+            return publish_sensor_data(&self.client, config, &self.stats, msgs, retain);
+        }
+        for msg in msgs {
+            if self.pending_states.len() >= config.mqtt.max_pending_states {
+                self.pending_states.pop_front();
+                let dropped = self.stats.dropped_pending_states.fetch_add(1, Ordering::Relaxed) + 1;
+                self.stats.publish_failures.fetch_add(1, Ordering::Relaxed);
+                warn!("Dropping the oldest pending state message, the MQTT queue is full ({} dropped so far)", dropped);
+            }
+            self.pending_states.push_back((msg, retain));
+        }
+        self.publish_pending_states(config);
+        Ok(())
+    }
+
+    /// Whether a measurement of `reading` moved at least its deadband away from the value last
+    /// published. Always true without deadbands.
+    fn outside_deadbands(&self, config: &Data, reading: &Reading) -> bool {
+        let mqtt = &config.mqtt;
+        if mqtt.temperature_deadband.is_none() && mqtt.pressure_deadband.is_none() && mqtt.pressure_deadband_percent.is_none() {
+            return true;
+        }
+        let moved = |value: Option<f32>, published: Option<f32>, deadband: Option<f32>| match (value, published) {
+            (Some(value), Some(published)) => match deadband {
+                Some(deadband) => (value - published).abs() >= deadband,
+                None => value != published,
+            },
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        let pressure_deadband = mqtt.pressure_deadband.or_else(|| {
+            Some(self.published_pressure?.abs() * mqtt.pressure_deadband_percent? / 100.0)
+        });
+        moved(reading.temperature, self.published_temperature, mqtt.temperature_deadband)
+            || moved(reading.pressure, self.published_pressure, pressure_deadband)
+    }
+
+    /// Hands pending state messages to the MQTT queue until it is full, retrying the rest shortly.
+    fn publish_pending_states(&mut self, config: &Data) {
+        let qos = qos(config, Category::State);
+        while let Some(((topic, msg), retain)) = self.pending_states.front() {
+            debug!("Publishing sensor data to topic [{}]", topic);
+            trace_publish(topic, qos, *retain, &String::from_utf8_lossy(msg));
+            if let Err(e) = self.client.try_publish(topic, qos, *retain, msg.as_slice()) {
+                debug!("MQTT queue full, holding {} state messages: {}", self.pending_states.len(), e);
+                break;
+            }
+            self.pending_states.pop_front();
+        }
+        self.stats.pending_states.store(self.pending_states.len() as u64, Ordering::Relaxed);
+        self.pending_retry_due = (!self.pending_states.is_empty()).then(|| Instant::now() + PENDING_RETRY_DELAY);
+    }
+}
+
+impl Sink for Publisher {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn publish_discovery(&mut self, config: &Data) {
+        if !config.mqtt.discovery() {
+            return;
+        }
+        for discovery in get_discoveries(config) {
+            match discovery {
+                Discovery::Component(sensor_component) => publish_sensor_discovery_message(&self.client, config, &self.stats, sensor_component),
+                Discovery::PollInterval => publish_poll_interval_discovery(&self.client, config),
+                Discovery::Trigger(trigger) => publish_trigger_discovery(&self.client, config, trigger),
+            }
+        }
+        if config.mqtt.post_discovery_delay_ms > 0 {
+            self.state_not_before = Some(Instant::now() + Duration::from_millis(config.mqtt.post_discovery_delay_ms));
+        }
+
+        let interval = config.mqtt.discovery_republish_interval_seconds;
+        if interval > 0 {
+            self.discovery_due = Some(Instant::now() + Duration::from_secs(interval));
+        }
+    }
+
+    fn publish_state(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        let first_reading = self.last_reading.is_none();
+        self.last_temperature = reading.temperature.or(self.last_temperature);
+        self.last_pressure = reading.pressure.or(self.last_pressure);
+        self.last_reading = Some(Reading {
+            temperature: self.last_temperature,
+            pressure: self.last_pressure,
+            temperature_drift: reading.temperature_drift.or(self.last_reading.and_then(|last| last.temperature_drift)),
+            temperature_failed: false,
+            pressure_failed: false,
+            ..*reading
+        });
+
+        if config.mqtt.stats_interval_seconds > 0 {
+            if let Some(temperature) = reading.temperature {
+                self.temperature_stats.add(temperature);
+            }
+            if let Some(pressure) = reading.pressure {
+                self.pressure_stats.add(pressure);
+            }
+        }
+        if config.mqtt.daily_summary {
+            self.add_to_daily_summary(config, reading)?;
+        }
+
+        if let (Some(threshold), Some(pressure)) = (config.sensor.pressure_drop_threshold, reading.pressure) {
+            self.pressure_trend.update(config, threshold, pressure);
+        }
+        if config.sensor.rate_of_change {
+            if let Some(temperature) = reading.temperature {
+                self.temperature_rate.update(config, temperature);
+            }
+            if let Some(pressure) = reading.pressure {
+                self.pressure_rate.update(config, pressure);
+            }
+        }
+        if let Some(temperature) = reading.temperature {
+            self.fire_temperature_triggers(config, temperature)?;
+        }
+
+        if self.sensor_unavailable && (reading.temperature.is_some() || reading.pressure.is_some()) {
+            self.sensor_unavailable = false;
+            if first_reading {
+                info!("First reading taken, announcing availability");
+            } else {
+                info!("Sensor readable again, announcing availability");
+            }
+            if self.connected.load(Ordering::Relaxed) {
+                publish_availability(&self.client, config, true)?;
+            }
+        }
+        if config.mqtt.availability && config.mqtt.per_entity_availability {
+            self.update_entity_availability(config, reading)?;
+        }
+
+        if !self.outside_deadbands(config, reading) {
+            debug!("Reading within the deadbands of the last published one, skipping it");
+            return Ok(());
+        }
+        if let Some(rate_limiter) = &mut self.rate_limiter {
+            if !rate_limiter.try_acquire() {
+                let dropped = self.stats.rate_limited_publishes.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("Publish rate limit reached, dropping state message ({} dropped so far)", dropped);
+                return Ok(());
+            }
+        }
+        self.published_temperature = reading.temperature.or(self.published_temperature);
+        self.published_pressure = reading.pressure.or(self.published_pressure);
+
+        // Split topics only carry fresh measurements, but a JSON payload missing a key would break the
+        // value templates of the other entities
+        let reading = match (config.mqtt.state_format, self.last_reading) {
+            (StateFormat::Json, Some(last_reading)) => last_reading,
+            _ => *reading,
+        };
+
+        let mut values = get_state_values(config, &reading, self.last_temperature, self.pressure_trend.dropping, self.rates(), self.started.elapsed(), &self.stats);
+        values.retain(|(sensor_component, _)| !sensor_component.is_scheduled_diagnostic(config));
+        if self.connected.load(Ordering::Relaxed) {
+            self.wait_for_discovery();
+            let retain = self.retain_state(config);
+            let msgs = get_state_messages(config, values, false);
+            self.last_state_messages.clone_from(&msgs);
+            return self.publish_states(config, msgs, retain);
+        }
+
+        let disconnected_since = *self.disconnected_since.get_or_insert_with(Instant::now);
+        if let Some(rest) = &config.homeassistant_rest {
+            self.rest_fallback.post_states(rest, disconnected_since.elapsed(), get_rest_states(config, rest, &values));
+        }
+
+        let msgs = get_state_messages(config, values, false);
+        self.last_state_messages.clone_from(&msgs);
+
+        if config.mqtt.offline_queue_size == 0 {
+            self.stats.publish_failures.fetch_add(1, Ordering::Relaxed);
+            debug!("Not connected to MQTT broker, dropping state message");
+            return Ok(());
+        }
+        if self.offline_queue.len() >= config.mqtt.offline_queue_size {
+            self.offline_queue.pop_front();
+            self.stats.publish_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.offline_queue.push_back(msgs);
+        debug!("Not connected to MQTT broker, queued state message ({} pending)", self.offline_queue.len());
+        Ok(())
+    }
+
+    fn handle_read_failure(&mut self, config: &Data) -> Result<(), ExitCode> {
+        match config.mqtt.on_read_failure {
+            OnReadFailure::Hold => Ok(()),
+            OnReadFailure::Unavailable => self.handle_sensor_lost(config),
+            // Queuing them while disconnected would only repeat what is already queued
+            OnReadFailure::RepublishLast => {
+                if self.last_state_messages.is_empty() || !self.connected.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                debug!("Republishing the last good reading");
+                let retain = self.retain_state(config);
+                self.publish_states(config, self.last_state_messages.clone(), retain)
+            },
+        }
+    }
+
+    fn handle_sensor_lost(&mut self, config: &Data) -> Result<(), ExitCode> {
+        if !config.mqtt.availability || self.sensor_unavailable {
+            return Ok(());
+        }
+        self.sensor_unavailable = true;
+        warn!("Marking the entities unavailable until the sensor can be read again");
+        if self.connected.load(Ordering::Relaxed) {
+            publish_availability(&self.client, config, false)?;
+        }
+        Ok(())
+    }
+
+    fn handle_signal(&mut self, config: &Data, signal: &LoopSignal) -> Result<(), ExitCode> {
+        match signal {
+            LoopSignal::Connected => self.handle_connected(config),
+            LoopSignal::ClientChanged(client) => {
+                self.set_client(client.clone());
+                Ok(())
+            },
+            LoopSignal::SetPollInterval(poll_interval) => {
+                self.poll_interval = *poll_interval;
+                if self.connected.load(Ordering::Relaxed) {
+                    publish_poll_interval(&self.client, config, self.poll_interval)?;
+                }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        [self.online_due, self.discovery_due, self.error_summary_due, self.diagnostics_due, self.stats_due, self.daily_due, self.pending_retry_due, self.publish_gap_due()]
+            .into_iter().flatten().min()
+    }
+
+    fn publish_due(&mut self, config: &Data) -> Result<(), ExitCode> {
+        self.check_publish_gap(config);
+
+        if self.pending_retry_due.is_some_and(|due| due <= Instant::now()) {
+            self.publish_pending_states(config);
+        }
+
+        if self.online_due.is_some_and(|due| due <= Instant::now()) {
+            self.online_due = None;
+            // A reconnect that didn't hold waits for the next one to announce itself, and an
+            // unreadable sensor, or one not read yet, keeps the entities unavailable
+            if self.connected.load(Ordering::Relaxed) && !self.sensor_unavailable {
+                publish_availability(&self.client, config, true)?;
+            }
+        }
+
+        if self.discovery_due.is_some_and(|due| due <= Instant::now()) {
+            self.discovery_due = None;
+            // Reconnecting republishes discovery anyway
+            if self.connected.load(Ordering::Relaxed) {
+                debug!("Republishing discovery messages on schedule");
+                self.publish_discovery(config);
+            }
+        }
+
+        if self.diagnostics_due.is_some_and(|due| due <= Instant::now()) {
+            self.diagnostics_due = Some(Instant::now() + Duration::from_secs(config.mqtt.diagnostics_interval_seconds.unwrap_or_default()));
+            // Read latency comes from the last reading, so there is nothing to report before one
+            if let (true, Some(last_reading)) = (self.connected.load(Ordering::Relaxed), self.last_reading) {
+                let mut values = get_state_values(config, &last_reading, self.last_temperature, self.pressure_trend.dropping, self.rates(), self.started.elapsed(), &self.stats);
+                values.retain(|(sensor_component, _)| sensor_component.is_scheduled_diagnostic(config));
+                if !values.is_empty() {
+                    let retain = self.retain_state(config);
+                    self.publish_states(config, get_state_messages(config, values, false), retain)?;
+                }
+            }
+        }
+
+        if let Some(due) = self.stats_due.filter(|&due| due <= Instant::now()) {
+            self.stats_due = Some(due + Duration::from_secs(config.mqtt.stats_interval_seconds));
+            // While disconnected the readings carry over into the next interval
+            if self.connected.load(Ordering::Relaxed) {
+                self.publish_stats(config)?;
+            }
+        }
+
+        if self.daily_due.is_some_and(|due| due <= Instant::now()) {
+            self.daily_due = Some(Instant::now() + until_midnight(config));
+            // Only once the date actually changed, in case the timer ran early
+            if self.daily_date.is_some_and(|date| date != now(config).date()) {
+                self.publish_daily_summary(config)?;
+            }
+        }
+
+        if let Some(due) = self.error_summary_due.filter(|&due| due <= Instant::now()) {
+            self.error_summary_due = Some(due + Duration::from_secs(config.mqtt.error_summary_interval_seconds));
+            // Skipped while disconnected, the next one still covers the whole hour
+            if self.connected.load(Ordering::Relaxed) {
+                self.publish_error_summary(config)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Command toggling a sink at runtime, e.g. `{"sink": "mqtt", "enabled": false}`.
+#[derive(Deserialize)]
+struct SinkCommand {
+    sink: String,
+    enabled: bool,
+}
+
+/// Flags set from signal handlers to interrupt [`poll_for_events`].
+#[derive(Default)]
+pub struct Interrupts {
+    pub reload: Arc<AtomicBool>,
+    pub shutdown: Arc<AtomicBool>,
+}
+
+/// Why [`poll_for_events`] returned.
+pub enum PollExit {
+    Reload,
+    Shutdown,
+    /// The connection failed in a way reconnecting can't fix.
+    Fatal,
+    /// The event loop can't carry on, and needs a new client and connection to the same broker.
+    /// `connected` is cleared first, so publishes are held back until the new one is up.
+    Rebuild,
+}
+
+/// Drives the connection until one of the `interrupts` is set. `first_connect` is cleared once
+/// connected, so the retained discovery check isn't repeated after a reload.
+#[allow(clippy::too_many_arguments)]
+pub fn poll_for_events(
+    connection: &mut Connection,
+    client: &Client,
+    config: &Data,
+    broker: &MQTTBroker,
+    signals: &Sender<LoopSignal>,
+    connected: &AtomicBool,
+    stats: &Stats,
+    interrupts: &Interrupts,
+    first_connect: &mut bool,
+) -> PollExit {
+    // Discovery payloads still awaiting comparison with what the broker retained, by topic. Only
+    // checked on the first connection, as later ones see our own retained messages.
+    let mut pending_discovery_checks: Option<HashMap<String, String>> = None;
+    let mut failures: u32 = 0;
+    // Steps of the reconnect delay, which unlike `failures` survive connections that didn't hold
+    // for `backoff_reset_seconds`
+    let mut backoff_steps: u32 = 0;
+    // A broker drops the older of two connections sharing a client id, so two instances keep
+    // kicking each other off moments after connecting
+    let mut connected_at: Option<Instant> = None;
+    let mut short_sessions: u32 = 0;
+
+    debug!("Polling for events");
+    for notification in connection.iter() {
+        if let Ok(event) = &notification {
+            trace!(target: TRACE_TARGET, "{:?}", event);
+        }
+        // Checked between events, which arrive at least once per keep-alive
+        if interrupts.shutdown.load(Ordering::Relaxed) {
+            return PollExit::Shutdown;
+        }
+        if interrupts.reload.swap(false, Ordering::Relaxed) {
+            return PollExit::Reload;
+        }
+        match notification {
+            Ok(Event::Incoming(Incoming::Connect(c))) => debug!("Connected to MQTT broker {}", c.client_id),
+            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                connected.store(true, Ordering::Relaxed);
+                connected_at = Some(Instant::now());
+                failures = 0;
+
+                if *first_connect {
+                    *first_connect = false;
+                    if config.mqtt.discovery() {
+                        pending_discovery_checks = subscribe_to_discovery_topics(client, config);
+                    }
+                }
+
+                // Subscriptions don't survive a clean session, so they are renewed on every connect
+                if let Some(birth_topic) = &config.mqtt.birth_topic {
+                    debug!("Subscribing to birth topic [{}]", birth_topic);
+                    if let Err(e) = client.try_subscribe(birth_topic, qos(config, Category::Command)) {
+                        error!("Failed to subscribe to birth topic due to error: {}", e);
+                    }
+                }
+                if let Some(command_topic) = &config.mqtt.command_topic {
+                    // The broker handles both in order, so the subscription no longer sees the retained command
+                    if config.mqtt.clear_retained_command {
+                        debug!("Clearing retained message on command topic [{}]", command_topic);
+                        trace_publish(command_topic, qos(config, Category::Command), true, "");
+                        if let Err(e) = client.try_publish(command_topic, qos(config, Category::Command), true, "") {
+                            error!("Failed to clear retained command due to error: {}", e);
+                        }
+                    }
+                    debug!("Subscribing to command topic [{}]", command_topic);
+                    if let Err(e) = client.try_subscribe(command_topic, qos(config, Category::Command)) {
+                        error!("Failed to subscribe to command topic due to error: {}", e);
+                    }
+                }
+
+                if config.mqtt.poll_interval_control {
+                    let topic = get_poll_interval_command_topic(config);
+                    debug!("Subscribing to poll interval command topic [{}]", topic);
+                    if let Err(e) = client.try_subscribe(topic, qos(config, Category::Command)) {
+                        error!("Failed to subscribe to poll interval command topic due to error: {}", e);
+                    }
+                }
+
+                // Signalled last so the subscriptions reach the broker ahead of our discovery messages
+                let _ = signals.send(LoopSignal::Connected);
+            },
+            Ok(Event::Incoming(Incoming::Publish(p))) if pending_discovery_checks.as_ref().is_some_and(|c| c.contains_key(&p.topic)) => {
+                if let Some(expected) = pending_discovery_checks.as_mut().and_then(|c| c.remove(&p.topic)) {
+                    compare_retained_discovery(&p.topic, &p.payload, &expected);
+                    if let Err(e) = client.try_unsubscribe(&p.topic) {
+                        debug!("Failed to unsubscribe from discovery topic due to error: {}", e);
+                    }
+                }
+            },
+            Ok(Event::Incoming(Incoming::Publish(p))) if config.mqtt.poll_interval_control && p.topic == get_poll_interval_command_topic(config) => {
+                let payload = String::from_utf8_lossy(&p.payload);
+                match payload.trim().parse::<f64>() {
+                    Ok(poll_interval) if poll_interval.is_finite() => {
+                        debug!("Received poll interval of {}s", poll_interval);
+                        let _ = signals.send(LoopSignal::SetPollInterval(poll_interval));
+                    },
+                    _ => warn!("Ignoring invalid poll interval [{}] on [{}]", payload, p.topic),
+                }
+            },
+            Ok(Event::Incoming(Incoming::Publish(p))) if config.mqtt.command_topic.as_deref() == Some(p.topic.as_str()) => {
+                match String::from_utf8_lossy(&p.payload).trim() {
+                    "read" => {
+                        debug!("Received read command");
+                        let _ = signals.send(LoopSignal::ReadNow);
+                    },
+                    "reset" => {
+                        debug!("Received reset command");
+                        let _ = signals.send(LoopSignal::ResetStats);
+                    },
+                    // Clearing a retained command
+                    "" => (),
+                    command if command.starts_with('{') => match serde_json::from_str::<SinkCommand>(command) {
+                        Ok(SinkCommand { sink, enabled }) => {
+                            debug!("Received command to {} sink [{}]", if enabled { "enable" } else { "disable" }, sink);
+                            let _ = signals.send(LoopSignal::SetSinkEnabled(sink, enabled));
+                        },
+                        Err(e) => warn!("Ignoring malformed command [{}] on [{}]: {}", command, p.topic, e),
+                    },
+                    command => warn!("Ignoring unknown command [{}] on [{}]", command, p.topic),
+                }
+            },
+            Ok(Event::Incoming(Incoming::Publish(p))) if config.mqtt.birth_topic.as_deref() == Some(p.topic.as_str()) => {
+                if p.payload == config.mqtt.birth_payload.as_bytes() {
+                    info!("Received birth message on [{}]", p.topic);
+                    let _ = signals.send(LoopSignal::RepublishDiscovery);
+                }
+            },
+            Ok(e) => {
+                if let Event::Outgoing(Outgoing::Publish(_)) = e {
+                    stats.publishes_sent.fetch_add(1, Ordering::Relaxed);
+                }
+                debug!("Got event: {:?}", e);
+            },
+            Err(e) => {
+                connected.store(false, Ordering::Relaxed);
+                let session = connected_at.take().map(|at| at.elapsed());
+                if session.is_some_and(|held| held >= Duration::from_secs(broker.backoff_reset_seconds)) {
+                    backoff_steps = 0;
+                }
+                match session {
+                    Some(held) if held < SHORT_SESSION => {
+                        short_sessions += 1;
+                        if short_sessions == SHORT_SESSION_LIMIT {
+                            warn!("Possible duplicate client_id [{}]: another instance may be connected to the broker. \
+                                Give each instance its own name in [mqtt]", config.mqtt.name);
+                        }
+                    },
+                    Some(_) => short_sessions = 0,
+                    None => (),
+                }
+                if let Some(hint) = fatal_error_hint(&e) {
+                    error!("Cannot connect to MQTT broker {}:{}: {}. {}", broker.host, broker.port, e, hint);
+                    return PollExit::Fatal;
+                }
+                let broken_state = is_broken_state(&e);
+                match e {
+                    ConnectionError::NetworkTimeout => error!("Timed out after {}s connecting to MQTT broker {}:{}. Is the broker reachable?",
+                        broker.connect_timeout_seconds, broker.host, broker.port),
+                    e => error!("Got an error when polling for events: {}", e),
+                }
+
+                failures += 1;
+                if broker.max_reconnect_attempts.is_some_and(|max| failures > max) {
+                    error!("Giving up after {} failed attempts to reach the MQTT broker", failures);
+                    return PollExit::Fatal;
+                }
+                backoff_steps += 1;
+                let backoff = (Duration::from_secs(1) * 2u32.saturating_pow(backoff_steps - 1)).min(MAX_NETWORK_BACKOFF);
+                debug!("Reconnecting in {:?}", backoff);
+                if let Some(exit) = sleep_unless_interrupted(backoff, interrupts) {
+                    return exit;
+                }
+                if broken_state {
+                    warn!("Rebuilding the MQTT connection, as reconnecting keeps the session state that went wrong");
+                    return PollExit::Rebuild;
+                }
+            },
+        }
+    }
+
+    // The iterator only ends once the event loop takes no more requests, and polling it again
+    // would return at once, so the connection is built anew
+    connected.store(false, Ordering::Relaxed);
+    warn!("The MQTT event loop stopped, rebuilding the connection");
+    sleep_unless_interrupted(REBUILD_DELAY, interrupts).unwrap_or(PollExit::Rebuild)
+}
+
+/// How to fix an error that reconnecting won't, or `None` when the error may be transient.
+fn fatal_error_hint(error: &ConnectionError) -> Option<&'static str> {
+    match error {
+        ConnectionError::ConnectionRefused(ConnectReturnCode::BadUserNamePassword) => Some("Check username and password in [mqtt_broker]"),
+        ConnectionError::ConnectionRefused(ConnectReturnCode::NotAuthorized) => Some("The broker doesn't allow this user to connect"),
+        ConnectionError::ConnectionRefused(ConnectReturnCode::BadClientId) => Some("The broker rejected the client id, check name in [mqtt]"),
+        ConnectionError::ConnectionRefused(ConnectReturnCode::RefusedProtocolVersion) => Some("The broker doesn't support MQTT 3.1.1"),
+        // A Pi without a real-time clock may not have set its clock yet, and the network may drop
+        // during the handshake
+        ConnectionError::Tls(TlsError::TLS(rustls::Error::InvalidCertificate(rustls::CertificateError::NotValidYet)) | TlsError::Io(_)) => None,
+        ConnectionError::Tls(_) => Some("The TLS handshake failed, check host and the files in [mqtt_broker.tls]"),
+        _ => None,
+    }
+}
+
+/// Whether the error comes from session state that went out of step with the broker, which the
+/// event loop carries over into its next connection.
+fn is_broken_state(error: &ConnectionError) -> bool {
+    matches!(error, ConnectionError::MqttState(
+        StateError::InvalidState | StateError::Unsolicited(_) | StateError::WrongPacket | StateError::CollisionTimeout
+    ))
+}
+
+/// Sleeps for `duration`, returning early if one of the `interrupts` is set.
+fn sleep_unless_interrupted(duration: Duration, interrupts: &Interrupts) -> Option<PollExit> {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if interrupts.shutdown.load(Ordering::Relaxed) {
+            return Some(PollExit::Shutdown);
+        }
+        if interrupts.reload.swap(false, Ordering::Relaxed) {
+            return Some(PollExit::Reload);
+        }
+        thread::sleep(deadline.saturating_duration_since(Instant::now()).min(Duration::from_millis(100)));
+    }
+    None
+}
+
+/// Subscribes to our discovery topics so the broker hands back whatever it retained from a previous
+/// run. Returns the payloads to compare against, by topic.
+fn subscribe_to_discovery_topics(client: &Client, config: &Data) -> Option<HashMap<String, String>> {
+    let expected: HashMap<String, String> = get_discoveries(config).iter()
+        .map(|discovery| (discovery.topic(config), discovery.message(config)))
+        .collect();
+
+    let filters = expected.keys().map(|topic| SubscribeFilter::new(topic.clone(), QoS::AtMostOnce));
+    match client.try_subscribe_many(filters) {
+        Ok(_) => Some(expected),
+        Err(e) => {
+            debug!("Failed to subscribe to discovery topics, skipping retained discovery check: {}", e);
+            None
+        }
+    }
+}
+
+/// Logs a notice for every field that differs between the retained discovery payload and ours.
+fn compare_retained_discovery(topic: &str, retained: &[u8], expected: &str) {
+    if retained.is_empty() {
+        return;
+    }
+    match discovery_differences(retained, expected) {
+        Some(differences) => for difference in differences {
+            info!("Discovery on [{}] changed: {}", topic, difference);
+        },
+        None => info!("Retained discovery message on [{}] is not a JSON object and will be replaced", topic),
+    }
+}
+
+/// Describes every field that differs between two discovery payloads, or `None` if the retained
+/// one isn't a JSON object.
+fn discovery_differences(retained: &[u8], expected: &str) -> Option<Vec<String>> {
+    let (Ok(Value::Object(retained)), Ok(Value::Object(expected))) =
+        (serde_json::from_slice::<Value>(retained), serde_json::from_str::<Value>(expected)) else {
+        return None;
+    };
+
+    let mut differences = Vec::new();
+    for (key, new) in &expected {
+        match retained.get(key) {
+            Some(old) if old == new => (),
+            Some(old) => differences.push(format!("{} changed from {} to {}", key, old, new)),
+            None => differences.push(format!("{} added as {}", key, new)),
+        }
+    }
+    for key in retained.keys().filter(|key| !expected.contains_key(*key)) {
+        differences.push(format!("{} removed", key));
+    }
+    Some(differences)
+}
+
+/// Prints the discovery messages retained on the broker for this config, and how they differ from
+/// what would be published now. Connects under its own client id and without a last will, so a
+/// running instance isn't disturbed.
+pub fn inspect_discovery(config: &Data) -> ExitCode {
+    let broker = &config.mqtt_broker;
+    let (client, mut connection) = get_tool_client(config, "inspect");
+
+    let mut expected: HashMap<String, String> = get_discoveries(config).iter()
+        .map(|discovery| (discovery.topic(config), discovery.message(config)))
+        .collect();
+    if expected.is_empty() {
+        println!("The config publishes no discovery messages");
+        return ExitCode::SUCCESS;
+    }
+    let mut topics: Vec<String> = expected.keys().cloned().collect();
+    topics.sort();
+    let mut retained: HashMap<String, Vec<u8>> = HashMap::new();
+
+    // Retained messages follow the SUBACK straight away, so a short wait after it is enough
+    let mut deadline = Instant::now() + Duration::from_secs(broker.connect_timeout_seconds + 5);
+    let connected = AtomicBool::new(false);
+    while retained.len() < expected.len() {
+        match connection.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(Event::Incoming(Incoming::ConnAck(_)))) => {
+                connected.store(true, Ordering::Relaxed);
+                let filters = topics.iter().map(|topic| SubscribeFilter::new(topic.clone(), QoS::AtMostOnce));
+                if let Err(e) = client.try_subscribe_many(filters) {
+                    error!("Failed to subscribe to discovery topics due to error: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            },
+            Ok(Ok(Event::Incoming(Incoming::SubAck(_)))) => deadline = Instant::now() + Duration::from_secs(1),
+            Ok(Ok(Event::Incoming(Incoming::Publish(p)))) if expected.contains_key(&p.topic) => {
+                retained.insert(p.topic.clone(), p.payload.to_vec());
+            },
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => {
+                error!("Cannot connect to MQTT broker {}:{}: {}", broker.host, broker.port, e);
+                return ExitCode::FAILURE;
+            },
+            Err(_) if connected.load(Ordering::Relaxed) => break,
+            Err(_) => {
+                error!("Timed out connecting to MQTT broker {}:{}", broker.host, broker.port);
+                return ExitCode::FAILURE;
+            },
+        }
+    }
+    disconnect(&client, &mut connection, &connected);
+
+    for topic in topics {
+        let ours = expected.remove(&topic).unwrap_or_default();
+        match retained.get(&topic).filter(|payload| !payload.is_empty()) {
+            None => println!("{}: nothing retained", topic),
+            Some(payload) => {
+                match discovery_differences(payload, &ours) {
+                    Some(differences) if differences.is_empty() => println!("{}: matches the current config", topic),
+                    Some(differences) => {
+                        println!("{}: differs from the current config", topic);
+                        for difference in differences {
+                            println!("  {}", difference);
+                        }
+                    },
+                    None => println!("{}: not a JSON object", topic),
+                }
+                let pretty = serde_json::from_slice::<Value>(payload).ok()
+                    .and_then(|value| serde_json::to_string_pretty(&value).ok())
+                    .unwrap_or_else(|| String::from_utf8_lossy(payload).into_owned());
+                println!("{}", pretty);
+            },
+        }
+        println!();
+    }
+    ExitCode::SUCCESS
+}
+
+/// Reads the files of the TLS config into `broker.tls_configuration`.
+pub fn load_tls(broker: &mut MQTTBroker) -> Result<(), String> {
+    let Some(tls) = &broker.tls else {
+        broker.tls_configuration = None;
+        return Ok(());
+    };
+    let read = |path: &Path| fs::read(path).map_err(|e| format!("could not read `{}`: {}", path.display(), e));
+    let client_auth = match (&tls.client_cert_file, &tls.client_key_file) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        (None, None) => None,
+        _ => return Err(String::from("client_cert_file and client_key_file have to be set together")),
+    };
+    broker.tls_configuration = Some(TlsConfiguration::Simple {
+        ca: read(&tls.ca_file)?,
+        alpn: (!tls.alpn.is_empty()).then(|| tls.alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect()),
+        client_auth,
+    });
+    Ok(())
+}
+
+/// Socket options of the broker connection. rumqttc 0.24 offers no TCP keepalive; idle connections
+/// are kept alive by the MQTT keep-alive pings instead.
+fn get_network_options(broker: &MQTTBroker) -> NetworkOptions {
+    let mut network_options = NetworkOptions::new();
+    network_options.set_connection_timeout(broker.connect_timeout_seconds);
+    if let Some(size) = broker.tcp_send_buffer_size {
+        network_options.set_tcp_send_buffer_size(size);
+    }
+    if let Some(size) = broker.tcp_recv_buffer_size {
+        network_options.set_tcp_recv_buffer_size(size);
+    }
+    if let Some(device) = &broker.bind_device {
+        network_options.set_bind_device(device);
+    }
+    network_options
+}
+
+/// Options for reaching the broker over the configured transport, with the credentials set.
+fn get_mqtt_options(broker: &MQTTBroker, client_id: String) -> MqttOptions {
+    // WebSocket transports take the endpoint's URL in place of the host, and the port from it
+    let websocket_url = |scheme: &str| {
+        let host = if broker.host.contains(':') { format!("[{}]", broker.host) } else { broker.host.clone() };
+        format!("{}://{}:{}{}", scheme, host, broker.port, broker.ws_path)
+    };
+    let (address, transport) = match (broker.transport(), &broker.tls_configuration) {
+        (BrokerTransport::Tls, Some(tls_configuration)) => (broker.host.clone(), Transport::tls_with_config(tls_configuration.clone())),
+        (BrokerTransport::Ws, _) => (websocket_url("ws"), Transport::ws()),
+        (BrokerTransport::Wss, Some(tls_configuration)) => (websocket_url("wss"), Transport::wss_with_config(tls_configuration.clone())),
+        // The TLS transports were checked to come with a TLS config
+        _ => (broker.host.clone(), Transport::tcp()),
+    };
+    let mut mqttoptions = MqttOptions::new(client_id, address, broker.port);
+    mqttoptions.set_transport(transport);
+    mqttoptions.set_credentials(&broker.username, broker.password.expose_secret());
+    mqttoptions
+}
+
+/// Client for a one-off command, under its own client id and without a last will so a running
+/// instance isn't disturbed.
+fn get_tool_client(config: &Data, suffix: &str) -> (Client, Connection) {
+    let broker = &config.mqtt_broker;
+    let mqttoptions = get_mqtt_options(broker, format!("{}-{}", config.mqtt.name, suffix));
+    let (client, mut connection) = Client::new(mqttoptions, 10);
+    connection.eventloop.set_network_options(get_network_options(broker));
+    (client, connection)
+}
+
+/// Publishes an empty retained message to each of the config's discovery topics, which makes HA
+/// remove the entities. Connects under its own client id and without a last will, so a running
+/// instance isn't disturbed.
+pub fn purge_discovery(config: &Data) -> ExitCode {
+    let topics = get_purge_topics(config);
+    if topics.is_empty() {
+        info!("This config publishes no discovery messages, so there are none to purge");
+        return ExitCode::SUCCESS;
+    }
+    let broker = &config.mqtt_broker;
+    let (client, mut connection) = get_tool_client(config, "purge");
+    let connected = AtomicBool::new(false);
+    let deadline = Instant::now() + Duration::from_secs(broker.connect_timeout_seconds + 5);
+    let qos = qos(config, Category::Discovery);
+    let mut remaining = topics.iter();
+    let mut cleared = 0;
+    let result = loop {
+        match connection.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(Event::Incoming(Incoming::ConnAck(_)))) => connected.store(true, Ordering::Relaxed),
+            Ok(Ok(Event::Outgoing(Outgoing::Publish(_)))) => cleared += 1,
+            Ok(Ok(_)) => continue,
+            Ok(Err(e)) => break Err(e.to_string()),
+            Err(_) => break Err(String::from("timed out")),
+        }
+        if cleared == topics.len() {
+            break Ok(());
+        }
+        // One at a time, as the queue of the client is short
+        if let Some(topic) = remaining.next() {
+            info!("Clearing retained discovery message on [{}]", topic);
+            trace_publish(topic, qos, true, "");
+            if let Err(e) = client.try_publish(topic, qos, true, "") {
+                break Err(e.to_string());
+            }
+        }
+    };
+    // Queued after the publishes, so it only goes out once they have
+    disconnect(&client, &mut connection, &connected);
+    match result {
+        Ok(()) => {
+            info!("Cleared {} discovery messages", cleared);
+            ExitCode::SUCCESS
+        },
+        Err(e) => {
+            error!("Failed to purge discovery on MQTT broker {}:{} after clearing {} of {} messages: {}", broker.host, broker.port, cleared, topics.len(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Connects to the broker and disconnects again once it accepts the credentials, publishing and
+/// subscribing to nothing, so it is safe against a broker in use.
+pub fn validate_broker(config: &Data) -> ExitCode {
+    let broker = &config.mqtt_broker;
+    let (client, mut connection) = get_tool_client(config, "validate");
+    let connected = AtomicBool::new(false);
+    let deadline = Instant::now() + Duration::from_secs(broker.connect_timeout_seconds + 5);
+    let result = loop {
+        match connection.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(Event::Incoming(Incoming::ConnAck(_)))) => {
+                connected.store(true, Ordering::Relaxed);
+                break Ok(());
+            },
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => break Err(match fatal_error_hint(&e) {
+                Some(hint) => format!("{}. {}", e, hint),
+                None => e.to_string(),
+            }),
+            Err(_) => break Err(String::from("timed out connecting")),
+        }
+    };
+    disconnect(&client, &mut connection, &connected);
+    match result {
+        Ok(()) => {
+            info!("Authenticated OK with MQTT broker {}:{} as {}", broker.host, broker.port, broker.username);
+            ExitCode::SUCCESS
+        },
+        Err(e) => {
+            error!("Cannot connect to MQTT broker {}:{}: {}", broker.host, broker.port, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Connects to the broker and publishes a test message to `{identifier}/self_test`, waiting for
+/// the broker to acknowledge it.
+pub fn self_test(config: &Data) -> Result<(), String> {
+    let (client, mut connection) = get_tool_client(config, "self-test");
+    let topic = format!("{}/self_test", config.mqtt.identifier);
+    let payload = json!({ "timestamp": now(config).format(&Rfc3339).unwrap_or_default() }).to_string();
+    let connected = AtomicBool::new(false);
+    let deadline = Instant::now() + Duration::from_secs(config.mqtt_broker.connect_timeout_seconds + 5);
+    let result = loop {
+        match connection.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(Event::Incoming(Incoming::ConnAck(_)))) => {
+                connected.store(true, Ordering::Relaxed);
+                if let Err(e) = client.try_publish(&topic, QoS::AtLeastOnce, false, payload.as_str()) {
+                    break Err(format!("could not publish to [{}]: {}", topic, e));
+                }
+            },
+            Ok(Ok(Event::Incoming(Incoming::PubAck(_)))) => break Ok(()),
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => break Err(e.to_string()),
+            Err(_) if connected.load(Ordering::Relaxed) => break Err(format!("no acknowledgement for the message to [{}]", topic)),
+            Err(_) => break Err(String::from("timed out connecting")),
+        }
+    };
+    disconnect(&client, &mut connection, &connected);
+    result
+}
+
+/// Retries resolving the broker host, backing off between attempts, until it resolves or
+/// `timeout` passes. DNS often isn't up yet right after boot.
+pub fn wait_for_network(broker: &MQTTBroker, timeout: Duration) -> Result<(), ExitCode> {
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match (broker.host.as_str(), broker.port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(_)) => {
+                debug!("Resolved MQTT broker {}", broker.host);
+                return Ok(());
+            },
+            Ok(None) => debug!("MQTT broker {} resolved to no addresses", broker.host),
+            Err(e) => debug!("Could not resolve MQTT broker {}: {}", broker.host, e),
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            error!("Gave up after {:?} waiting for MQTT broker {} to resolve", timeout, broker.host);
+            return Err(ExitCode::FAILURE);
+        }
+        let pause = backoff.min(deadline - now);
+        info!("Waiting for the network, retrying in {:?}", pause);
+        thread::sleep(pause);
+        backoff = (backoff * 2).min(MAX_NETWORK_BACKOFF);
+    }
+}
+
+pub fn get_mqtt_client(config: &Data, broker: &MQTTBroker) -> (Client, Connection) {
+    let client_id = match &broker.client_id_suffix {
+        Some(suffix) => format!("{}-{}", config.mqtt.name, suffix),
+        None => config.mqtt.name.clone(),
+    };
+    let mut mqttoptions = get_mqtt_options(broker, client_id);
+    // The broker declares the client gone after 1.5 times the keep-alive
+    let grace_keep_alive = (config.mqtt.availability_grace_seconds * 2).div_ceil(3);
+    mqttoptions.set_keep_alive(Duration::from_secs(grace_keep_alive.max(5)));
+    if config.mqtt.availability {
+        mqttoptions.set_last_will(LastWill::new(get_availability_topic(config), config.mqtt.payload_not_available.as_str(), qos(config, Category::Availability), true));
+    }
+
+    let (client, mut connection) = Client::new(mqttoptions, config.mqtt.mqtt_queue_capacity);
+
+    connection.eventloop.set_network_options(get_network_options(broker));
+
+    (client, connection)
+}
+
+/// Publishes `offline` and waits, for a bounded time, until the broker has acknowledged it and
+/// anything published before it, then disconnects. Announcing `offline` ourselves lets HA see the
+/// transition right away instead of after the keep-alive times out.
+pub fn shutdown(client: &Client, connection: &mut Connection, config: &Data, connected: &AtomicBool) {
+    if !connected.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if config.mqtt.availability {
+        let topic = get_availability_topic(config);
+        let payload = &config.mqtt.payload_not_available;
+        // Waiting for the acknowledgement needs one to be sent
+        let qos = match qos(config, Category::Availability) {
+            QoS::AtMostOnce => QoS::AtLeastOnce,
+            qos => qos,
+        };
+        debug!("Publishing availability [{}] to topic [{}]", payload, topic);
+        trace_publish(&topic, qos, true, payload);
+        match client.try_publish(topic, qos, true, payload.as_str()) {
+            Ok(_) => wait_for_acks(connection),
+            Err(e) => error!("Failed to publish availability due to error: {}", e),
+        }
+    }
+
+    disconnect(client, connection, connected);
+}
+
+/// Drives the connection until the last QoS 1 publish queued has been acknowledged.
+fn wait_for_acks(connection: &mut Connection) {
+    let deadline = Instant::now() + DISCONNECT_TIMEOUT;
+    // Requests are sent in order, so once a QoS 1 publish has gone out and nothing is left in
+    // flight, the last one queued has been acknowledged
+    let mut sent = false;
+    loop {
+        match connection.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(Event::Outgoing(Outgoing::Publish(pkid)))) if pkid != 0 => sent = true,
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => {
+                debug!("Connection closed while waiting for acknowledgements: {}", e);
+                return;
+            },
+            Err(_) => {
+                warn!("Timed out after {:?} waiting for the broker to acknowledge pending messages", DISCONNECT_TIMEOUT);
+                return;
+            },
+        }
+        if sent && connection.eventloop.state.inflight() == 0 {
+            debug!("All pending messages acknowledged");
+            return;
+        }
+    }
+}
+
+/// Disconnects cleanly so the broker doesn't publish the last will, giving queued messages a
+/// bounded amount of time to go out first.
+pub fn disconnect(client: &Client, connection: &mut Connection, connected: &AtomicBool) {
+    if !connected.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    if let Err(e) = client.try_disconnect() {
+        debug!("Failed to request disconnect due to error: {}", e);
+        return;
+    }
+
+    let deadline = Instant::now() + DISCONNECT_TIMEOUT;
+    loop {
+        match connection.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(Event::Outgoing(Outgoing::Disconnect))) => {
+                debug!("Disconnected from MQTT broker");
+                return;
+            },
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => {
+                debug!("Connection closed while disconnecting: {}", e);
+                return;
+            },
+            Err(_) => {
+                warn!("Timed out after {:?} disconnecting from MQTT broker", DISCONNECT_TIMEOUT);
+                return;
+            },
+        }
+    }
+}
+
+/// Every topic this config publishes or subscribes to, as `(direction, topic)`, in the order they
+/// are first used.
+pub fn get_topics(config: &Data) -> Vec<(&'static str, String)> {
+    let mut topics = Vec::new();
+    let components = get_enabled_components(config);
+
+    let discovery = if config.mqtt.discovery() { components.as_slice() } else { &[] };
+    for &sensor_component in discovery {
+        topics.push(("publish", get_discovery_topic(config, sensor_component)));
+    }
+    for &sensor_component in &components {
+        let topic = get_state_topic(config, sensor_component);
+        if !topics.iter().any(|(_, t)| *t == topic) {
+            topics.push(("publish", topic));
+        }
+    }
+    if config.mqtt.availability {
+        topics.push(("publish", get_availability_topic(config)));
+        if config.mqtt.per_entity_availability {
+            for sensor_component in components.iter().filter(|c| c.is_measurement()) {
+                topics.push(("publish", get_entity_availability_topic(config, *sensor_component)));
+            }
+        }
+    }
+    if publishes_attributes(config) {
+        topics.push(("publish", get_attributes_topic(config)));
+    }
+    if config.mqtt.publish_config_echo {
+        topics.push(("publish", get_config_echo_topic(config)));
+    }
+    if config.mqtt.error_summary_interval_seconds > 0 || config.mqtt.max_publish_gap_seconds.is_some() {
+        topics.push(("publish", get_error_summary_topic(config)));
+    }
+    if config.mqtt.stats_interval_seconds > 0 {
+        topics.push(("publish", get_stats_topic(config)));
+    }
+    if config.mqtt.daily_summary {
+        topics.push(("publish", get_daily_summary_topic(config, "+")));
+    }
+    if config.mqtt.heartbeat_interval_seconds > 0 {
+        topics.push(("publish", heartbeat::get_heartbeat_topic(config)));
+    }
+    if config.mqtt.poll_interval_control {
+        if config.mqtt.discovery() {
+            topics.push(("publish", get_poll_interval_discovery_topic(config)));
+        }
+        topics.push(("publish", get_poll_interval_state_topic(config)));
+    }
+    for trigger in &config.mqtt.temperature_triggers {
+        if config.mqtt.discovery() {
+            topics.push(("publish", get_trigger_discovery_topic(config, trigger)));
+        }
+        topics.push(("publish", get_trigger_topic(config, trigger)));
+    }
+    if let Some(command_topic) = config.mqtt.command_topic.as_ref().filter(|_| config.mqtt.clear_retained_command) {
+        topics.push(("publish", command_topic.clone()));
+    }
+    // The discovery topics are also subscribed to briefly at startup to compare the retained payloads
+    for &sensor_component in discovery {
+        topics.push(("subscribe", get_discovery_topic(config, sensor_component)));
+    }
+    if let Some(birth_topic) = &config.mqtt.birth_topic {
+        topics.push(("subscribe", birth_topic.clone()));
+    }
+    if config.mqtt.poll_interval_control {
+        topics.push(("subscribe", get_poll_interval_command_topic(config)));
+    }
+    if let Some(command_topic) = &config.mqtt.command_topic {
+        topics.push(("subscribe", command_topic.clone()));
+    }
+    topics
+}
+
+/// Converts `[metadata]` values to JSON as they are, with dates and times as their TOML text.
+fn metadata_to_json(value: &toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => json!(s),
+        toml::Value::Integer(i) => json!(i),
+        toml::Value::Float(f) => json!(f),
+        toml::Value::Boolean(b) => json!(b),
+        toml::Value::Datetime(datetime) => json!(datetime.to_string()),
+        toml::Value::Array(values) => Value::Array(values.iter().map(metadata_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(table.iter().map(|(key, value)| (key.clone(), metadata_to_json(value))).collect()),
+    }
+}
+
+/// Whether there is anything to put on the attributes topic.
+fn publishes_attributes(config: &Data) -> bool {
+    !config.mqtt.attributes.is_empty() || !config.metadata.is_empty() || config.mqtt.latitude.is_some()
+}
+
+fn get_attributes_topic(config: &Data) -> String {
+    format!("{}/attributes", get_base_topic(config))
+}
+
+/// Describes the running build and the deployment, shown in HA as attributes of every entity.
+fn get_attributes_message(config: &Data, stats: &Stats) -> String {
+    // Set first, so a metadata key can't hide the build details of the same name
+    let mut attributes = metadata_to_json(&toml::Value::Table(config.metadata.clone()));
+    for attribute in &config.mqtt.attributes {
+        match attribute {
+            Attribute::Version => attributes["version"] = json!(env!("CARGO_PKG_VERSION")),
+            Attribute::Commit => attributes["commit"] = json!(env!("BUILD_GIT_COMMIT")),
+            Attribute::Built => {
+                let built = env!("BUILD_TIMESTAMP").parse().ok()
+                    .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp).ok())
+                    .and_then(|built| in_timezone(config, built).format(&Rfc3339).ok());
+                attributes["built"] = json!(built);
+            },
+            Attribute::Target => attributes["target"] = json!(env!("BUILD_TARGET")),
+            Attribute::SamplingMode => attributes["sampling_mode"] = json!(config.sensor.sampling_mode),
+            Attribute::SessionId => attributes["session_id"] = json!(session_id()),
+            Attribute::Capabilities => {
+                if let Some(capabilities) = get_capabilities(stats.chip_id.load(Ordering::Relaxed), config.sensor.sampling_mode) {
+                    attributes["capabilities"] = capabilities;
+                }
+            },
+        }
+    }
+    if let Some(coefficients) = stats.coefficients.get().filter(|_| config.mqtt.publish_coefficients) {
+        attributes["calibration_coefficients"] = json!(coefficients);
+    }
+    // The names HA looks for to show an entity on the map
+    if let (Some(latitude), Some(longitude)) = (config.mqtt.latitude, config.mqtt.longitude) {
+        attributes["latitude"] = json!(latitude);
+        attributes["longitude"] = json!(longitude);
+    }
+    attributes.to_string()
+}
+
+/// Ranges and accuracy the datasheet rates the chip with `chip_id` for, and the pressure noise of
+/// the sampling mode. `None` for a chip the figures aren't known of, or before one was detected.
+fn get_capabilities(chip_id: u8, sampling_mode: SensorSamplingMode) -> Option<Value> {
+    if chip_id != BMP180_CHIP_ID {
+        return None;
+    }
+    let pressure_noise_hpa = match sampling_mode {
+        SensorSamplingMode::UltraLowPower => 0.06,
+        SensorSamplingMode::Standard => 0.05,
+        SensorSamplingMode::HighRes => 0.04,
+        SensorSamplingMode::UltraHighRes => 0.03,
+    };
+    // The BMP085 and BMP180 share a chip id and are rated the same
+    Some(json!({
+        "model": "BMP085/BMP180",
+        "temperature_range_c": [-40, 85],
+        "temperature_resolution_c": 0.1,
+        "temperature_accuracy_c": 1.0,
+        "pressure_range_hpa": [300, 1100],
+        "pressure_resolution_hpa": 0.01,
+        "pressure_accuracy_hpa": [-4.0, 2.0],
+        "pressure_relative_accuracy_hpa": 0.12,
+        "pressure_noise_hpa": pressure_noise_hpa,
+    }))
+}
+
+fn publish_attributes(client: &Client, config: &Data, stats: &Stats) -> Result<(), ExitCode> {
+    if !publishes_attributes(config) {
+        return Ok(());
+    }
+    let topic = get_attributes_topic(config);
+    debug!("Publishing attributes to topic [{}]", topic);
+    let msg = get_attributes_message(config, stats);
+    let qos = qos(config, Category::Diagnostics);
+    trace_publish(&topic, qos, true, &msg);
+    match client.publish(topic, qos, true, msg) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to publish attributes due to error: {}", e);
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+fn get_stats_topic(config: &Data) -> String {
+    format!("{}/stats", config.mqtt.room)
+}
+
+fn get_daily_summary_topic(config: &Data, date: &str) -> String {
+    format!("{}/daily/{}", config.mqtt.room, date)
+}
+
+/// Time left until the next midnight in the configured timezone.
+fn until_midnight(config: &Data) -> Duration {
+    let now = now(config);
+    match now.date().next_day() {
+        Some(tomorrow) => (tomorrow.midnight().assume_offset(now.offset()) - now).unsigned_abs(),
+        None => Duration::from_secs(24 * 60 * 60),
+    }
+}
+
+fn get_error_summary_topic(config: &Data) -> String {
+    format!("{}/errors", config.mqtt.identifier)
+}
+
+fn get_config_echo_topic(config: &Data) -> String {
+    format!("{}/config", config.mqtt.identifier)
+}
+
+fn get_availability_topic(config: &Data) -> String {
+    format!("{}/availability", get_base_topic(config))
+}
+
+fn publish_availability(client: &Client, config: &Data, online: bool) -> Result<(), ExitCode> {
+    let topic = get_availability_topic(config);
+    let payload = if online { &config.mqtt.payload_available } else { &config.mqtt.payload_not_available };
+    debug!("Publishing availability [{}] to topic [{}]", payload, topic);
+    let qos = qos(config, Category::Availability);
+    trace_publish(&topic, qos, true, payload);
+    match client.publish(topic, qos, true, payload.as_str()) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to publish availability due to error: {}", e);
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+fn get_entity_availability_topic(config: &Data, sensor_component: SensorComponent) -> String {
+    format!("{}/{}/availability", get_base_topic(config), sensor_component.id())
+}
+
+fn publish_entity_availability(client: &Client, config: &Data, sensor_component: SensorComponent, online: bool) -> Result<(), ExitCode> {
+    let topic = get_entity_availability_topic(config, sensor_component);
+    let payload = if online { &config.mqtt.payload_available } else { &config.mqtt.payload_not_available };
+    debug!("Publishing {} availability [{}] to topic [{}]", sensor_component.id(), payload, topic);
+    let qos = qos(config, Category::Availability);
+    trace_publish(&topic, qos, true, payload);
+    match client.publish(topic, qos, true, payload.as_str()) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to publish {} availability due to error: {}", sensor_component.id(), e);
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+fn publish_sensor_data(client: &Client, config: &Data, stats: &Stats, msgs: Vec<StateMessage>, retain: bool) -> Result<(), ExitCode> {
+    let qos = qos(config, Category::State);
+    for (topic, msg) in msgs {
+        debug!("Publishing sensor data to topic [{}]", topic);
+        trace_publish(&topic, qos, retain, &String::from_utf8_lossy(&msg));
+        if !config.mqtt.publish_blocking {
+            if let Err(e) = client.try_publish(topic, qos, retain, msg) {
+                let dropped = stats.dropped_publishes.fetch_add(1, Ordering::Relaxed) + 1;
+                stats.publish_failures.fetch_add(1, Ordering::Relaxed);
+                warn!("Dropping sensor state message, the MQTT queue is full ({} dropped so far): {}", dropped, e);
+            }
+            continue;
+        }
+        // The next reading publishes fresh values anyway, so one that can't be published is skipped
+        let mut attempt = 0;
+        while let Err(e) = client.publish(&topic, qos, retain, msg.as_slice()) {
+            if attempt == config.mqtt.state_retries {
+                stats.publish_failures.fetch_add(1, Ordering::Relaxed);
+                warn!("Skipping this reading, the state message failed to publish after {} attempts: {}", attempt + 1, e);
+                return Ok(());
+            }
+            attempt += 1;
+            debug!("Retrying the state message in {:?} after error: {}", STATE_RETRY_DELAY, e);
+            thread::sleep(STATE_RETRY_DELAY);
+        }
+    }
+    Ok(())
+}
+
+/// Kinds of message, each published with the QoS `[mqtt.qos]` sets for it.
+#[derive(Clone, Copy)]
+pub enum Category {
+    Discovery,
+    State,
+    Availability,
+    Diagnostics,
+    Command,
+}
+
+pub fn qos(config: &Data, category: Category) -> QoS {
+    let levels = &config.mqtt.qos;
+    let level = match category {
+        Category::Discovery => config.mqtt.qos_discovery.unwrap_or(levels.discovery),
+        Category::State => levels.state,
+        Category::Availability => levels.availability,
+        Category::Diagnostics => levels.diagnostics,
+        Category::Command => levels.command,
+    };
+    // Checked when the config is read
+    rumqttc::qos(level).unwrap_or(QoS::AtMostOnce)
+}
+
+/// Logs a publish as it is queued. The event loop only reports the packet id once it goes out.
+fn trace_publish(topic: &str, qos: QoS, retain: bool, payload: &str) {
+    trace!(target: TRACE_TARGET, "Publishing to [{}] ({:?}, retain {}): {}", topic, qos, retain, payload);
+}
+
+/// Topic the state of a component is published to. All components share one topic in JSON mode.
+fn get_state_topic(config: &Data, sensor_component: SensorComponent) -> String {
+    if matches!(config.mqtt.state_format, StateFormat::Json) && sensor_component.is_scheduled_diagnostic(config) {
+        return match &config.mqtt.state_topic {
+            Some(state_topic) => format!("{}/diagnostics", state_topic),
+            None => format!("{}/diagnostics", get_base_topic(config)),
+        };
+    }
+    match (config.mqtt.state_format, &config.mqtt.state_topic) {
+        (StateFormat::Json, Some(state_topic)) => state_topic.clone(),
+        (StateFormat::Json, None) if config.mqtt.layout == Layout::Z2m => get_base_topic(config),
+        (StateFormat::Json, None) => format!("{}/state", get_base_topic(config)),
+        (StateFormat::Split, Some(state_topic)) => format!("{}/{}", state_topic, sensor_component.id()),
+        (StateFormat::Split, None) => format!("{}/{}", get_base_topic(config), sensor_component.id()),
+    }
+}
+
+/// Topic the device's own topics are published under, set by `layout`.
+fn get_base_topic(config: &Data) -> String {
+    match config.mqtt.layout {
+        Layout::Z2m => format!("sensors/{}", config.mqtt.room),
+        Layout::Hass | Layout::Custom => format!("homeassistant/sensor/{}", config.mqtt.room),
+    }
+}
+
+/// A retained discovery message, for an entity or a device trigger.
+#[derive(Clone, Copy)]
+enum Discovery<'a> {
+    Component(SensorComponent),
+    PollInterval,
+    Trigger(&'a TemperatureTrigger),
+}
+
+impl Discovery<'_> {
+    fn topic(&self, config: &Data) -> String {
+        match self {
+            Discovery::Component(sensor_component) => get_discovery_topic(config, *sensor_component),
+            Discovery::PollInterval => get_poll_interval_discovery_topic(config),
+            Discovery::Trigger(trigger) => get_trigger_discovery_topic(config, trigger),
+        }
+    }
+
+    fn message(&self, config: &Data) -> String {
+        match self {
+            Discovery::Component(sensor_component) => get_discovery_message(config, *sensor_component),
+            Discovery::PollInterval => get_poll_interval_discovery_message(config),
+            Discovery::Trigger(trigger) => get_trigger_discovery_message(config, trigger),
+        }
+    }
+}
+
+/// Topics `--purge-discovery` clears the retained discovery message on.
+fn get_purge_topics(config: &Data) -> Vec<String> {
+    get_discoveries(config).iter().map(|discovery| discovery.topic(config)).collect()
+}
+
+/// Every discovery message the config publishes, in order. `--purge-discovery` clears the same
+/// ones and `--inspect-discovery` and the retained check compare against them, so none of them
+/// misses one of ours or touches anything else.
+fn get_discoveries(config: &Data) -> Vec<Discovery<'_>> {
+    if !config.mqtt.discovery() {
+        return Vec::new();
+    }
+    let mut discoveries: Vec<Discovery> = get_enabled_components(config).into_iter().map(Discovery::Component).collect();
+    if config.mqtt.poll_interval_control {
+        discoveries.push(Discovery::PollInterval);
+    }
+    discoveries.extend(config.mqtt.temperature_triggers.iter().map(Discovery::Trigger));
+    discoveries
+}
+
+/// Discovery is best effort: each message is retried with backoff, and one that still fails is
+/// skipped so state keeps flowing. HA can still be configured manually.
+fn publish_sensor_discovery_message(client: &Client, config: &Data, stats: &Stats, sensor_component: SensorComponent) {
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+    while publish_discovery_message(client, config, sensor_component).is_err() {
+        if attempt == config.mqtt.discovery_retries {
+            warn!("Giving up on the {} discovery message after {} attempts", sensor_component.id(), attempt + 1);
+            stats.publish_failures.fetch_add(1, Ordering::Relaxed);
+            break;
+        }
+        attempt += 1;
+        debug!("Retrying the {} discovery message in {:?}", sensor_component.id(), backoff);
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+}
+
+fn get_discovery_topic(config: &Data, sensor_component: SensorComponent) -> String {
+    format!("homeassistant/{}/{}{}/config", sensor_component.platform(), config.mqtt.room, sensor_component.topic_name())
+}
+
+fn publish_discovery_message(client: &Client, config: &Data, sensor_component: SensorComponent) -> Result<(), ExitCode> {
+    let topic = get_discovery_topic(config, sensor_component);
+    debug!("Publishing sensor {} discovery message to topic [{}]", sensor_component.id(), topic);
+    let msg = get_discovery_message(config, sensor_component);
+    // Not blocking on a full queue, so a backed up event loop counts as a failure to retry
+    let qos = qos(config, Category::Discovery);
+    trace_publish(&topic, qos, true, &msg);
+    match client.try_publish(topic, qos, true, msg) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to publish {} discovery message due to error: {}", sensor_component.id(), e);
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+fn get_discovery_message(config: &Data, sensor_component: SensorComponent) -> String {
+    let value_template_str = match config.mqtt.state_format {
+        StateFormat::Json => format!("value_json.{}", sensor_component.json_key(config)),
+        StateFormat::Split => String::from("value"),
+    };
+
+    // The unique_id is built from the component id rather than the device class, as several
+    // components share a device class.
+    let mut discovery_msg = json!({
+        "state_topic": get_state_topic(config, sensor_component),
+        "value_template": format!("{{{{ {} }}}}", value_template_str),
+        "unique_id": format!("{}{}_{}", config.mqtt.unique_id_prefix, config.mqtt.identifier, sensor_component.id()),
+        "device": get_device(config),
+    });
+    if publishes_attributes(config) {
+        discovery_msg["json_attributes_topic"] = json!(get_attributes_topic(config));
+    }
+    if let Some(device_class) = sensor_component.device_class() {
+        discovery_msg["device_class"] = json!(device_class);
+    }
+    if let Some(state_class) = sensor_component.state_class(config) {
+        discovery_msg["state_class"] = json!(state_class);
+    }
+    add_availability(config, &mut discovery_msg, Some(sensor_component));
+    if config.mqtt.force_update {
+        discovery_msg["force_update"] = json!(true);
+    }
+    if let Some(expire_after) = config.mqtt.expire_after_seconds.filter(|_| sensor_component.is_measurement()) {
+        discovery_msg["expire_after"] = json!(expire_after);
+    }
+    if let Some(unit) = sensor_component.unit(config) {
+        discovery_msg["unit_of_measurement"] = json!(unit);
+    }
+    if let Some(name) = sensor_component.entity_name() {
+        discovery_msg["name"] = json!(name);
+    }
+    if let Some(icon) = sensor_component.icon(config) {
+        discovery_msg["icon"] = json!(icon);
+    }
+    if let Some(entity_category) = sensor_component.entity_category() {
+        discovery_msg["entity_category"] = json!(entity_category);
+    }
+    if matches!(sensor_component, SensorComponent::ComfortBand) {
+        discovery_msg["options"] = json!(COMFORT_BANDS);
+    }
+
+    discovery_msg.to_string()
+}
+
+/// Home Assistant `mqtt:` YAML setting up the same entities as discovery, for pasting into
+/// `configuration.yaml`. Built from the discovery messages, so the two can't drift apart.
+pub fn get_ha_yaml(config: &Data) -> String {
+    let mut entities: Vec<(&str, String)> = get_enabled_components(config).into_iter()
+        .map(|sensor_component| (sensor_component.platform(), get_discovery_message(config, sensor_component)))
+        .collect();
+    if config.mqtt.poll_interval_control {
+        entities.push(("number", get_poll_interval_discovery_message(config)));
+    }
+
+    let mut yaml = String::from("mqtt:\n");
+    for platform in ["sensor", "binary_sensor", "number"] {
+        let items: Vec<Value> = entities.iter()
+            .filter(|(p, _)| *p == platform)
+            .filter_map(|(_, msg)| serde_json::from_str(msg).ok())
+            .collect();
+        if !items.is_empty() {
+            yaml.push_str(&format!("  {}:\n", platform));
+            write_yaml(&mut yaml, &Value::Array(items), 4);
+        }
+    }
+    if !config.mqtt.temperature_triggers.is_empty() {
+        yaml.push_str("# The temperature triggers are device triggers, which HA only sets up through discovery\n");
+    }
+    yaml
+}
+
+/// Writes `value` as block YAML indented by `indent`. Scalars are written as JSON, which is valid
+/// YAML and quotes the templates.
+fn write_yaml(yaml: &mut String, value: &Value, indent: usize) {
+    let pad = " ".repeat(indent);
+    match value {
+        Value::Object(map) => for (key, value) in map {
+            match value {
+                Value::Object(m) if !m.is_empty() => {
+                    yaml.push_str(&format!("{}{}:\n", pad, key));
+                    write_yaml(yaml, value, indent + 2);
+                },
+                Value::Array(a) if !a.is_empty() => {
+                    yaml.push_str(&format!("{}{}:\n", pad, key));
+                    write_yaml(yaml, value, indent + 2);
+                },
+                _ => yaml.push_str(&format!("{}{}: {}\n", pad, key, value)),
+            }
+        },
+        // Each item is written two deeper, then its first line's indent replaced by the dash
+        Value::Array(items) => for item in items {
+            let mut entry = String::new();
+            write_yaml(&mut entry, item, indent + 2);
+            yaml.push_str(&format!("{}- {}", pad, &entry[indent + 2..]));
+        },
+        scalar => yaml.push_str(&format!("{}{}\n", pad, scalar)),
+    }
+}
+
+/// The device every entity belongs to, in discovery messages.
+fn get_device(config: &Data) -> Value {
+    let mut device = json!({
+        "identifiers": [config.mqtt.identifier],
+        "name": config.mqtt.name,
+    });
+    let suggested_area = config.mqtt.suggested_area.as_ref().unwrap_or(&config.mqtt.room);
+    if !suggested_area.is_empty() {
+        device["suggested_area"] = json!(suggested_area);
+    }
+    if let Some(via_device) = &config.mqtt.via_device {
+        device["via_device"] = json!(via_device);
+    }
+    if let Some(configuration_url) = &config.mqtt.configuration_url {
+        device["configuration_url"] = json!(configuration_url);
+    }
+    device
+}
+
+/// Points the entity at the device's availability topic, and with `per_entity_availability` at
+/// that of a measurement entity too, which both have to be `online`.
+fn add_availability(config: &Data, discovery_msg: &mut Value, sensor_component: Option<SensorComponent>) {
+    if !config.mqtt.availability {
+        return;
+    }
+    match sensor_component.filter(|c| config.mqtt.per_entity_availability && c.is_measurement()) {
+        Some(sensor_component) => {
+            let entry = |topic: String| json!({
+                "topic": topic,
+                "payload_available": config.mqtt.payload_available,
+                "payload_not_available": config.mqtt.payload_not_available,
+            });
+            discovery_msg["availability"] = json!([
+                entry(get_availability_topic(config)),
+                entry(get_entity_availability_topic(config, sensor_component)),
+            ]);
+            discovery_msg["availability_mode"] = json!("all");
+        },
+        None => {
+            discovery_msg["availability_topic"] = json!(get_availability_topic(config));
+            discovery_msg["payload_available"] = json!(config.mqtt.payload_available);
+            discovery_msg["payload_not_available"] = json!(config.mqtt.payload_not_available);
+        },
+    }
+}
+
+fn get_poll_interval_discovery_topic(config: &Data) -> String {
+    format!("homeassistant/number/{}PollInterval/config", config.mqtt.room)
+}
+
+fn get_poll_interval_state_topic(config: &Data) -> String {
+    format!("{}/poll_interval", config.mqtt.identifier)
+}
+
+fn get_poll_interval_command_topic(config: &Data) -> String {
+    format!("{}/poll_interval/set", config.mqtt.identifier)
+}
+
+/// A number entity for changing the poll interval from HA, bounded by what the sensor can keep
+/// up with.
+fn get_poll_interval_discovery_message(config: &Data) -> String {
+    let mut discovery_msg = json!({
+        "name": "Poll interval",
+        "unique_id": format!("{}{}_poll_interval", config.mqtt.unique_id_prefix, config.mqtt.identifier),
+        "state_topic": get_poll_interval_state_topic(config),
+        "command_topic": get_poll_interval_command_topic(config),
+        "min": config.sensor.min_poll_interval().as_secs_f64(),
+        "max": POLL_INTERVAL_MAX,
+        "step": 0.1,
+        "mode": "box",
+        "unit_of_measurement": "s",
+        "entity_category": "config",
+        "device": get_device(config),
+    });
+    add_availability(config, &mut discovery_msg, None);
+    discovery_msg.to_string()
+}
+
+fn publish_poll_interval_discovery(client: &Client, config: &Data) {
+    let topic = get_poll_interval_discovery_topic(config);
+    debug!("Publishing poll interval discovery message to topic [{}]", topic);
+    let msg = get_poll_interval_discovery_message(config);
+    let qos = qos(config, Category::Discovery);
+    trace_publish(&topic, qos, true, &msg);
+    if let Err(e) = client.try_publish(topic, qos, true, msg) {
+        warn!("Failed to publish poll interval discovery message due to error: {}", e);
+    }
+}
+
+fn get_trigger_discovery_topic(config: &Data, trigger: &TemperatureTrigger) -> String {
+    format!("homeassistant/device_automation/{}/{}/config", config.mqtt.room, trigger.name)
+}
+
+fn get_trigger_topic(config: &Data, trigger: &TemperatureTrigger) -> String {
+    format!("{}/trigger/{}", config.mqtt.identifier, trigger.name)
+}
+
+/// A device trigger, shown in HA's automation editor as e.g. "temperature_above too_hot". Any
+/// payload fires it.
+fn get_trigger_discovery_message(config: &Data, trigger: &TemperatureTrigger) -> String {
+    json!({
+        "automation_type": "trigger",
+        "topic": get_trigger_topic(config, trigger),
+        "type": if trigger.above.is_some() { "temperature_above" } else { "temperature_below" },
+        "subtype": trigger.name,
+        "device": get_device(config),
+    }).to_string()
+}
+
+fn publish_trigger_discovery(client: &Client, config: &Data, trigger: &TemperatureTrigger) {
+    let topic = get_trigger_discovery_topic(config, trigger);
+    debug!("Publishing trigger {} discovery message to topic [{}]", trigger.name, topic);
+    let msg = get_trigger_discovery_message(config, trigger);
+    let qos = qos(config, Category::Discovery);
+    trace_publish(&topic, qos, true, &msg);
+    if let Err(e) = client.try_publish(topic, qos, true, msg) {
+        warn!("Failed to publish trigger {} discovery message due to error: {}", trigger.name, e);
+    }
+}
+
+/// Fires the trigger. Not retained, as a trigger replayed on reconnect would fire the automation
+/// again.
+fn publish_trigger(client: &Client, config: &Data, trigger: &TemperatureTrigger, temperature: f32) -> Result<(), ExitCode> {
+    let topic = get_trigger_topic(config, trigger);
+    let msg = temperature.to_string();
+    debug!("Firing trigger {} on topic [{}]", trigger.name, topic);
+    trace_publish(&topic, QoS::AtLeastOnce, false, &msg);
+    match client.publish(topic, QoS::AtLeastOnce, false, msg) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to fire trigger {} due to error: {}", trigger.name, e);
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+fn publish_poll_interval(client: &Client, config: &Data, poll_interval: f64) -> Result<(), ExitCode> {
+    let topic = get_poll_interval_state_topic(config);
+    let msg = poll_interval.to_string();
+    debug!("Publishing poll interval [{}] to topic [{}]", msg, topic);
+    let qos = qos(config, Category::State);
+    trace_publish(&topic, qos, true, &msg);
+    match client.publish(topic, qos, true, msg) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to publish poll interval due to error: {}", e);
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+/// `last_temperature` stands in for the temperature of readings that only took the pressure.
+/// `rates` are the temperature and pressure changes per minute.
+fn get_state_values(config: &Data, reading: &Reading, last_temperature: Option<f32>, rapid_drop: bool, rates: (Option<f32>, Option<f32>), uptime: Duration, stats: &Stats) -> Vec<(SensorComponent, StateValue)> {
+    get_enabled_components(config).into_iter().filter_map(|sensor_component| {
+        let value = match sensor_component {
+            SensorComponent::Temperature(unit) => {
+                let temperature = Measurement::new(reading.temperature?, Unit::Celsius).converted_to(unit)?;
+                StateValue::Number(format_number(temperature, sensor_component.transform(config), config.sensor.temperature_decimals))
+            },
+            SensorComponent::Pressure => {
+                let pressure = reduce_pressure(&config.sensor, reading.pressure?, reading.temperature.or(last_temperature))?;
+                StateValue::Number(format_number(Measurement::new(pressure, Unit::Kilopascals), sensor_component.transform(config), None))
+            },
+            SensorComponent::RawPressure => {
+                StateValue::Number(format_number(Measurement::new(reading.pressure?, Unit::Kilopascals), sensor_component.transform(config), None))
+            },
+            SensorComponent::Altitude => {
+                let altitude = calculate_altitude(reading.pressure?, config.sensor.sea_level_pressure);
+                // A nonsensical sea level pressure can leave nothing sensible to publish
+                if !config.sensor.altitude_range().contains(&altitude.raw) {
+                    warn!("Skipping altitude of {} m, check sea_level_pressure", altitude.raw);
+                    return None;
+                }
+                let altitude = altitude.converted_to(config.sensor.altitude_unit.into())?;
+                StateValue::Number(format_number(altitude, sensor_component.transform(config), Some(config.sensor.altitude_decimals)))
+            },
+            SensorComponent::PressureAltitude => {
+                let altitude = calculate_pressure_altitude(reading.pressure?).converted_to(config.sensor.altitude_unit.into())?;
+                StateValue::Number(format_number(altitude, sensor_component.transform(config), Some(config.sensor.altitude_decimals)))
+            },
+            SensorComponent::DensityAltitude => {
+                let altitude = calculate_density_altitude(reading.pressure?, reading.temperature.or(last_temperature)?)
+                    .converted_to(config.sensor.altitude_unit.into())?;
+                StateValue::Number(format_number(altitude, sensor_component.transform(config), Some(config.sensor.altitude_decimals)))
+            },
+            SensorComponent::TemperatureDrift => StateValue::Number(format!("{:.2}", Measurement::new(reading.temperature_drift?, Unit::Celsius))),
+            SensorComponent::TemperatureRate => StateValue::Number(format!("{:.3}", rates.0?)),
+            SensorComponent::PressureRate => StateValue::Number(format!("{:.4}", rates.1?)),
+            SensorComponent::ComfortBand => StateValue::Text(String::from(comfort_band(config.sensor.comfort_bands?, reading.temperature?))),
+            SensorComponent::RapidDrop => StateValue::Text(String::from(if rapid_drop { "ON" } else { "OFF" })),
+            SensorComponent::Uptime => StateValue::Number(uptime.as_secs().to_string()),
+            SensorComponent::ReadLatency => StateValue::Number(format!("{:.1}", reading.read_latency.as_secs_f64() * 1000.0)),
+            SensorComponent::LastUpdate => StateValue::Text(now(config).format(&Rfc3339).unwrap_or_default()),
+            SensorComponent::ReinitCount => StateValue::Number(stats.sensor_reinits.load(Ordering::Relaxed).to_string()),
+            // Left out when reading from a replay file rather than the chip
+            SensorComponent::ChipId => match stats.chip_id.load(Ordering::Relaxed) {
+                0 => return None,
+                chip_id => StateValue::Text(format!("{:#04x}", chip_id)),
+            },
+            SensorComponent::PendingStates => StateValue::Number(stats.pending_states.load(Ordering::Relaxed).to_string()),
+            SensorComponent::DroppedStates => StateValue::Number(stats.dropped_pending_states.load(Ordering::Relaxed).to_string()),
+            SensorComponent::CpuTemperature => StateValue::Number(format!("{:.1}", Measurement::new(read_cpu_temperature()?, Unit::Celsius))),
+        };
+        Some((sensor_component, value))
+    }).collect()
+}
+
+/// The CPU temperature in °C, or `None` when the kernel doesn't report one, e.g. off a Pi.
+fn read_cpu_temperature() -> Option<f32> {
+    let millidegrees = fs::read_to_string(CPU_TEMPERATURE_PATH).map_err(|e| e.to_string())
+        .and_then(|contents| contents.trim().parse::<i32>().map_err(|e| e.to_string()));
+    match millidegrees {
+        Ok(millidegrees) => Some(millidegrees as f32 / 1000.0),
+        Err(e) => {
+            debug!("Cannot read the CPU temperature from `{}`: {}", CPU_TEMPERATURE_PATH, e);
+            None
+        }
+    }
+}
+
+/// Formats a measurement already converted to its unit, applying its transform and rounding to
+/// the transform's decimals, or else to `decimals`.
+fn format_number(measurement: Measurement, transform: Option<&Transform>, decimals: Option<usize>) -> String {
+    let (measurement, decimals) = match transform {
+        Some(transform) => (measurement.scaled(transform.scale, transform.offset), transform.decimals.or(decimals)),
+        None => (measurement, decimals),
+    };
+    match decimals {
+        Some(decimals) => format!("{:.*}", decimals, measurement.rounded(decimals)),
+        None => measurement.to_string(),
+    }
+}
+
+/// `last_known` marks a reading restored from before a restart, in JSON mode.
+fn get_state_messages(config: &Data, values: Vec<(SensorComponent, StateValue)>, last_known: bool) -> Vec<StateMessage> {
+    match config.mqtt.state_format {
+        StateFormat::Json => {
+            // The values of one batch share a topic, as measurements and diagnostics are published apart
+            let topic = get_state_topic(config, values.first().map_or(SensorComponent::Pressure, |&(sensor_component, _)| sensor_component));
+            let payload = match config.mqtt.payload_encoding {
+                PayloadEncoding::Json => get_state_message(config, values, last_known).into_bytes(),
+                PayloadEncoding::Msgpack => get_msgpack_state_message(config, &values, last_known),
+            };
+            vec![(topic, payload)]
+        },
+        StateFormat::Split => {
+            // Only the plain-number topics honor the separator, JSON always uses `.`
+            values.into_iter().map(|(sensor_component, value)| {
+                let payload = match value {
+                    StateValue::Number(n) => n.replace('.', &config.mqtt.decimal_separator),
+                    StateValue::Text(t) => t,
+                };
+                (get_state_topic(config, sensor_component), payload.into_bytes())
+            }).collect()
+        },
+    }
+}
+
+/// States setting every entity published with the readings to unknown: a null in JSON, which the
+/// value template renders as the `None` HA takes for unknown, and `None` itself on split topics.
+fn get_unknown_state_messages(config: &Data) -> Vec<StateMessage> {
+    let components: Vec<SensorComponent> = get_enabled_components(config).into_iter()
+        .filter(|sensor_component| !sensor_component.is_scheduled_diagnostic(config))
+        .collect();
+    match config.mqtt.state_format {
+        StateFormat::Json => {
+            let topic = get_state_topic(config, components.first().copied().unwrap_or(SensorComponent::Pressure));
+            let keys: Vec<String> = components.iter().map(|sensor_component| sensor_component.json_key(config)).collect();
+            let payload = match config.mqtt.payload_encoding {
+                PayloadEncoding::Json => Value::Object(keys.into_iter().map(|key| (key, Value::Null)).collect()).to_string().into_bytes(),
+                PayloadEncoding::Msgpack => msgpack::encode_map(&keys.iter().map(|key| (key.as_str(), msgpack::Value::Nil)).collect::<Vec<_>>()),
+            };
+            vec![(topic, payload)]
+        },
+        StateFormat::Split => components.into_iter()
+            .map(|sensor_component| (get_state_topic(config, sensor_component), b"None".to_vec()))
+            .collect(),
+    }
+}
+
+/// The JSON state object encoded as MessagePack, with integers kept as such and the other numbers
+/// as 32-bit floats.
+fn get_msgpack_state_message(config: &Data, values: &[(SensorComponent, StateValue)], last_known: bool) -> Vec<u8> {
+    let keys: Vec<String> = values.iter().map(|(sensor_component, _)| sensor_component.json_key(config)).collect();
+    let mut entries: Vec<(&str, msgpack::Value)> = keys.iter().zip(values).map(|(key, (_, value))| {
+        let value = match value {
+            StateValue::Number(n) => match n.parse::<i64>() {
+                Ok(n) => msgpack::Value::Int(n),
+                Err(_) => msgpack::Value::Float(n.parse().unwrap_or(f32::NAN)),
+            },
+            StateValue::Text(t) => msgpack::Value::Str(t),
+        };
+        (key.as_str(), value)
+    }).collect();
+    if last_known {
+        entries.push(("last_known", msgpack::Value::Bool(true)));
+    }
+    msgpack::encode_map(&entries)
+}
+
+/// States of the measurement entities, to set through HA's REST API while the broker is unreachable.
+fn get_rest_states(config: &Data, rest: &HomeAssistantRest, values: &[(SensorComponent, StateValue)]) -> Vec<homeassistant_rest::State> {
+    // HA only accepts lowercase letters, digits and underscores in object ids
+    let prefix = rest.entity_prefix.clone().unwrap_or_else(|| {
+        let object_id: String = config.mqtt.identifier.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+            .collect();
+        format!("sensor.{}", object_id)
+    });
+
+    // Binary sensors are left out, as the prefix names sensor entities
+    values.iter().filter(|(sensor_component, _)| sensor_component.entity_category().is_none() && sensor_component.platform() == "sensor").map(|(sensor_component, value)| {
+        let state = match value {
+            StateValue::Number(n) => n.clone(),
+            StateValue::Text(t) => t.clone(),
+        };
+        let entity_name = sensor_component.entity_name().unwrap_or(sensor_component.topic_name());
+        let mut attributes = json!({ "friendly_name": format!("{} {}", config.mqtt.name, entity_name) });
+        if let Some(device_class) = sensor_component.device_class() {
+            attributes["device_class"] = json!(device_class);
+        }
+        if let Some(unit) = sensor_component.unit(config) {
+            attributes["unit_of_measurement"] = json!(unit);
+        }
+        if let Some(state_class) = sensor_component.state_class(config) {
+            attributes["state_class"] = json!(state_class);
+        }
+        (format!("{}_{}", prefix, sensor_component.id()), state, attributes)
+    }).collect()
+}
+
+fn get_state_message(config: &Data, values: Vec<(SensorComponent, StateValue)>, last_known: bool) -> String {
+    let mut fields: Vec<String> = values.into_iter().map(|(sensor_component, value)| {
+        let value = match value {
+            StateValue::Number(n) => n,
+            StateValue::Text(t) => json!(t).to_string(),
+        };
+        format!("   {}: {}", json!(sensor_component.json_key(config)), value)
+    }).collect();
+    if last_known {
+        fields.push(String::from("   \"last_known\": true"));
+    }
+
+    format!("{{\n{}\n}}\n", fields.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config, reading};
+
+    /// The payloads of the state messages for `reading`, taken through the steps the read loop
+    /// puts it through before handing it to the publisher.
+    fn state_payloads(config: &Data, reading: Reading) -> Vec<(String, String)> {
+        let reading = crate::discard_non_finite(crate::calibrate(&config.sensor, reading));
+        let values = get_state_values(config, &reading, None, false, (None, None), Duration::ZERO, &Stats::default());
+        get_state_messages(config, values, false).into_iter()
+            .map(|(topic, payload)| (topic, String::from_utf8(payload).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn errors_reconnecting_cannot_fix_are_fatal() {
+        let invalid_certificate = || TlsError::TLS(rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer));
+        let fatal = [
+            ConnectionError::ConnectionRefused(ConnectReturnCode::BadUserNamePassword),
+            ConnectionError::ConnectionRefused(ConnectReturnCode::NotAuthorized),
+            ConnectionError::ConnectionRefused(ConnectReturnCode::BadClientId),
+            ConnectionError::ConnectionRefused(ConnectReturnCode::RefusedProtocolVersion),
+            ConnectionError::Tls(invalid_certificate()),
+            ConnectionError::Tls(TlsError::NoValidCertInChain),
+        ];
+        for error in &fatal {
+            assert!(fatal_error_hint(error).is_some(), "{}", error);
+            assert!(!is_broken_state(error), "{}", error);
+        }
+    }
+
+    #[test]
+    fn errors_that_may_pass_are_retried() {
+        let io = |kind| std::io::Error::from(kind);
+        let retryable = [
+            ConnectionError::Io(io(std::io::ErrorKind::ConnectionRefused)),
+            ConnectionError::Io(io(std::io::ErrorKind::TimedOut)),
+            ConnectionError::ConnectionRefused(ConnectReturnCode::ServiceUnavailable),
+            ConnectionError::NetworkTimeout,
+            ConnectionError::Tls(TlsError::Io(io(std::io::ErrorKind::ConnectionReset))),
+            ConnectionError::Tls(TlsError::TLS(rustls::Error::InvalidCertificate(rustls::CertificateError::NotValidYet))),
+            ConnectionError::MqttState(StateError::InvalidState),
+        ];
+        for error in &retryable {
+            assert!(fatal_error_hint(error).is_none(), "{}", error);
+        }
+    }
+
+    #[test]
+    fn only_session_state_errors_rebuild_the_connection() {
+        assert!(is_broken_state(&ConnectionError::MqttState(StateError::InvalidState)));
+        assert!(is_broken_state(&ConnectionError::MqttState(StateError::WrongPacket)));
+        assert!(!is_broken_state(&ConnectionError::MqttState(StateError::AwaitPingResp)));
+        assert!(!is_broken_state(&ConnectionError::Io(std::io::Error::from(std::io::ErrorKind::ConnectionReset))));
+        assert!(!is_broken_state(&ConnectionError::NetworkTimeout));
+    }
+
+    /// The discovery topics among those `--print-topics` lists as published, which are built
+    /// apart from the purge set.
+    fn published_discovery_topics(config: &Data) -> HashSet<String> {
+        get_topics(config).into_iter()
+            .filter(|(direction, topic)| *direction == "publish" && topic.starts_with("homeassistant/") && topic.ends_with("/config"))
+            .map(|(_, topic)| topic)
+            .collect()
+    }
+
+    #[test]
+    fn purge_clears_exactly_the_published_discovery_messages() {
+        let configs = [
+            "",
+            "sensor.altitude = true\nsensor.temperature_units = [\"C\", \"F\"]",
+            "mqtt.poll_interval_control = true",
+            "[[mqtt.temperature_triggers]]\nname = \"too_hot\"\nabove = 30.0",
+            "mqtt.diagnostics = true",
+        ];
+        for extra in configs {
+            let config = config(extra);
+            let purged = get_purge_topics(&config);
+            assert_eq!(purged.iter().cloned().collect::<HashSet<_>>().len(), purged.len(), "{}", extra);
+            assert_eq!(purged.into_iter().collect::<HashSet<_>>(), published_discovery_topics(&config), "{}", extra);
+        }
+    }
+
+    #[test]
+    fn purge_clears_nothing_without_discovery() {
+        let config = config("mqtt.publish_discovery = false");
+        assert!(get_purge_topics(&config).is_empty());
+        assert!(published_discovery_topics(&config).is_empty());
+    }
+
+    #[test]
+    fn topics_of_the_default_layout() {
+        let topics = get_topics(&config(""));
+        assert_eq!(topics, [
+            ("publish", String::from("homeassistant/sensor/hallwayTemperature/config")),
+            ("publish", String::from("homeassistant/sensor/hallwayPressure/config")),
+            ("publish", String::from("homeassistant/sensor/hallway/state")),
+            ("publish", String::from("homeassistant/sensor/hallway/availability")),
+            ("publish", String::from("homeassistant/sensor/hallway/attributes")),
+            ("subscribe", String::from("homeassistant/sensor/hallwayTemperature/config")),
+            ("subscribe", String::from("homeassistant/sensor/hallwayPressure/config")),
+        ]);
+    }
+
+    #[test]
+    fn topics_of_split_states_with_a_command_topic() {
+        let topics = get_topics(&config("mqtt.state_format = \"split\"\nmqtt.command_topic = \"bmp180/hallway/command\""));
+        assert_eq!(topics, [
+            ("publish", String::from("homeassistant/sensor/hallwayTemperature/config")),
+            ("publish", String::from("homeassistant/sensor/hallwayPressure/config")),
+            ("publish", String::from("homeassistant/sensor/hallway/temperature")),
+            ("publish", String::from("homeassistant/sensor/hallway/pressure")),
+            ("publish", String::from("homeassistant/sensor/hallway/availability")),
+            ("publish", String::from("homeassistant/sensor/hallway/attributes")),
+            ("publish", String::from("bmp180/hallway/command")),
+            ("subscribe", String::from("homeassistant/sensor/hallwayTemperature/config")),
+            ("subscribe", String::from("homeassistant/sensor/hallwayPressure/config")),
+            ("subscribe", String::from("bmp180/hallway/command")),
+        ]);
+    }
+
+    #[test]
+    fn z2m_layout_publishes_under_sensors_without_discovery() {
+        let config = config("mqtt.layout = \"z2m\"");
+        let topics = get_topics(&config);
+        assert!(topics.contains(&("publish", String::from("sensors/hallway"))), "{:?}", topics);
+        assert!(topics.iter().all(|(_, topic)| !topic.starts_with("homeassistant/")), "{:?}", topics);
+    }
+
+    /// The pressure is published unrounded, so the tests take one whose offset adds up exactly
+    /// in binary.
+    const PIPELINE_CONFIG: &str = r#"
+[sensor]
+temperature_offset = -1.5
+pressure_offset = 0.5
+temperature_units = ["C", "F"]
+temperature_decimals = 1
+"#;
+
+    #[test]
+    fn reading_goes_through_offsets_conversion_and_rounding_into_json() {
+        let config = config(PIPELINE_CONFIG);
+        let payloads = state_payloads(&config, reading(Some(22.34), Some(100.75)));
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].0, "homeassistant/sensor/hallway/state");
+        let state: Value = serde_json::from_str(&payloads[0].1).unwrap();
+        assert_eq!(state["temperature"], json!(20.8));
+        assert_eq!(state["temperature_fahrenheit"], json!(69.5));
+        assert_eq!(state["pressure"], json!(101.25));
+    }
+
+    #[test]
+    fn reading_goes_into_one_plain_number_per_split_topic() {
+        let config = config(&format!("{}\n[mqtt]\nstate_format = \"split\"", PIPELINE_CONFIG));
+        let payloads = state_payloads(&config, reading(Some(22.34), Some(100.75)));
+        assert_eq!(payloads, [
+            (String::from("homeassistant/sensor/hallway/temperature"), String::from("20.8")),
+            (String::from("homeassistant/sensor/hallway/temperature_fahrenheit"), String::from("69.5")),
+            (String::from("homeassistant/sensor/hallway/pressure"), String::from("101.25")),
+        ]);
+    }
+
+    /// A publisher that takes itself to be connected, with the connection whose queue its
+    /// messages pile up in.
+    fn connected_publisher(config: &Data) -> (Publisher, Connection) {
+        let (client, connection) = Client::new(MqttOptions::new("test", "127.0.0.1", 1883), 100);
+        let publisher = Publisher::new(config, client, Arc::new(AtomicBool::new(true)), Arc::new(Stats::default()));
+        (publisher, connection)
+    }
+
+    /// The messages queued since the last call, as `(topic, payload)`.
+    fn sent(connection: &mut Connection) -> Vec<(String, String)> {
+        connection.eventloop.clean();
+        connection.eventloop.pending.drain(..).filter_map(|request| match request {
+            rumqttc::Request::Publish(publish) => Some((publish.topic, String::from_utf8(publish.payload.to_vec()).unwrap())),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn nothing_reports_ready_before_the_first_good_read() {
+        for on_read_failure in ["hold", "unavailable", "republish_last"] {
+            let config = config(&format!("mqtt.availability = true\nmqtt.on_read_failure = \"{}\"", on_read_failure));
+            let state_topic = get_state_topic(&config, SensorComponent::Pressure);
+            let availability_topic = get_availability_topic(&config);
+            let online = (availability_topic.clone(), config.mqtt.payload_available.clone());
+            let (mut publisher, mut connection) = connected_publisher(&config);
+
+            publisher.handle_connected(&config).unwrap();
+            for _ in 0..3 {
+                publisher.handle_read_failure(&config).unwrap();
+                publisher.publish_due(&config).unwrap();
+            }
+            let before = sent(&mut connection);
+            assert!(!before.iter().any(|(topic, _)| *topic == state_topic), "{}: {:?}", on_read_failure, before);
+            assert!(!before.contains(&online), "{}: {:?}", on_read_failure, before);
+
+            publisher.publish_state(&config, &reading(Some(21.5), Some(101.2))).unwrap();
+            let after = sent(&mut connection);
+            assert!(after.iter().any(|(topic, _)| *topic == state_topic), "{}: {:?}", on_read_failure, after);
+            assert!(after.contains(&online), "{}: {:?}", on_read_failure, after);
+        }
+    }
+
+    #[test]
+    fn out_of_range_readings_are_clamped_before_publishing() {
+        let config = config("");
+        let payloads = state_payloads(&config, reading(Some(120.0), Some(101.2)));
+        let state: Value = serde_json::from_str(&payloads[0].1).unwrap();
+        assert_eq!(state["temperature"].as_f64(), Some(85.0));
+    }
+
+    #[test]
+    fn temperature_decimals_round_only_the_published_temperature() {
+        let config = config("sensor.temperature_decimals = 1\nmqtt.state_format = \"split\"");
+        let payloads = state_payloads(&config, reading(Some(20.04), Some(101.23456)));
+        assert_eq!(payloads, [
+            (String::from("homeassistant/sensor/hallway/temperature"), String::from("20.0")),
+            (String::from("homeassistant/sensor/hallway/pressure"), String::from("101.23456")),
+        ]);
+    }
+
+    #[test]
+    fn state_shows_no_negative_zero() {
+        let config = config("sensor.temperature_decimals = 1");
+        for (temperature, shown) in [(-0.04, "0.0"), (-0.06, "-0.1"), (-12.34, "-12.3")] {
+            let payloads = state_payloads(&config, reading(Some(temperature), Some(101.2)));
+            assert!(payloads[0].1.contains(&format!("\"temperature\": {},", shown)), "{}", payloads[0].1);
+        }
+    }
+
+    #[test]
+    fn non_finite_readings_are_left_out_of_the_state() {
+        let config = config("");
+        for temperature in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let payloads = state_payloads(&config, reading(Some(temperature), Some(101.2)));
+            assert_eq!(payloads.len(), 1);
+            let state: Value = serde_json::from_str(&payloads[0].1).unwrap();
+            assert_eq!(state.get("temperature"), None, "{}", payloads[0].1);
+            assert!(state.get("pressure").is_some(), "{}", payloads[0].1);
+        }
+    }
+}
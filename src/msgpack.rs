@@ -0,0 +1,83 @@
+/// The few MessagePack types a state payload is made of.
+pub enum Value<'a> {
+    Int(i64),
+    Float(f32),
+    Str(&'a str),
+    Bool(bool),
+    Nil,
+}
+
+/// Encodes `entries` as a MessagePack map with string keys, using the shortest form of each value.
+pub fn encode_map(entries: &[(&str, Value)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match entries.len() {
+        len @ 0..=15 => buf.push(0x80 | len as u8),
+        len @ 16..=0xffff => {
+            buf.push(0xde);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len => {
+            buf.push(0xdf);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        },
+    }
+    for (key, value) in entries {
+        write_str(&mut buf, key);
+        match value {
+            Value::Int(n) => write_int(&mut buf, *n),
+            Value::Float(f) => {
+                buf.push(0xca);
+                buf.extend_from_slice(&f.to_be_bytes());
+            },
+            Value::Str(s) => write_str(&mut buf, s),
+            Value::Bool(b) => buf.push(if *b { 0xc3 } else { 0xc2 }),
+            Value::Nil => buf.push(0xc0),
+        }
+    }
+    buf
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    match s.len() {
+        len @ 0..=31 => buf.push(0xa0 | len as u8),
+        len @ 32..=0xff => buf.extend_from_slice(&[0xd9, len as u8]),
+        len @ 0x100..=0xffff => {
+            buf.push(0xda);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len => {
+            buf.push(0xdb);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        },
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_int(buf: &mut Vec<u8>, n: i64) {
+    match n {
+        0..=0x7f => buf.push(n as u8),
+        -32..=-1 => buf.push(n as i8 as u8),
+        0x80..=0xff => buf.extend_from_slice(&[0xcc, n as u8]),
+        0x100..=0xffff => {
+            buf.push(0xcd);
+            buf.extend_from_slice(&(n as u16).to_be_bytes());
+        },
+        0x1_0000..=0xffff_ffff => {
+            buf.push(0xce);
+            buf.extend_from_slice(&(n as u32).to_be_bytes());
+        },
+        -0x80..=-33 => buf.extend_from_slice(&[0xd0, n as i8 as u8]),
+        -0x8000..=-0x81 => {
+            buf.push(0xd1);
+            buf.extend_from_slice(&(n as i16).to_be_bytes());
+        },
+        -0x8000_0000..=-0x8001 => {
+            buf.push(0xd2);
+            buf.extend_from_slice(&(n as i32).to_be_bytes());
+        },
+        _ => {
+            buf.push(0xd3);
+            buf.extend_from_slice(&n.to_be_bytes());
+        },
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{Data, Persistence, Reading};
+use crate::sink::Sink;
+use log::{debug, error, warn};
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Keeps the last reading in a small JSON file, replaced on every reading, so it can be published
+/// right away after a restart.
+pub struct PersistenceSink {
+    path: PathBuf,
+    temperature: Option<f32>,
+    pressure: Option<f32>,
+    /// Set after a failed write, as a full or read-only SD card would fail every write after it.
+    disabled: bool,
+}
+
+impl PersistenceSink {
+    pub fn new(path: &Path) -> PersistenceSink {
+        PersistenceSink { path: path.to_path_buf(), temperature: None, pressure: None, disabled: false }
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        // Going through the shortest decimal form keeps 101.3 from turning into 101.30000305175781
+        let number = |value: Option<f32>| value.and_then(|v| v.to_string().parse::<f64>().ok());
+        let contents = json!({
+            "timestamp": timestamp,
+            "temperature": number(self.temperature),
+            "pressure": number(self.pressure),
+        }).to_string();
+        // Written aside and renamed over the old file, so a power cut never leaves half a file
+        let partial = self.path.with_extension("tmp");
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, &self.path)
+    }
+}
+
+impl Sink for PersistenceSink {
+    fn name(&self) -> &'static str {
+        "persistence"
+    }
+
+    fn publish_state(&mut self, _config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        if self.disabled {
+            return Ok(());
+        }
+        // A reading that only took one measurement keeps the other from before
+        self.temperature = reading.temperature.or(self.temperature);
+        self.pressure = reading.pressure.or(self.pressure);
+        if let Err(e) = self.write() {
+            error!("Failed to write the last reading to `{}`: {}", self.path.display(), e);
+            warn!("Disabling the persistence sink until restart, other outputs are unaffected");
+            self.disabled = true;
+        }
+        Ok(())
+    }
+}
+
+/// Reads back the reading kept by [`PersistenceSink`], unless it is older than `max_age_seconds`.
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub fn load_last_reading(persistence: &Persistence) -> Option<Reading> {
+    let contents = match fs::read_to_string(&persistence.path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            debug!("No last reading to restore from `{}`: {}", persistence.path.display(), e);
+            return None;
+        }
+    };
+    let Ok(saved) = serde_json::from_str::<Value>(&contents) else {
+        warn!("Ignoring the last reading in `{}`, it isn't valid JSON", persistence.path.display());
+        return None;
+    };
+    let taken = UNIX_EPOCH + Duration::from_secs(saved["timestamp"].as_u64()?);
+    let age = SystemTime::now().duration_since(taken).unwrap_or_default();
+    if age > Duration::from_secs(persistence.max_age_seconds) {
+        debug!("Not restoring the last reading, it is {}s old", age.as_secs());
+        return None;
+    }
+    let value = |key: &str| saved[key].as_f64().map(|v| v as f32);
+    Some(Reading {
+        temperature: value("temperature"),
+        pressure: value("pressure"),
+        read_latency: Duration::ZERO,
+        temperature_drift: None,
+        raw_temperature: None,
+        raw_pressure: None,
+        temperature_failed: false,
+        pressure_failed: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kept_reading_restores_at_full_precision() {
+        let path = std::env::temp_dir().join(format!("bmp180-persistence-{}.json", std::process::id()));
+        let sink = PersistenceSink { temperature: Some(21.456789), pressure: Some(101.32468), ..PersistenceSink::new(&path) };
+        sink.write().unwrap();
+        let restored = load_last_reading(&Persistence { path: path.clone(), max_age_seconds: 60 });
+        fs::remove_file(&path).unwrap();
+
+        // Rounding is left to formatting, so nothing short of the f32 itself is kept
+        let restored = restored.unwrap();
+        assert_eq!(restored.temperature, Some(21.456789));
+        assert_eq!(restored.pressure, Some(101.32468));
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{calculate_altitude, csv, http, now, persistence, reduce_pressure, unix_socket, Data, LoopSignal, Reading};
+use crate::measurement::{Measurement, Unit};
+use log::error;
+#[cfg(not(feature = "mqtt"))]
+use log::info;
+use std::process::ExitCode;
+use time::format_description::well_known::Rfc3339;
+use std::time::Instant;
+
+/// An output readings are fanned out to. Adding one means implementing this trait and registering it
+/// in [`open_sinks`], or in `main` when it depends on the MQTT connection.
+pub trait Sink: Send {
+    /// Identifies the sink in commands toggling it at runtime.
+    fn name(&self) -> &'static str;
+
+    /// Announces the entities to consumers that need them before any state.
+    fn publish_discovery(&mut self, _config: &Data) {}
+
+    /// An error stops the read loop, so failures the sink can ride out are logged instead.
+    fn publish_state(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode>;
+
+    /// Called for every failed read, in place of [`Sink::publish_state`].
+    fn handle_read_failure(&mut self, _config: &Data) -> Result<(), ExitCode> {
+        Ok(())
+    }
+
+    /// Called once reinitializing gave up on the sensor, which stays unreadable until a read
+    /// succeeds again.
+    fn handle_sensor_lost(&mut self, _config: &Data) -> Result<(), ExitCode> {
+        Ok(())
+    }
+
+    /// Reacts to a change of the MQTT connection.
+    fn handle_signal(&mut self, _config: &Data, _signal: &LoopSignal) -> Result<(), ExitCode> {
+        Ok(())
+    }
+
+    /// Earliest time [`Sink::publish_due`] has something to do.
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Publishes messages that were deferred until a later time.
+    fn publish_due(&mut self, _config: &Data) -> Result<(), ExitCode> {
+        Ok(())
+    }
+}
+
+/// Opens the sinks enabled in the config, in the order readings are handed to them.
+pub fn open_sinks(config: &Data) -> Result<Vec<Box<dyn Sink>>, ExitCode> {
+    let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+    if let Some(c) = &config.csv {
+        match csv::CsvSink::open(&c.path) {
+            Ok(sink) => sinks.push(Box::new(sink)),
+            Err(e) => {
+                error!("Could not open CSV file `{}`: {}", c.path.display(), e);
+                return Err(ExitCode::FAILURE);
+            }
+        }
+    }
+    if let Some(s) = &config.unix_socket {
+        match unix_socket::UnixSocketSink::open(&s.path) {
+            Ok(sink) => sinks.push(Box::new(sink)),
+            Err(e) => {
+                error!("Could not bind Unix socket `{}`: {}", s.path.display(), e);
+                return Err(ExitCode::FAILURE);
+            }
+        }
+    }
+    if let Some(h) = &config.http {
+        match http::HttpSink::open(config, &h.listen) {
+            Ok(sink) => sinks.push(Box::new(sink)),
+            Err(e) => {
+                error!("Could not listen for HTTP on `{}`: {}", h.listen, e);
+                return Err(ExitCode::FAILURE);
+            }
+        }
+    }
+    if let Some(p) = &config.persistence {
+        sinks.push(Box::new(persistence::PersistenceSink::new(&p.path)));
+    }
+    // Without MQTT the log is the only place readings show up live
+    #[cfg(not(feature = "mqtt"))]
+    sinks.push(Box::new(LogSink));
+    Ok(sinks)
+}
+
+/// Logs every reading at info level.
+#[cfg(not(feature = "mqtt"))]
+struct LogSink;
+
+#[cfg(not(feature = "mqtt"))]
+impl Sink for LogSink {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn publish_state(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        if let Some(temperature) = reading.temperature {
+            info!("Temperature: [{}].", temperature);
+        }
+        if let Some(pressure) = reading.pressure {
+            // A pressure-only reading has no temperature to reduce to QFF with, so the station
+            // pressure is shown instead
+            let reported = reduce_pressure(&config.sensor, pressure, reading.temperature).unwrap_or(pressure);
+            let altitude = Some(calculate_altitude(pressure, config.sensor.sea_level_pressure))
+                .filter(|altitude| config.sensor.altitude && config.sensor.altitude_range().contains(&altitude.raw))
+                .and_then(|altitude| altitude.converted_to(config.sensor.altitude_unit.into()))
+                .map(|altitude| altitude.rounded(config.sensor.altitude_decimals));
+            match altitude {
+                Some(altitude) => info!("Pressure: [{}]. Altitude: [{:.*}].", reported, config.sensor.altitude_decimals, altitude),
+                None => info!("Pressure: [{}].", reported),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prints every reading to stdout as a single line, with the units, rounding and pressure
+/// reduction the MQTT states get. Used by `--config-test-sensor`.
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    fn publish_state(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        let sensor = &config.sensor;
+        let mut fields = vec![now(config).format(&Rfc3339).unwrap_or_default()];
+        if let Some(temperature) = reading.temperature {
+            for &unit in &sensor.temperature_units {
+                let Some(temperature) = Measurement::new(temperature, Unit::Celsius).converted_to(unit.into()) else {
+                    continue;
+                };
+                fields.push(match sensor.temperature_decimals {
+                    Some(decimals) => format!("{:.*} {}", decimals, temperature.rounded(decimals), temperature.unit.symbol()),
+                    None => format!("{} {}", temperature, temperature.unit.symbol()),
+                });
+            }
+        }
+        if let Some(pressure) = reading.pressure {
+            if let Some(reported) = reduce_pressure(sensor, pressure, reading.temperature) {
+                fields.push(format!("{} {}", reported, Unit::Kilopascals.symbol()));
+            }
+            let altitude = Some(calculate_altitude(pressure, sensor.sea_level_pressure))
+                .filter(|altitude| sensor.altitude && sensor.altitude_range().contains(&altitude.raw))
+                .and_then(|altitude| altitude.converted_to(sensor.altitude_unit.into()));
+            if let Some(altitude) = altitude.map(|altitude| altitude.rounded(sensor.altitude_decimals)) {
+                fields.push(format!("{:.*} {}", sensor.altitude_decimals, altitude, altitude.unit.symbol()));
+            }
+        }
+        if let Some(drift) = reading.temperature_drift {
+            fields.push(format!("drift {:.2}", Measurement::new(drift, Unit::Celsius)));
+        }
+        println!("{}", fields.join("  "));
+        Ok(())
+    }
+
+    fn handle_read_failure(&mut self, config: &Data) -> Result<(), ExitCode> {
+        println!("{}  read failed", now(config).format(&Rfc3339).unwrap_or_default());
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{now, reduce_pressure, Data, LoopSignal, Reading, Stats};
+use crate::measurement::{Measurement, Unit};
+use crate::sink::Sink;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use time::format_description::well_known::Rfc3339;
+
+/// Readings the sparklines span.
+const HISTORY: usize = 60;
+const SPARK_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Redraws a dashboard of the latest reading, the recent trend, the broker connection and the
+/// error counts on stdout after every reading. Drawn with plain ANSI escapes on the terminal's
+/// alternate screen, which is left again when the read loop ends.
+pub struct TuiSink {
+    stats: Arc<Stats>,
+    /// `None` without a broker to connect to.
+    connected: Option<Arc<AtomicBool>>,
+    temperatures: VecDeque<f32>,
+    pressures: VecDeque<f32>,
+    last_read_failed: bool,
+}
+
+impl TuiSink {
+    pub fn new(stats: Arc<Stats>, connected: Option<Arc<AtomicBool>>) -> TuiSink {
+        print!("\x1b[?1049h\x1b[?25l");
+        let _ = io::stdout().flush();
+        TuiSink { stats, connected, temperatures: VecDeque::new(), pressures: VecDeque::new(), last_read_failed: false }
+    }
+
+    fn draw(&self, config: &Data) {
+        let sensor = &config.sensor;
+        let unit: Unit = sensor.temperature_units.first().copied().map_or(Unit::Celsius, Into::into);
+        let temperature = self.temperatures.back()
+            .and_then(|&t| Measurement::new(t, Unit::Celsius).converted_to(unit))
+            .map_or_else(|| String::from("-"), |t| format!("{:.*} {}", sensor.temperature_decimals.unwrap_or(2), t, t.unit.symbol()));
+        let pressure = self.pressures.back().map_or_else(|| String::from("-"), |p| format!("{:.2} {}", p, Unit::Kilopascals.symbol()));
+        let connection = match &self.connected {
+            Some(connected) if connected.load(Ordering::Relaxed) => "connected",
+            Some(_) => "disconnected",
+            None => "off",
+        };
+
+        let mut screen = String::from("\x1b[H\x1b[2J");
+        screen.push_str(&format!("BMP180   {}\r\n\r\n", now(config).format(&Rfc3339).unwrap_or_default()));
+        screen.push_str(&format!("Temperature  {:>12}  {}\r\n", temperature, sparkline(&self.temperatures)));
+        screen.push_str(&format!("Pressure     {:>12}  {}\r\n\r\n", pressure, sparkline(&self.pressures)));
+        screen.push_str(&format!("MQTT         {}\r\n", connection));
+        screen.push_str(&format!("Last read    {}\r\n\r\n", if self.last_read_failed { "failed" } else { "ok" }));
+        screen.push_str(&format!(
+            "Read failures {}   Publish failures {}   Reconnects {}   Sensor reinits {}\r\n",
+            self.stats.read_failures.load(Ordering::Relaxed),
+            self.stats.publish_failures.load(Ordering::Relaxed),
+            self.stats.reconnects.load(Ordering::Relaxed),
+            self.stats.sensor_reinits.load(Ordering::Relaxed),
+        ));
+        screen.push_str("\r\nStop with Ctrl-C\r\n");
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(screen.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+/// One block per value, scaled between the lowest and highest of them.
+fn sparkline(values: &VecDeque<f32>) -> String {
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    values.iter().map(|&value| {
+        let level = if max > min { ((value - min) / (max - min) * 7.0).round() as usize } else { 3 };
+        SPARK_LEVELS[level.min(7)]
+    }).collect()
+}
+
+fn push_capped(history: &mut VecDeque<f32>, value: f32) {
+    if history.len() == HISTORY {
+        history.pop_front();
+    }
+    history.push_back(value);
+}
+
+impl Sink for TuiSink {
+    fn name(&self) -> &'static str {
+        "tui"
+    }
+
+    fn publish_state(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        if let Some(temperature) = reading.temperature {
+            push_capped(&mut self.temperatures, temperature);
+        }
+        if let Some(reported) = reading.pressure.and_then(|p| reduce_pressure(&config.sensor, p, reading.temperature)) {
+            push_capped(&mut self.pressures, reported);
+        }
+        self.last_read_failed = false;
+        self.draw(config);
+        Ok(())
+    }
+
+    fn handle_read_failure(&mut self, config: &Data) -> Result<(), ExitCode> {
+        self.last_read_failed = true;
+        self.draw(config);
+        Ok(())
+    }
+
+    fn handle_signal(&mut self, config: &Data, _signal: &LoopSignal) -> Result<(), ExitCode> {
+        self.draw(config);
+        Ok(())
+    }
+}
+
+impl Drop for TuiSink {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+    }
+}
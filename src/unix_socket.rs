@@ -0,0 +1,103 @@
+use crate::{now, Data, Reading};
+use crate::sink::Sink;
+use log::{debug, warn};
+use serde_json::json;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+
+/// How long a write to one client may block, so a client that stops reading can't stall the read
+/// loop.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Clients connected to the socket and the last line sent, shared with the accept thread.
+#[derive(Default)]
+struct Clients {
+    streams: Vec<UnixStream>,
+    latest: Option<String>,
+}
+
+/// Serves readings as JSON lines on a Unix socket. A new client is sent the latest reading right
+/// away, then every reading after it until it disconnects.
+pub struct UnixSocketSink {
+    clients: Arc<Mutex<Clients>>,
+    path: PathBuf,
+}
+
+impl UnixSocketSink {
+    /// Binds the socket, replacing one left behind by an earlier run.
+    pub fn open(path: &Path) -> io::Result<UnixSocketSink> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "exists and is not a socket"));
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let clients = Arc::new(Mutex::new(Clients::default()));
+
+        let accepted = Arc::clone(&clients);
+        thread::Builder::new()
+            .name(String::from("unix socket"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => accept(&accepted, stream),
+                        Err(e) => warn!("Failed to accept a Unix socket connection: {}", e),
+                    }
+                }
+            })?;
+        Ok(UnixSocketSink { clients, path: path.to_path_buf() })
+    }
+}
+
+fn accept(clients: &Mutex<Clients>, mut stream: UnixStream) {
+    if let Err(e) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+        warn!("Failed to set up a Unix socket connection: {}", e);
+        return;
+    }
+    let mut clients = clients.lock().unwrap();
+    if let Some(line) = &clients.latest {
+        if stream.write_all(line.as_bytes()).is_err() {
+            return;
+        }
+    }
+    debug!("Unix socket client connected, {} now connected", clients.streams.len() + 1);
+    clients.streams.push(stream);
+}
+
+impl Sink for UnixSocketSink {
+    fn name(&self) -> &'static str {
+        "unix_socket"
+    }
+
+    fn publish_state(&mut self, config: &Data, reading: &Reading) -> Result<(), ExitCode> {
+        // Going through the shortest decimal form keeps 101.3 from turning into 101.30000305175781
+        let number = |value: Option<f32>| value.and_then(|v| v.to_string().parse::<f64>().ok());
+        let line = json!({
+            "timestamp": now(config).format(&Rfc3339).unwrap_or_default(),
+            "temperature": number(reading.temperature),
+            "pressure": number(reading.pressure),
+            "temperature_drift": number(reading.temperature_drift),
+        }).to_string() + "\n";
+
+        let mut clients = self.clients.lock().unwrap();
+        // A failed write means the client went away or stopped reading, either way it is dropped
+        clients.streams.retain_mut(|stream| stream.write_all(line.as_bytes()).is_ok());
+        clients.latest = Some(line);
+        Ok(())
+    }
+}
+
+impl Drop for UnixSocketSink {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}